@@ -0,0 +1,195 @@
+use crate::hash::{crc32, sha1};
+use crate::mmu::Model;
+use crate::movie::{Movie, MovieRecorder, StartCondition};
+use crate::zip::{read_zip, write_zip, ZipEntry};
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+// BizHawk BK2 movies: a zip holding Header.txt ("Key Value" lines) and Input Log.txt
+// The input log has a LogKey line naming the buttons, then one line per frame such as |U......AP|
+// where every character is a button from the LogKey and '.' means released
+// BizHawk usually boots through the boot ROM, which this emulator skips, so such movies start out a few frames early
+
+const LOG_KEY: &str = "LogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|";
+const BUTTONS: [(&str, char, u8); 8] = [
+    ("Up", 'U', 2),
+    ("Down", 'D', 3),
+    ("Left", 'L', 1),
+    ("Right", 'R', 0),
+    ("Start", 'S', 7),
+    ("Select", 's', 6),
+    ("B", 'B', 5),
+    ("A", 'A', 4),
+];
+
+pub fn import(path: &str, rom: &[u8]) -> io::Result<Movie> {
+    let mut data: Vec<u8> = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let entries = read_zip(&data)?;
+
+    // BizHawk's Gambatte core runs the cartridge's own hardware under "GB"
+    let mut model = match Model::from_rom(rom) {
+        Model::Sgb => Model::Dmg,
+        model => model,
+    };
+    if let Some(header) = entries.iter().find(|entry| entry.name == "Header.txt") {
+        for line in String::from_utf8_lossy(&header.data).lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "Platform" if !["GB", "GBC", "SGB"].contains(&value.trim()) => {
+                    return Err(invalid(&format!("movie is for the {} platform", value.trim())));
+                }
+                "Platform" if value.trim() == "GBC" => model = Model::Cgb,
+                "Platform" if value.trim() == "SGB" => model = Model::Sgb,
+                "SHA1" if !value.trim().eq_ignore_ascii_case(&hex(&sha1(rom))) => {
+                    return Err(invalid("movie was recorded with a different ROM (SHA1 mismatch)"));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let input_log = entries
+        .iter()
+        .find(|entry| entry.name == "Input Log.txt")
+        .ok_or_else(|| invalid("bk2 has no Input Log.txt"))?;
+
+    let mut columns: Vec<Option<u8>> = Vec::new();
+    let mut recorder = MovieRecorder::new(Movie::new(crc32(rom), model, StartCondition::PowerOn));
+    let mut frame: u32 = 0;
+
+    for line in String::from_utf8_lossy(&input_log.data).lines() {
+        if let Some(log_key) = line.strip_prefix("LogKey:") {
+            columns = log_key
+                .split(['|', '#'])
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let name = name.strip_prefix("P1 ").unwrap_or(name);
+                    BUTTONS.iter().find(|button| button.0 == name).map(|button| button.2)
+                })
+                .collect();
+        } else if line.starts_with('|') {
+            if columns.is_empty() {
+                columns = BUTTONS.iter().map(|button| Some(button.2)).collect();
+            }
+            let mut joypad: u8 = 0;
+            for (column, c) in line.chars().filter(|c| *c != '|').enumerate() {
+                if let Some(Some(bit)) = columns.get(column) {
+                    if c != '.' && c != ' ' {
+                        joypad |= 1 << bit;
+                    }
+                }
+            }
+            recorder.record(frame, joypad);
+            frame += 1;
+        }
+    }
+
+    Ok(recorder.finish())
+}
+
+pub fn export(movie: &Movie, rom: &[u8], path: &str) -> io::Result<()> {
+    let title: String = rom
+        .get(0x134..0x144)
+        .unwrap_or(&[])
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as char)
+        .collect();
+
+    let mut header = String::new();
+    header.push_str("MovieVersion BizHawk v2.0.0\n");
+    header.push_str(&format!("emuVersion rustboy {}\n", movie.emulator_version));
+    header.push_str(match movie.model {
+        Model::Dmg => "Platform GB\n",
+        Model::Cgb => "Platform GBC\n",
+        Model::Sgb => "Platform SGB\n",
+    });
+    header.push_str(&format!("GameName {}\n", title.trim()));
+    header.push_str(&format!("SHA1 {}\n", hex(&sha1(rom))));
+    header.push_str("Core Gambatte\n");
+    header.push_str("rerecordCount 0\n");
+
+    let mut input_log = String::new();
+    input_log.push_str("[Input]\n");
+    input_log.push_str(LOG_KEY);
+    input_log.push('\n');
+
+    let mut next_event: usize = 0;
+    let mut joypad: u8 = 0;
+    for frame in 0..movie.length {
+        while next_event < movie.events.len() && movie.events[next_event].frame <= frame {
+            joypad = movie.events[next_event].joypad;
+            next_event += 1;
+        }
+        input_log.push('|');
+        for (_, mnemonic, bit) in BUTTONS.iter() {
+            input_log.push(if joypad & (1 << bit) > 0 { *mnemonic } else { '.' });
+        }
+        input_log.push_str(".|\n"); // Power
+    }
+    input_log.push_str("[/Input]\n");
+
+    let zip = write_zip(&[
+        ZipEntry { name: "Header.txt".to_string(), data: header.into_bytes() },
+        ZipEntry { name: "Input Log.txt".to_string(), data: input_log.into_bytes() },
+    ]);
+    File::create(path)?.write_all(&zip)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import};
+    use crate::mmu::Model;
+    use crate::movie::{InputEvent, Movie, MovieRecorder, StartCondition};
+
+    // Written by Python's zipfile with deflated entries, for a ROM of 32KB of zeros
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/movie.bk2");
+
+    fn events(movie: &Movie) -> Vec<(u32, u8)> {
+        movie.events.iter().map(|event| (event.frame, event.joypad)).collect()
+    }
+
+    #[test]
+    fn imports_the_input_log() {
+        let movie = import(FIXTURE, &[0; 0x8000]).unwrap();
+        assert_eq!(movie.length, 5);
+        assert_eq!(movie.model, Model::Dmg);
+        // Up and A, then Right and Start
+        assert_eq!(events(&movie), vec![(0, 0x00), (1, 0x14), (3, 0x81), (4, 0x00)]);
+    }
+
+    #[test]
+    fn rejects_a_different_rom() {
+        assert!(import(FIXTURE, &[1; 0x8000]).is_err());
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let rom: Vec<u8> = (0..0x8000).map(|i| i as u8).collect();
+        let mut recorder = MovieRecorder::new(Movie::new(0, Model::Cgb, StartCondition::PowerOn));
+        for (frame, joypad) in [0x00, 0x01, 0x01, 0xF0, 0x0F, 0x00].into_iter().enumerate() {
+            recorder.record(frame as u32, joypad);
+        }
+        let movie = recorder.finish();
+
+        let path = std::env::temp_dir().join(format!("rustboy-bk2-{}.bk2", std::process::id()));
+        let path = path.to_str().unwrap();
+        export(&movie, &rom, path).unwrap();
+        let imported = import(path, &rom).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(imported.model, Model::Cgb);
+        assert_eq!(imported.length, movie.length);
+        assert_eq!(imported.events, movie.events);
+        assert_eq!(imported.events[2], InputEvent { frame: 3, joypad: 0xF0 });
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Where screenshots and GIF clips end up, the encoders in png.rs and gif.rs only produce the bytes
+
+// Writes screenshots and GIF clips to rustboy-YYYYMMDD-HHMMSS-mmm.extension (UTC) in directory, creating it if needed
+pub fn save_capture(directory: &Path, extension: &str, data: &[u8]) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let name = timestamp();
+    let mut path = directory.join(format!("rustboy-{}.{}", name, extension));
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = directory.join(format!("rustboy-{}-{}.{}", name, count, extension));
+    }
+    fs::write(&path, data)?;
+    Ok(path)
+}
+
+// YYYYMMDD-HHMMSS-mmm
+pub fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = now.as_secs();
+    let (year, month, day) = civil_date((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
+
+// Days since 1970-01-01 to year, month and day in the Gregorian calendar
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468; // Counted from 0000-03-01 instead, so leap days end each 400 year era
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153; // From March
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{civil_date, save_capture};
+    use std::fs;
+
+    #[test]
+    fn captures_never_overwrite_each_other() {
+        let directory = std::env::temp_dir().join(format!("rustboy-capture-{}", std::process::id())).join("shots");
+        let first = save_capture(&directory, "png", b"first").unwrap();
+        let second = save_capture(&directory, "png", b"second").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read(&first).unwrap(), b"first");
+        assert_eq!(fs::read(&second).unwrap(), b"second");
+
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("rustboy-") && name.ends_with(".png"), "{}", name);
+        fs::remove_dir_all(directory.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(11016), (2000, 2, 29));
+        assert_eq!(civil_date(19723), (2024, 1, 1));
+        assert_eq!(civil_date(2932896), (9999, 12, 31));
+    }
+}
@@ -0,0 +1,359 @@
+use crate::mmu::MMU;
+
+// Palettes the CGB boot ROM gives DMG cartridges
+// Nintendo titles are recognised by the sum of their title bytes (plus the fourth letter when sums collide),
+// holding a direction with or without A or B while the logo shows picks one of 12 palettes instead
+// The GPU then looks up BGP/OBP0/OBP1 shades in BG palette 0 and OBJ palettes 0 and 1
+
+pub struct CompatPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+// 8 bits per channel in, BGR555 out like the boot ROM stores them
+const fn rgb(colour: u32) -> u16 {
+    let r = ((colour >> 16) & 0xFF) >> 3;
+    let g = ((colour >> 8) & 0xFF) >> 3;
+    let b = (colour & 0xFF) >> 3;
+    (r | g << 5 | b << 10) as u16
+}
+
+const fn colours(palette: [u32; 4]) -> [u16; 4] {
+    [rgb(palette[0]), rgb(palette[1]), rgb(palette[2]), rgb(palette[3])]
+}
+
+// The boot ROM's palettes, which it combines into OBJ0, OBJ1 and BG sets below
+const PALETTES: [[u16; 4]; 30] = [
+    colours([0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]), // 0, brown
+    colours([0xFFE7C6, 0xCE9C84, 0x846B29, 0x5A3108]), // 1, dark brown
+    colours([0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000]), // 2, dark blue
+    colours([0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]), // 3, light green
+    colours([0xFFFFFF, 0xFF8484, 0x943939, 0x000000]), // 4, red
+    colours([0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]), // 5, grey
+    colours([0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000]), // 6, yellow
+    colours([0xFFFFFF, 0x7BFF00, 0xB57300, 0x000000]), // 7
+    colours([0xFFFFFF, 0xADAD84, 0x42737B, 0x000000]), // 8
+    colours([0xA59CFF, 0xFFFF00, 0x006300, 0x000000]), // 9
+    colours([0xFFFFCE, 0x63EFEF, 0x9C8431, 0x5A5A5A]), // 10
+    colours([0xB5B5FF, 0xFFFF94, 0xAD5A42, 0x000000]), // 11
+    colours([0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]), // 12, pastel
+    colours([0xFFFF9C, 0x94B5FF, 0x639473, 0x003939]), // 13
+    colours([0x6BFF00, 0xFFFFFF, 0xFF524A, 0x000000]), // 14
+    colours([0x52DE00, 0xFF8400, 0xFFFF00, 0xFFFFFF]), // 15
+    colours([0xFFFFFF, 0xFF7300, 0x944200, 0x000000]), // 16
+    colours([0xFFC642, 0xFFD600, 0x943900, 0x4A0000]), // 17
+    colours([0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000]), // 18, green
+    colours([0xFF6352, 0xD60000, 0x630000, 0x000000]), // 19
+    colours([0xFFFFFF, 0xFF9C00, 0xFF0000, 0x000000]), // 20
+    colours([0xFFFFFF, 0x00FF00, 0x318400, 0x004A00]), // 21
+    colours([0xFFFFFF, 0x5ABDFF, 0xFF0000, 0x0000FF]), // 22
+    colours([0xFFFFFF, 0xFFFF7B, 0x0084FF, 0xFF0000]), // 23
+    colours([0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]), // 24, orange
+    colours([0xFFFF00, 0xFF0000, 0x630000, 0x000000]), // 25
+    colours([0xFFFFFF, 0xFFCE00, 0x9C6300, 0x000000]), // 26
+    colours([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]), // 27, reverse
+    colours([0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]), // 28, blue
+    colours([0xFFFFFF, 0x7BFF31, 0x0063C6, 0x000000]), // 29, dark green
+];
+
+const fn combination(obj0: usize, obj1: usize, bg: usize) -> CompatPalette {
+    CompatPalette { bg: PALETTES[bg], obj0: PALETTES[obj0], obj1: PALETTES[obj1] }
+}
+
+// A few combinations start one colour early, taking the last colour of the palette before
+const fn shifted(palette: usize) -> [u16; 4] {
+    [PALETTES[palette - 1][3], PALETTES[palette][0], PALETTES[palette][1], PALETTES[palette][2]]
+}
+
+static COMBINATIONS: [CompatPalette; 51] = [
+    combination(4, 4, 29), // 0, Right+A, the default
+    combination(18, 18, 18), // 1, Right
+    combination(20, 20, 20), // 2
+    combination(24, 24, 24), // 3, Down+A
+    combination(9, 9, 9), // 4
+    combination(0, 0, 0), // 5, Up
+    combination(27, 27, 27), // 6, Right+B
+    combination(5, 5, 5), // 7, Left+B
+    combination(12, 12, 12), // 8, Down
+    combination(26, 26, 26), // 9
+    combination(16, 8, 8), // 10
+    combination(4, 28, 28), // 11
+    combination(4, 2, 2), // 12
+    combination(3, 4, 4), // 13
+    combination(4, 29, 29), // 14
+    combination(28, 4, 28), // 15
+    combination(2, 17, 2), // 16
+    combination(16, 16, 8), // 17
+    combination(4, 4, 7), // 18
+    combination(4, 4, 18), // 19
+    combination(4, 4, 20), // 20
+    combination(19, 19, 9), // 21
+    CompatPalette { bg: PALETTES[11], obj0: shifted(4), obj1: shifted(4) }, // 22
+    combination(17, 17, 2), // 23
+    combination(4, 4, 2), // 24
+    combination(4, 4, 3), // 25
+    combination(28, 28, 0), // 26
+    combination(3, 3, 0), // 27
+    combination(0, 0, 1), // 28, Up+B
+    combination(18, 22, 18), // 29
+    combination(20, 22, 20), // 30
+    combination(24, 22, 24), // 31
+    combination(16, 22, 8), // 32
+    combination(17, 4, 13), // 33
+    CompatPalette { bg: PALETTES[14], obj0: shifted(28), obj1: PALETTES[0] }, // 34
+    CompatPalette { bg: PALETTES[15], obj0: shifted(28), obj1: PALETTES[4] }, // 35
+    combination(19, 22, 9), // 36
+    combination(16, 28, 10), // 37
+    combination(4, 23, 28), // 38
+    combination(17, 22, 2), // 39
+    combination(4, 0, 2), // 40, Left+A
+    combination(4, 28, 3), // 41
+    combination(28, 3, 0), // 42
+    combination(3, 28, 4), // 43, Up+A
+    combination(21, 28, 4), // 44
+    combination(3, 28, 0), // 45
+    combination(25, 3, 28), // 46
+    combination(0, 28, 8), // 47
+    combination(4, 3, 28), // 48, Left
+    combination(28, 3, 6), // 49, Down+B
+    combination(4, 28, 29), // 50
+];
+
+// Unrecognised and third party games
+pub static DEFAULT: &CompatPalette = &COMBINATIONS[0];
+
+// Title checksum, fourth title letter for checksums shared by several games, combination
+// In the boot ROM's order, titles missing from its table get the default
+const TITLES: [(u8, Option<u8>, usize); 94] = [
+    (0x00, None, 0), // Unrecognised Nintendo titles
+    (0x88, None, 4), // ALLEY WAY
+    (0x16, None, 5), // YAKUMAN
+    (0x36, None, 35), // BASEBALL, GAME&WATCH 2
+    (0xD1, None, 34), // TENNIS
+    (0xDB, None, 3), // TETRIS
+    (0xF2, None, 31), // QIX
+    (0x3C, None, 15), // DR.MARIO
+    (0x8C, None, 10), // RADARMISSION
+    (0x92, None, 5), // F1RACE
+    (0x3D, None, 19), // YOSSY NO TAMAGO
+    (0x5C, None, 36),
+    (0x58, None, 7), // X
+    (0xC9, None, 37), // MARIOLAND2
+    (0x3E, None, 30), // YOSSY NO COOKIE
+    (0x70, None, 44), // ZELDA
+    (0x1D, None, 21),
+    (0x59, None, 32),
+    (0x69, None, 31), // TETRIS FLASH
+    (0x19, None, 20), // DONKEY KONG
+    (0x35, None, 5), // MARIO'S PICROSS
+    (0xA8, None, 33),
+    (0x14, None, 13), // POKEMON RED, GAMEBOYCAMERA G
+    (0xAA, None, 14), // POKEMON GREEN
+    (0x75, None, 5), // PICROSS 2
+    (0x95, None, 29), // YOSSY NO PANEPON
+    (0x99, None, 5), // KIRAKIRA KIDS
+    (0x34, None, 18), // GAMEBOY GALLERY
+    (0x6F, None, 9), // POCKETCAMERA
+    (0x15, None, 3),
+    (0xFF, None, 2), // BALLOON KID
+    (0x97, None, 26), // KINGOFTHEZOO
+    (0x4B, None, 25), // DMG FOOTBALL
+    (0x90, None, 25), // WORLD CUP
+    (0x17, None, 41), // OTHELLO
+    (0x10, None, 42), // SUPER RC PRO-AM
+    (0x39, None, 26), // DYNABLASTER
+    (0xF7, None, 45), // BOY AND BLOB GB2
+    (0xF6, None, 42), // MEGAMAN
+    (0xA2, None, 45), // STAR WARS-NOA
+    (0x49, None, 36),
+    (0x4E, None, 38), // WAVERACE
+    (0x43, None, 26),
+    (0x68, None, 42), // LOLO2
+    (0xE0, None, 30), // YOSHI'S COOKIE
+    (0x8B, None, 41), // MYSTIC QUEST
+    (0xF0, None, 34),
+    (0xCE, None, 34), // TOPRANKINGTENNIS
+    (0x0C, None, 5), // MANSELL
+    (0x29, None, 42), // MEGAMAN3
+    (0xE8, None, 6), // SPACE INVADERS
+    (0xB7, None, 5), // GAME&WATCH
+    (0x86, None, 33), // DONKEYKONGLAND95
+    (0x9A, None, 25), // ASTEROIDS/MISCMD
+    (0x52, None, 42), // STREET FIGHTER 2
+    (0x01, None, 42), // DEFENDER/JOUST
+    (0x9D, None, 40), // KILLERINSTINCT95
+    (0x71, None, 2), // TETRIS BLAST
+    (0x9C, None, 16), // PINOCCHIO
+    (0xBD, None, 25),
+    (0x5D, None, 42), // BA.TOSHINDEN
+    (0x6D, None, 42), // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3F, None, 0), // TETRIS PLUS
+    (0x6B, None, 39), // DONKEYKONGLAND 3
+    (0xB3, Some(b'B'), 36),
+    (0x46, Some(b'E'), 22), // SUPER MARIOLAND
+    (0x28, Some(b'F'), 25), // GOLF
+    (0xA5, Some(b'A'), 6), // SOLARSTRIKER
+    (0xC6, Some(b'A'), 32), // GBWARS
+    (0xD3, Some(b'R'), 12), // KAERUNOTAMENI
+    (0x27, Some(b'B'), 36),
+    (0x61, Some(b'E'), 11), // POKEMON BLUE
+    (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18), // GAMEBOY GALLERY2
+    (0x6A, Some(b'K'), 39), // DONKEYKONGLAND 2
+    (0xBF, Some(b' '), 24), // KID ICARUS
+    (0x0D, Some(b'R'), 31), // TETRIS2
+    (0xF4, Some(b'-'), 50),
+    (0xB3, Some(b'U'), 17), // MOGURANYA
+    (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6), // GALAGA&GALAXIAN
+    (0xA5, Some(b'R'), 27), // BT2RAGNAROKWORLD
+    (0xC6, Some(b' '), 0), // KEN GRIFFEY JR
+    (0xD3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 41), // VEGAS STAKES
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0), // MILLI/CENTI/PEDE
+    (0x6A, Some(b'I'), 19), // MARIO & YOSHI
+    (0xBF, Some(b'C'), 34), // SOCCER
+    (0x0D, Some(b'E'), 23), // POKEBOM
+    (0xF4, Some(b' '), 18), // G&W GALLERY
+    (0xB3, Some(b'R'), 29), // TETRIS ATTACK
+];
+
+// Joypad masks, see movie.rs for the bit layout
+const RIGHT_BUTTON: u8 = 1 << 0;
+const LEFT_BUTTON: u8 = 1 << 1;
+const UP_BUTTON: u8 = 1 << 2;
+const DOWN_BUTTON: u8 = 1 << 3;
+const A_BUTTON: u8 = 1 << 4;
+const B_BUTTON: u8 = 1 << 5;
+
+// The palette a held button combination picks, the combination has to be one direction plus optionally A or B
+pub fn combo_palette(buttons: u8) -> Option<&'static CompatPalette> {
+    // Combinations for the direction alone, with A and with B
+    let combinations: [usize; 3] = match buttons & 0x0F {
+        UP_BUTTON => [5, 43, 28],
+        LEFT_BUTTON => [48, 40, 7],
+        DOWN_BUTTON => [8, 3, 49],
+        RIGHT_BUTTON => [1, 0, 6],
+        _ => return None,
+    };
+    match buttons & 0xF0 {
+        0 => Some(&COMBINATIONS[combinations[0]]),
+        A_BUTTON => Some(&COMBINATIONS[combinations[1]]),
+        B_BUTTON => Some(&COMBINATIONS[combinations[2]]),
+        _ => None,
+    }
+}
+
+// Button names are the ones config files use, joined with '+' such as "up+a"
+pub fn parse_combo(combo: &str) -> Option<u8> {
+    let mut buttons: u8 = 0;
+    for name in combo.split('+') {
+        let bit = crate::input::BUTTON_NAMES.iter().position(|button| button.eq_ignore_ascii_case(name.trim()))?;
+        buttons |= 1 << bit;
+    }
+    combo_palette(buttons).map(|_| buttons)
+}
+
+// Only games with Nintendo as licensee are looked up, the new licensee code is used when the old one is 0x33
+fn is_nintendo(rom: &[u8]) -> bool {
+    match rom.get(0x14B) {
+        Some(0x01) => true,
+        Some(0x33) => rom.get(0x144..0x146) == Some(b"01".as_slice()),
+        _ => false,
+    }
+}
+
+pub fn title_palette(rom: &[u8]) -> &'static CompatPalette {
+    if !is_nintendo(rom) || rom.len() < 0x144 {
+        return DEFAULT;
+    }
+    let checksum: u8 = rom[0x134..0x144].iter().fold(0, |sum, byte| sum.wrapping_add(*byte));
+    let fourth_letter: u8 = rom[0x137];
+    TITLES
+        .iter()
+        .find(|(sum, letter, _)| *sum == checksum && (letter.is_none() || *letter == Some(fourth_letter)))
+        .map_or(DEFAULT, |(_, _, combination)| &COMBINATIONS[*combination])
+}
+
+// What the boot ROM writes to palette RAM, a valid button combo wins over the title
+pub fn load_palette(mmu: &mut MMU, rom: &[u8], buttons: u8) {
+    let palette = combo_palette(buttons).unwrap_or_else(|| title_palette(rom));
+    write_colours(&mut mmu.bg_palette_ram[..8], &palette.bg);
+    write_colours(&mut mmu.obj_palette_ram[..8], &palette.obj0);
+    write_colours(&mut mmu.obj_palette_ram[8..16], &palette.obj1);
+}
+
+fn write_colours(palette_ram: &mut [u8], colours: &[u16; 4]) {
+    for (i, colour) in colours.iter().enumerate() {
+        palette_ram[i * 2..i * 2 + 2].copy_from_slice(&colour.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combo_palette, parse_combo, rgb, title_palette, CompatPalette, COMBINATIONS, DEFAULT};
+    use std::ptr;
+
+    fn rom(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = licensee;
+        rom
+    }
+
+    fn is(palette: &CompatPalette, combination: usize) -> bool {
+        ptr::eq(palette, &COMBINATIONS[combination])
+    }
+
+    #[test]
+    fn rgb_keeps_the_top_five_bits() {
+        assert_eq!(rgb(0xFF0000), 0x001F);
+        assert_eq!(rgb(0x00FF00), 0x03E0);
+        assert_eq!(rgb(0x0000FF), 0x7C00);
+        assert_eq!(rgb(0xFFAD63), 0x32BF);
+        assert_eq!(rgb(0x070707), 0x0000);
+    }
+
+    #[test]
+    fn titles_pick_their_palette() {
+        assert!(is(title_palette(&rom(b"POKEMON RED", 0x01)), 13));
+        assert!(is(title_palette(&rom(b"ZELDA", 0x01)), 44));
+
+        // New licensee code "01" is Nintendo too
+        let mut zelda = rom(b"ZELDA", 0x33);
+        zelda[0x144..0x146].copy_from_slice(b"01");
+        assert!(is(title_palette(&zelda), 44));
+    }
+
+    #[test]
+    fn fourth_letter_separates_shared_checksums() {
+        // Both sum to 0x61
+        assert!(is(title_palette(&rom(b"POKEMON BLUE", 0x01)), 11));
+        assert!(is(title_palette(&rom(b"VEGAS STAKES", 0x01)), 41));
+        // Also 0x61 but with a fourth letter in neither entry
+        assert!(ptr::eq(title_palette(&rom(b"POKFMON BLUD", 0x01)), DEFAULT));
+    }
+
+    #[test]
+    fn only_nintendo_titles_are_looked_up() {
+        assert!(ptr::eq(title_palette(&rom(b"POKEMON RED", 0x08)), DEFAULT));
+        assert!(ptr::eq(title_palette(&rom(b"POKEMON RED", 0x33)), DEFAULT));
+        assert!(ptr::eq(title_palette(&[0x01; 0x140]), DEFAULT));
+    }
+
+    #[test]
+    fn combos_are_a_direction_and_at_most_one_button() {
+        let up_a = parse_combo("up+a").unwrap();
+        assert!(is(combo_palette(up_a).unwrap(), 43));
+        assert!(is(combo_palette(parse_combo(" Right ").unwrap()).unwrap(), 1));
+        assert!(parse_combo("up+left").is_none());
+        assert!(parse_combo("a+b").is_none());
+        assert!(parse_combo("up+a+b").is_none());
+        assert!(parse_combo("up+turbo").is_none());
+        assert!(combo_palette(0).is_none());
+    }
+}
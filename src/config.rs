@@ -0,0 +1,538 @@
+use minifb::{Key, Window};
+use rustboy::emulator::MAX_FRAME_BLENDING;
+use rustboy::input::{InputMacro, BUTTON_NAMES, TURBO_OFF_FRAMES, TURBO_ON_FRAMES};
+use rustboy::palette::{self, DmgPalette};
+use rustboy::png::MAX_SCALE;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Key bindings read from a TOML file, by default $XDG_CONFIG_HOME/rustboy/config.toml
+// Only the part of TOML needed here is understood: [sections], comments, numbers,
+// "strings" and ["lists", "of strings"], bindings take minifb key names
+
+pub const DEFAULT_CONFIG: &str = r##"# rustboy configuration
+# Every binding takes a key name or a list of them, e.g. a = ["A", "Z"]
+# Key names are minifb's: A-Z, Key0-Key9, F1-F15, Up, Down, Left, Right, Space, Enter, Tab,
+# Backspace, Escape, LeftShift, RightShift, LeftCtrl, RightCtrl, NumPad0-NumPad9, ...
+
+[buttons]
+right = "Right"
+left = "Left"
+up = "Up"
+down = "Down"
+a = "A"
+b = "S"
+select = "Space"
+start = "Enter"
+
+# Controllers 2-4 for multiplayer SGB games, [player3] and [player4] work the same way
+[player2]
+right = "L"
+left = "J"
+up = "I"
+down = "K"
+a = "O"
+b = "U"
+select = "Key7"
+start = "Key8"
+
+# Held down these press the button for on_frames, then release it for off_frames
+[turbo]
+a = "Z"
+b = "X"
+on_frames = 2
+off_frames = 2
+
+[hotkeys]
+fast_forward = "Tab"
+pause = "P"
+frame_advance = "N"
+rewind = "Backspace"
+save_state = "F5"
+load_state = "F8"
+cycle_palette = "F2"
+cycle_filter = "F3"
+frame_blending = "F4"
+screenshot = "F12"
+record_gif = "F11"
+save_gif_buffer = "F10"
+
+# Screenshots are PNGs named after the time they were taken, scale enlarges them 1-8 times
+# A relative directory is relative to where rustboy was started
+[screenshot]
+directory = "screenshots"
+scale = 1
+
+# GIF clips go to the screenshot directory, record_gif starts and stops one
+# save_gif_buffer saves the last buffer_seconds of play, 0 stops keeping them
+[gif]
+buffer_seconds = 10
+
+# Frame blending keeps persistence percent of the previous picture in every frame, like the slow DMG LCD
+# It starts on for games whose header title is in games, e.g. games = ["NEMESIS", "KIRBY DREAM LAND"]
+[frame_blending]
+persistence = 50
+games = []
+
+# Macros replay a sequence of joypad states when their key is pressed
+# Each step is buttons joined with '+' (or "none") and how many frames to hold them
+# [macro.walk_right_then_a]
+# key = "F1"
+# steps = ["right:160", "none:10", "a:2"]
+
+# DMG palettes are added to the built in grey, dmg, pocket, light and high-contrast ones
+# colours sets background and sprites at once, bg, obj0 and obj1 set them separately and take precedence
+# [palette.sepia]
+# colours = ["#F8E8C8", "#D8A878", "#906838", "#302010"]
+# obj1 = ["#F8E8C8", "#E08070", "#A04030", "#302010"]
+"##;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    FastForward,
+    Pause,
+    FrameAdvance,
+    Rewind,
+    SaveState,
+    LoadState,
+    CyclePalette,
+    CycleFilter,
+    FrameBlending,
+    Screenshot,
+    RecordGif,
+    SaveGifBuffer,
+}
+
+const HOTKEY_NAMES: [(&str, Hotkey); 12] = [
+    ("fast_forward", Hotkey::FastForward),
+    ("pause", Hotkey::Pause),
+    ("frame_advance", Hotkey::FrameAdvance),
+    ("rewind", Hotkey::Rewind),
+    ("save_state", Hotkey::SaveState),
+    ("load_state", Hotkey::LoadState),
+    ("cycle_palette", Hotkey::CyclePalette),
+    ("cycle_filter", Hotkey::CycleFilter),
+    ("frame_blending", Hotkey::FrameBlending),
+    ("screenshot", Hotkey::Screenshot),
+    ("record_gif", Hotkey::RecordGif),
+    ("save_gif_buffer", Hotkey::SaveGifBuffer),
+];
+
+pub struct Config {
+    pub buttons: [Vec<Key>; 8],
+    pub turbo: [Vec<Key>; 8],
+    pub players: [[Vec<Key>; 8]; 3], // SGB controllers 2-4
+    pub hotkeys: Vec<(Hotkey, Vec<Key>)>,
+    pub turbo_on_frames: u32,
+    pub turbo_off_frames: u32,
+    pub macros: Vec<(Vec<Key>, InputMacro)>,
+    pub palettes: Vec<DmgPalette>,
+    pub screenshot_directory: PathBuf,
+    pub screenshot_scale: usize,
+    pub gif_buffer_seconds: u32,
+    pub frame_blending: u32,
+    pub frame_blending_games: Vec<String>,
+}
+
+impl Config {
+    // Reads the config file, writing out the defaults first if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            if let Some(directory) = path.parent() {
+                fs::create_dir_all(directory).map_err(|e| format!("Couldn't create {}: {}", directory.display(), e))?;
+            }
+            fs::write(path, DEFAULT_CONFIG).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+        }
+
+        let text = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Anything the file leaves out keeps its default binding
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Self {
+            buttons: Default::default(),
+            turbo: Default::default(),
+            players: Default::default(),
+            hotkeys: Vec::new(),
+            turbo_on_frames: TURBO_ON_FRAMES,
+            turbo_off_frames: TURBO_OFF_FRAMES,
+            macros: Vec::new(),
+            palettes: Vec::new(),
+            screenshot_directory: PathBuf::new(),
+            screenshot_scale: 1,
+            gif_buffer_seconds: 0,
+            frame_blending: 0,
+            frame_blending_games: Vec::new(),
+        };
+        config.apply(DEFAULT_CONFIG)?;
+        config.apply(text)?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, text: &str) -> Result<(), String> {
+        let mut section = String::new();
+        // bg, obj0 and obj1 of the current palette that were set on their own, colours leaves them alone
+        let mut separate_layers: [bool; 3] = [false; 3];
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                section = line.trim_start_matches('[').trim_end_matches(']').trim().to_string();
+                if let Some(name) = section.strip_prefix("macro.") {
+                    self.macros.retain(|binding| binding.1.name != name);
+                    self.macros.push((Vec::new(), InputMacro { name: name.to_string(), steps: Vec::new() }));
+                } else if let Some(name) = section.strip_prefix("palette.") {
+                    self.palettes.retain(|palette| palette.name != name);
+                    self.palettes.push(DmgPalette { name: name.to_string(), ..DmgPalette::default() });
+                    separate_layers = [false; 3];
+                } else if !["buttons", "turbo", "hotkeys", "screenshot", "gif", "frame_blending", "player2", "player3", "player4"].contains(&section.as_str()) {
+                    return Err(format!("line {}: unknown section [{}]", line_number, section));
+                }
+                continue;
+            }
+
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected name = value", line_number))?;
+            let name = name.trim();
+            let value = value.trim();
+            let error = |e: String| format!("line {}: {}.{}: {}", line_number, section, name, e);
+
+            match section.as_str() {
+                "turbo" if name == "on_frames" || name == "off_frames" => {
+                    let frames: u32 = value.parse().map_err(|_| error(format!("expected a number of frames, found {}", value)))?;
+                    if frames == 0 {
+                        return Err(error("must be at least 1 frame".to_string()));
+                    }
+                    if name == "on_frames" {
+                        self.turbo_on_frames = frames;
+                    } else {
+                        self.turbo_off_frames = frames;
+                    }
+                }
+                "buttons" | "turbo" | "player2" | "player3" | "player4" => {
+                    let keys = parse_keys(value).map_err(error)?;
+                    let index = BUTTON_NAMES
+                        .iter()
+                        .position(|button| *button == name)
+                        .ok_or_else(|| format!("line {}: unknown button \"{}\", expected one of {}", line_number, name, BUTTON_NAMES.join(", ")))?;
+                    match section.as_str() {
+                        "buttons" => self.buttons[index] = keys,
+                        "turbo" => self.turbo[index] = keys,
+                        _ => self.players[(section.as_bytes()[6] - b'2') as usize][index] = keys,
+                    }
+                }
+                "hotkeys" => {
+                    let keys = parse_keys(value).map_err(error)?;
+                    let hotkey = HOTKEY_NAMES
+                        .iter()
+                        .find(|hotkey| hotkey.0 == name)
+                        .ok_or_else(|| format!("line {}: unknown hotkey \"{}\"", line_number, name))?
+                        .1;
+                    self.hotkeys.retain(|binding| binding.0 != hotkey);
+                    self.hotkeys.push((hotkey, keys));
+                }
+                "screenshot" => match name {
+                    "directory" => {
+                        let directory = parse_strings(value).map_err(error)?;
+                        if directory.len() != 1 {
+                            return Err(error("expected a single directory".to_string()));
+                        }
+                        self.screenshot_directory = PathBuf::from(directory[0]);
+                    }
+                    "scale" => {
+                        self.screenshot_scale = value
+                            .parse()
+                            .ok()
+                            .filter(|scale| (1..=MAX_SCALE).contains(scale))
+                            .ok_or_else(|| error(format!("expected a scale from 1 to {}, found {}", MAX_SCALE, value)))?;
+                    }
+                    _ => return Err(error("screenshots only have directory and scale".to_string())),
+                },
+                "gif" if name == "buffer_seconds" => {
+                    self.gif_buffer_seconds = value.parse().map_err(|_| error(format!("expected a number of seconds, found {}", value)))?;
+                }
+                "gif" => return Err(error("gif only has buffer_seconds".to_string())),
+                "frame_blending" => match name {
+                    "persistence" => {
+                        self.frame_blending = value
+                            .parse()
+                            .ok()
+                            .filter(|persistence| (1..=MAX_FRAME_BLENDING).contains(persistence))
+                            .ok_or_else(|| error(format!("expected a percentage from 1 to {}, found {}", MAX_FRAME_BLENDING, value)))?;
+                    }
+                    "games" => self.frame_blending_games = parse_strings(value).map_err(error)?.iter().map(|title| title.to_string()).collect(),
+                    _ => return Err(error("frame blending only has persistence and games".to_string())),
+                },
+                _ if section.starts_with("macro.") => {
+                    let binding = self.macros.last_mut().unwrap();
+                    match name {
+                        "key" => binding.0 = parse_keys(value).map_err(error)?,
+                        "steps" => {
+                            let steps = parse_strings(value).map_err(error)?;
+                            binding.1 = InputMacro::parse(&binding.1.name, &steps).map_err(error)?;
+                        }
+                        _ => return Err(error("macros only have key and steps".to_string())),
+                    }
+                }
+                _ if section.starts_with("palette.") => {
+                    let colours = palette::parse_colours(&parse_strings(value).map_err(error)?).map_err(error)?;
+                    let dmg_palette = self.palettes.last_mut().unwrap();
+                    match name {
+                        "colours" => {
+                            let layers = [&mut dmg_palette.bg, &mut dmg_palette.obj0, &mut dmg_palette.obj1];
+                            for (layer, separate) in layers.into_iter().zip(separate_layers) {
+                                if !separate {
+                                    *layer = colours;
+                                }
+                            }
+                        }
+                        "bg" => (dmg_palette.bg, separate_layers[0]) = (colours, true),
+                        "obj0" => (dmg_palette.obj0, separate_layers[1]) = (colours, true),
+                        "obj1" => (dmg_palette.obj1, separate_layers[2]) = (colours, true),
+                        _ => return Err(error("palettes only have colours, bg, obj0 and obj1".to_string())),
+                    }
+                }
+                _ => return Err(format!("line {}: binding outside of a section", line_number)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Joypad bits for every bound button that is held down
+    pub fn joypad(&self, window: &Window) -> u8 {
+        Self::held(&self.buttons, window)
+    }
+
+    pub fn turbo(&self, window: &Window) -> u8 {
+        Self::held(&self.turbo, window)
+    }
+
+    // Player 2-4
+    pub fn player_joypad(&self, player: usize, window: &Window) -> u8 {
+        Self::held(&self.players[player - 2], window)
+    }
+
+    fn held(bindings: &[Vec<Key>; 8], window: &Window) -> u8 {
+        let mut joypad: u8 = 0;
+        for (i, keys) in bindings.iter().enumerate() {
+            if keys.iter().any(|key| window.is_key_down(*key)) {
+                joypad |= 1 << i;
+            }
+        }
+        joypad
+    }
+
+    pub fn hotkey(&self, hotkey: Hotkey) -> &[Key] {
+        self.hotkeys
+            .iter()
+            .find(|binding| binding.0 == hotkey)
+            .map(|binding| binding.1.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+// $XDG_CONFIG_HOME/rustboy/config.toml, falling back to ~/.config
+pub fn default_path() -> PathBuf {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(directory) if !directory.is_empty() => PathBuf::from(directory),
+        _ => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    base.join("rustboy").join("config.toml")
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+// A "string" or a ["list", "of strings"]
+fn parse_strings(value: &str) -> Result<Vec<&str>, String> {
+    let items: Vec<&str> = if value.starts_with('[') {
+        if !value.ends_with(']') {
+            return Err("unterminated list".to_string());
+        }
+        value[1..value.len() - 1]
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+            .collect()
+    } else {
+        vec![value]
+    };
+
+    let mut strings: Vec<&str> = Vec::new();
+    for item in items {
+        if item.len() < 2 || !item.starts_with('"') || !item.ends_with('"') {
+            return Err(format!("expected a quoted string, found {}", item));
+        }
+        strings.push(&item[1..item.len() - 1]);
+    }
+    Ok(strings)
+}
+
+fn parse_keys(value: &str) -> Result<Vec<Key>, String> {
+    let mut keys: Vec<Key> = Vec::new();
+    for name in parse_strings(value)? {
+        keys.push(key_from_name(name).ok_or_else(|| format!("unknown key name \"{}\"", name))?);
+    }
+    Ok(keys)
+}
+
+pub fn key_from_name(name: &str) -> Option<Key> {
+    ALL_KEYS.iter().find(|key| format!("{:?}", key) == name).copied()
+}
+
+const ALL_KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11,
+    Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus,
+    Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home, Key::Insert, Key::Menu,
+    Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6,
+    Key::NumPad7, Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk,
+    Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{key_from_name, parse_strings, Config, Hotkey};
+    use minifb::Key;
+    use rustboy::input::{TURBO_OFF_FRAMES, TURBO_ON_FRAMES};
+    use rustboy::palette::DmgPalette;
+    use std::path::Path;
+
+    #[test]
+    fn bindings_become_keys() {
+        let config = Config::parse("[buttons]\na = [\"Z\", \"Space\"]\n[hotkeys]\npause = \"F1\"\n").unwrap();
+        assert_eq!(config.buttons[4], [Key::Z, Key::Space]);
+        assert_eq!(config.hotkey(Hotkey::Pause), [Key::F1]);
+        // Rebinding a hotkey replaces the default instead of adding to it
+        assert_eq!(config.hotkeys.iter().filter(|binding| binding.0 == Hotkey::Pause).count(), 1);
+    }
+
+    #[test]
+    fn key_names_are_minifb_names() {
+        assert_eq!(key_from_name("NumPad5"), Some(Key::NumPad5));
+        assert_eq!(key_from_name("Key7"), Some(Key::Key7));
+        assert_eq!(key_from_name("numpad5"), None);
+        assert_eq!(key_from_name("Shift"), None);
+        let error = Config::parse("[buttons]\n\nstart = \"Return\"\n").err().unwrap();
+        assert_eq!(error, "line 3: buttons.start: unknown key name \"Return\"");
+    }
+
+    #[test]
+    fn bindings_need_a_known_section() {
+        assert_eq!(Config::parse("[controls]\n").err().unwrap(), "line 1: unknown section [controls]");
+        assert_eq!(Config::parse("# Keys\na = \"A\"\n").err().unwrap(), "line 2: binding outside of a section");
+        assert!(Config::parse("[buttons]\nturbo = \"T\"\n").err().unwrap().starts_with("line 2: unknown button \"turbo\""));
+        assert_eq!(Config::parse("[hotkeys]\nquit = \"Q\"\n").err().unwrap(), "line 2: unknown hotkey \"quit\"");
+    }
+
+    #[test]
+    fn strings_and_lists() {
+        assert_eq!(parse_strings("\"A\""), Ok(vec!["A"]));
+        assert_eq!(parse_strings("[\"A\", \"B\",]"), Ok(vec!["A", "B"]));
+        assert_eq!(parse_strings("[]"), Ok(vec![]));
+        assert_eq!(parse_strings("[\"A\", \"B\""), Err("unterminated list".to_string()));
+        assert_eq!(parse_strings("[A, \"B\"]"), Err("expected a quoted string, found A".to_string()));
+        assert_eq!(parse_strings("\"A"), Err("expected a quoted string, found \"A".to_string()));
+        // Comments end a line unless they're quoted
+        let config = Config::parse("[frame_blending]\ngames = [\"#1 HITS\"] # blend these\n").unwrap();
+        assert_eq!(config.frame_blending_games, ["#1 HITS"]);
+    }
+
+    #[test]
+    fn defaults_survive_a_partial_file() {
+        let config = Config::parse("[buttons]\nb = \"X\"\n[screenshot]\nscale = 3\n").unwrap();
+        assert_eq!(config.buttons[5], [Key::X]);
+        assert_eq!(config.buttons[4], [Key::A]);
+        assert_eq!(config.players[0][7], [Key::Key8]);
+        assert_eq!(config.hotkey(Hotkey::FastForward), [Key::Tab]);
+        assert_eq!(config.screenshot_scale, 3);
+        assert_eq!(config.screenshot_directory, Path::new("screenshots"));
+        assert_eq!(config.gif_buffer_seconds, 10);
+        assert_eq!(config.frame_blending, 50);
+    }
+
+    #[test]
+    fn turbo_buttons_and_period() {
+        let config = Config::parse("[turbo]\nselect = \"Q\"\non_frames = 4\n").unwrap();
+        assert_eq!(config.turbo[6], [Key::Q]);
+        assert_eq!(config.turbo[4], [Key::Z]);
+        assert_eq!((config.turbo_on_frames, config.turbo_off_frames), (4, TURBO_OFF_FRAMES));
+        assert_eq!(Config::parse("").unwrap().turbo_on_frames, TURBO_ON_FRAMES);
+
+        let error = Config::parse("[turbo]\noff_frames = 0\n").err().unwrap();
+        assert_eq!(error, "line 2: turbo.off_frames: must be at least 1 frame");
+        assert!(Config::parse("[turbo]\non_frames = fast\n").is_err());
+    }
+
+    const SEPIA: [u32; 4] = [0xF8E8C8, 0xD8A878, 0x906838, 0x302010];
+    const RED: [u32; 4] = [0xF8E8C8, 0xE08070, 0xA04030, 0x302010];
+    const GREEN: [u32; 4] = [0xE0F8D0, 0x88C070, 0x346856, 0x081820];
+
+    fn palette(text: &str) -> DmgPalette {
+        let config = Config::parse(text).unwrap();
+        assert_eq!(config.palettes.len(), 1);
+        config.palettes[0].clone()
+    }
+
+    #[test]
+    fn palette_colours_set_every_layer() {
+        let sepia = palette("[palette.sepia]\ncolours = [\"#F8E8C8\", \"#D8A878\", \"#906838\", \"#302010\"]\n");
+        assert_eq!(sepia, DmgPalette::uniform("sepia", SEPIA));
+    }
+
+    #[test]
+    fn palette_layers_override_colours_in_either_order() {
+        let colours = "colours = [\"#F8E8C8\", \"#D8A878\", \"#906838\", \"#302010\"]\n";
+        let layers = "obj1 = [\"#F8E8C8\", \"#E08070\", \"#A04030\", \"#302010\"]\nbg = [\"#E0F8D0\", \"#88C070\", \"#346856\", \"#081820\"]\n";
+        let expected = DmgPalette { name: "sepia".to_string(), bg: GREEN, obj0: SEPIA, obj1: RED };
+        assert_eq!(palette(&format!("[palette.sepia]\n{}{}", colours, layers)), expected);
+        assert_eq!(palette(&format!("[palette.sepia]\n{}{}", layers, colours)), expected);
+    }
+
+    #[test]
+    fn palette_layers_are_per_palette() {
+        let config = Config::parse(
+            "[palette.a]\nobj0 = [\"#E0F8D0\", \"#88C070\", \"#346856\", \"#081820\"]\n\
+             [palette.b]\ncolours = [\"#F8E8C8\", \"#D8A878\", \"#906838\", \"#302010\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.palettes[0].obj0, GREEN);
+        assert_eq!(config.palettes[0].bg, DmgPalette::default().bg);
+        assert_eq!(config.palettes[1], DmgPalette::uniform("b", SEPIA));
+    }
+
+    #[test]
+    fn palette_errors_name_the_line() {
+        let error = Config::parse("[palette.x]\nbg = [\"#FFFFFF\", \"#000000\"]\n").err().unwrap();
+        assert!(error.starts_with("line 2: palette.x.bg: expected 4 colours"), "{}", error);
+        let error = Config::parse("[palette.x]\nobj2 = [\"#FFFFFF\", \"#AAAAAA\", \"#555555\", \"#000000\"]\n").err().unwrap();
+        assert!(error.contains("palettes only have colours, bg, obj0 and obj1"), "{}", error);
+    }
+}
@@ -1,6 +1,7 @@
-use crate::mmu::MMU;
+use crate::mmu::{Model, MMU};
 use crate::registers::Flags;
 use crate::registers::Registers;
+use crate::state::{write_u16, StateReader};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -31,14 +32,59 @@ impl CPU {
             is_halted: false,
         };
 
-        cpu.registers.set_af(0x01B0);
-        cpu.registers.set_bc(0x0013);
-        cpu.registers.set_de(0x00D8);
-        cpu.registers.set_hl(0x014D);
+        // Register values the boot ROM leaves behind, games check for A = 0x11 to detect a CGB
+        if cpu.mmu.borrow().model == Model::Cgb {
+            cpu.registers.set_af(0x1180);
+            cpu.registers.set_bc(0x0000);
+            cpu.registers.set_de(0xFF56);
+            cpu.registers.set_hl(0x000D);
+        } else if cpu.mmu.borrow().model == Model::Sgb {
+            cpu.registers.set_af(0x0100);
+            cpu.registers.set_bc(0x0014);
+            cpu.registers.set_de(0x0000);
+            cpu.registers.set_hl(0xC060);
+        } else {
+            cpu.registers.set_af(0x01B0);
+            cpu.registers.set_bc(0x0013);
+            cpu.registers.set_de(0x00D8);
+            cpu.registers.set_hl(0x014D);
+        }
 
         cpu
     }
 
+    pub fn save_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&[
+            self.registers.a,
+            self.registers.f,
+            self.registers.b,
+            self.registers.c,
+            self.registers.d,
+            self.registers.e,
+            self.registers.h,
+            self.registers.l,
+        ]);
+        write_u16(state, self.registers.sp);
+        write_u16(state, self.registers.pc);
+        state.push(self.interrupt_master as u8);
+        state.push(self.is_halted as u8);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.registers.a = state.read_u8();
+        self.registers.f = state.read_u8();
+        self.registers.b = state.read_u8();
+        self.registers.c = state.read_u8();
+        self.registers.d = state.read_u8();
+        self.registers.e = state.read_u8();
+        self.registers.h = state.read_u8();
+        self.registers.l = state.read_u8();
+        self.registers.sp = state.read_u16();
+        self.registers.pc = state.read_u16();
+        self.interrupt_master = state.read_bool();
+        self.is_halted = state.read_bool();
+    }
+
     pub fn fetch_byte(&mut self) -> u8 {
         let b = self.mmu.borrow_mut().rb(self.registers.pc);
         self.registers.pc += 1;
@@ -106,7 +152,7 @@ impl CPU {
             0x0D => { self.registers.c = self.alu_dec(self.registers.c); 1 }
             0x0E => { self.registers.c = self.fetch_byte(); 2 }
             0x0F => { self.registers.a = self.alu_rrc(self.registers.a); self.registers.clear_flag(Flags::Zero); 1 }
-            0x10 => { 1 } // STOP, Check Implementation
+            0x10 => { self.mmu.borrow_mut().speed_switch(); 1 } // STOP, only the CGB speed switch is implemented
             0x11 => { let w: u16 = self.fetch_word(); self.registers.set_de(w); 3 }
             0x12 => { self.mmu.borrow_mut().wb(self.registers.de(), self.registers.a); 2 }
             0x13 => { self.registers.set_de(self.registers.de().wrapping_add(1)); 2 }
@@ -0,0 +1,779 @@
+use crate::capture;
+use crate::compat;
+use crate::cpu::CPU;
+use crate::gif::GifRecorder;
+use crate::gpu::{ColourCorrection, GPU};
+use crate::hash::crc32;
+use crate::input::{InputMacro, MacroPlayer, Turbo, TURBO_OFF_FRAMES, TURBO_ON_FRAMES};
+use crate::mmu::{Model, MMU};
+use crate::movie::{Movie, MoviePlayer, MovieRecorder, StartCondition};
+use crate::palette::DmgPalette;
+use crate::png;
+use crate::rewind::Rewind;
+use crate::sgb;
+use crate::state;
+use crate::video::VideoRecorder;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub const MAXCYCLES: u32 = 70221; // Cycles per frame
+pub const MIN_SPEED: u32 = 25; // Percent
+pub const MAX_SPEED: u32 = 800; // Percent
+
+pub const MAX_FRAME_BLENDING: u32 = 90; // Percent, beyond this moving sprites leave long trails
+
+pub const REWIND_INTERVAL: u32 = 4; // Frames between snapshots
+pub const REWIND_BUDGET: usize = 64 * 1024 * 1024; // Bytes
+
+// Save state files: magic, format version, CRC-32 of the ROM, then the snapshot
+const STATE_FILE_MAGIC: &[u8; 4] = b"RBST";
+const STATE_FILE_VERSION: u8 = 1;
+
+// Time spent emulating per displayed frame while fast forwarding, frames in between are skipped
+const FAST_FORWARD_SLICE: Duration = Duration::from_millis(15);
+
+pub struct Emulator {
+    pub cpu: CPU,
+    pub gpu: GPU,
+    pub mmu: Rc<RefCell<MMU>>,
+    pub rewind: Rewind,
+    rom: Vec<u8>,
+    model: Model,
+    colour_correction: ColourCorrection,
+    dmg_palette: DmgPalette,
+    compat_buttons: u8, // Button combo held at power-on of a DMG cartridge on CGB, picks its palette
+    ppu_access_restrictions: bool,
+    frame_count: u32,
+    frame_blending: u32, // Percentage of the previous picture kept in the next one, 0 is off
+    blended_screen: Vec<u32>,
+    joypad: u8,
+    player_joypads: [u8; 3], // SGB controllers 2-4
+    turbo: Turbo,
+    macro_player: Option<MacroPlayer>,
+    recorder: Option<MovieRecorder>,
+    player: Option<MoviePlayer>,
+    video: Option<VideoRecorder>,
+    gif: Option<GifRecorder>,
+    gif_buffer: Option<GifRecorder>, // The last few seconds, always recording when enabled
+    movie_frame: u32,
+    speed: u32,
+    speed_credit: u32,
+    fast_forward: bool,
+    paused: bool,
+    frame_advance: bool,
+}
+
+impl Emulator {
+    // Power-on state only depends on the ROM (all RAM starts zeroed) so runs are fully deterministic
+    // The model is picked from the cartridge header
+    pub fn new(rom: Vec<u8>) -> Self {
+        let model = Model::from_rom(&rom);
+        Self::with_model(rom, model)
+    }
+
+    pub fn with_model(rom: Vec<u8>, model: Model) -> Self {
+        let mmu: Rc<RefCell<MMU>> = Rc::new(RefCell::new(MMU::with_model(rom.clone(), model)));
+        let mut emulator = Self {
+            cpu: CPU::new(Rc::clone(&mmu)),
+            gpu: GPU::new(Rc::clone(&mmu)),
+            mmu,
+            rewind: Rewind::new(REWIND_INTERVAL, REWIND_BUDGET),
+            rom,
+            model,
+            colour_correction: ColourCorrection::None,
+            dmg_palette: DmgPalette::default(),
+            compat_buttons: 0,
+            ppu_access_restrictions: true,
+            frame_count: 0,
+            frame_blending: 0,
+            blended_screen: Vec::new(),
+            joypad: 0,
+            player_joypads: [0; 3],
+            turbo: Turbo::new(TURBO_ON_FRAMES, TURBO_OFF_FRAMES),
+            macro_player: None,
+            recorder: None,
+            player: None,
+            video: None,
+            gif: None,
+            gif_buffer: None,
+            movie_frame: 0,
+            speed: 100,
+            speed_credit: 0,
+            fast_forward: false,
+            paused: false,
+            frame_advance: false,
+        };
+        emulator.load_compat_palette();
+        emulator
+    }
+
+    // Back to power-on state with the same ROM
+    pub fn reset(&mut self) {
+        self.mmu = Rc::new(RefCell::new(MMU::with_model(self.rom.clone(), self.model)));
+        self.cpu = CPU::new(Rc::clone(&self.mmu));
+        self.gpu = GPU::new(Rc::clone(&self.mmu));
+        self.gpu.set_colour_correction(self.colour_correction);
+        self.gpu.set_dmg_palette(self.dmg_palette.clone());
+        self.mmu.borrow_mut().ppu_access_restrictions = self.ppu_access_restrictions;
+        self.load_compat_palette();
+        self.rewind.clear();
+        self.frame_count = 0;
+        self.blended_screen.clear();
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // The title in the cartridge header, CGB cartridges have a shorter one
+    pub fn rom_title(&self) -> String {
+        self.rom
+            .get(0x134..0x144)
+            .unwrap_or(&[])
+            .iter()
+            .take_while(|c| c.is_ascii_graphic() || **c == b' ')
+            .map(|c| *c as char)
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        crc32(&self.rom)
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    // Buttons held by the player, see movie.rs for the bit layout
+    // Ignored while a movie is playing
+    pub fn set_joypad(&mut self, joypad: u8) {
+        self.joypad = joypad;
+    }
+
+    // Buttons held on SGB controllers 2-4 for games that use MLT_REQ, movies only keep the first controller
+    pub fn set_player_joypad(&mut self, player: usize, joypad: u8) {
+        if (2..=4).contains(&player) {
+            self.player_joypads[player - 2] = joypad;
+        }
+    }
+
+    // Buttons held with turbo, these are pressed and released following self.turbo's period
+    pub fn set_turbo(&mut self, turbo: u8) {
+        self.turbo.set_held(turbo);
+    }
+
+    // Frames turbo buttons stay pressed and released, at least one each
+    pub fn set_turbo_period(&mut self, on_frames: u32, off_frames: u32) {
+        self.turbo.set_period(on_frames, off_frames);
+    }
+
+    // Replaces the player's input with the macro until it finishes
+    pub fn play_macro(&mut self, input_macro: &InputMacro) {
+        self.macro_player = Some(MacroPlayer::new(input_macro));
+    }
+
+    pub fn stop_macro(&mut self) {
+        self.macro_player = None;
+    }
+
+    pub fn is_macro_playing(&self) -> bool {
+        self.macro_player.is_some()
+    }
+
+    fn apply_joypad(&mut self, joypad: u8) {
+        let mut mmu = self.mmu.borrow_mut();
+        for i in 0..8 {
+            if joypad & (1 << i) > 0 {
+                mmu.poll_key_pressed(i);
+            } else {
+                mmu.poll_key_released(i);
+            }
+        }
+        for (i, player_joypad) in self.player_joypads.iter().enumerate() {
+            mmu.set_player_joypad(i + 1, *player_joypad);
+        }
+    }
+
+    // 256x224 with the SGB border, otherwise the 160x144 LCD
+    pub fn screen_size(&self) -> (usize, usize) {
+        if self.model == Model::Sgb {
+            (sgb::SCREEN_WIDTH, sgb::SCREEN_HEIGHT)
+        } else {
+            (160, 144)
+        }
+    }
+
+    // The picture to display as RGB888 pixels, screen_size() gives its dimensions
+    // With frame blending on it's mixed with the frames before it
+    pub fn screen(&self) -> Vec<u32> {
+        if self.frame_blending > 0 && !self.blended_screen.is_empty() {
+            return self.blended_screen.clone();
+        }
+        self.unblended_screen()
+    }
+
+    fn unblended_screen(&self) -> Vec<u32> {
+        match &self.mmu.borrow().sgb {
+            Some(sgb) => {
+                let mut screen: Vec<u32> = vec![0; sgb::SCREEN_WIDTH * sgb::SCREEN_HEIGHT];
+                sgb.render(&mut screen);
+                screen
+            }
+            None => self.gpu.screen_data.to_vec(),
+        }
+    }
+
+    // The current picture as a PNG, every pixel drawn as a scale x scale block
+    pub fn screenshot(&self, scale: usize) -> Vec<u8> {
+        let (width, height) = self.screen_size();
+        png::encode(&self.screen(), width, height, scale)
+    }
+
+    // Saves a screenshot with a timestamped name in directory, returning where it went and the image itself
+    pub fn save_screenshot(&self, directory: &Path, scale: usize) -> io::Result<(PathBuf, Vec<u8>)> {
+        let image = self.screenshot(scale);
+        let path = capture::save_capture(directory, "png", &image)?;
+        Ok((path, image))
+    }
+
+    // Emulates a single frame regardless of speed or pause settings
+    pub fn run_frame(&mut self) {
+        // Turbo and macros feed in ahead of the movie, so recordings hold the buttons they produced
+        let mut joypad = self.joypad | self.turbo.next_frame();
+        if let Some(macro_player) = self.macro_player.as_mut() {
+            match macro_player.next_frame() {
+                Some(macro_joypad) => joypad = macro_joypad,
+                None => self.macro_player = None,
+            }
+        }
+        if let Some(player) = self.player.as_mut() {
+            match player.joypad(self.movie_frame) {
+                Some(movie_joypad) => joypad = movie_joypad,
+                None => self.player = None,
+            }
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(self.movie_frame, joypad);
+        }
+        self.apply_joypad(joypad);
+
+        let mut cycles_elapsed: u32 = 0;
+        let mut frame_shown = false;
+        while cycles_elapsed < MAXCYCLES {
+            // A halted CPU still uses up time, otherwise the length of a frame would depend on the previous instruction
+            let mut cycles: u16 = 4;
+            let dma_stall: u16 = self.mmu.borrow_mut().take_dma_stall();
+            if dma_stall > 0 {
+                // The CPU sits out VRAM DMA transfers
+                cycles = dma_stall;
+            } else if !self.cpu.is_halted {
+                let opcode = self.cpu.fetch_byte();
+                cycles = (self.cpu.execute(opcode) as u16) * 4;
+            }
+            // In double speed mode the CPU, timers and DMA run twice as fast as the PPU
+            let ppu_cycles: u16 = if self.mmu.borrow().is_double_speed() { cycles / 2 } else { cycles };
+            cycles_elapsed += ppu_cycles as u32;
+
+            self.mmu.borrow_mut().update_timers(cycles);
+            self.mmu.borrow_mut().update_dma(cycles);
+            self.gpu.update_graphics(ppu_cycles);
+            if self.gpu.take_frame_ready() {
+                self.frame_end();
+                frame_shown = true;
+            }
+            self.cpu.do_interrupts();
+        }
+
+        // With the LCD off there's no V-Blank, the blank screen still lasts a frame
+        if !frame_shown && self.mmu.borrow().io_ram[0xFF40 - 0xFF00] & (1 << 7) == 0 {
+            self.frame_end();
+        }
+
+        self.frame_count += 1;
+        self.movie_frame += 1;
+
+        // Rewinding would desync a movie so no snapshots are taken while one is active
+        if !self.is_movie_active() && self.rewind.frame_elapsed() {
+            self.rewind.push(state::save_state(&self.cpu, &self.gpu));
+        }
+    }
+
+    // Blends and records the finished screen, as V-Blank starts so frames don't drift from the LCD's
+    fn frame_end(&mut self) {
+        if self.frame_blending > 0 {
+            let screen = self.unblended_screen();
+            if self.blended_screen.len() == screen.len() {
+                blend_frames(&mut self.blended_screen, &screen, self.frame_blending);
+            } else {
+                self.blended_screen = screen;
+            }
+        }
+
+        // Every emulated frame is recorded, including ones fast forward or a slow speed never shows
+        if self.video.is_some() || self.gif.is_some() || self.gif_buffer.is_some() {
+            let screen = self.screen();
+            let recorders = (self.video.as_mut(), self.gif.as_mut(), self.gif_buffer.as_mut());
+            if let Some(video) = recorders.0 {
+                video.add_frame(&screen);
+            }
+            if let Some(gif) = recorders.1 {
+                gif.add_frame(&screen);
+            }
+            if let Some(gif_buffer) = recorders.2 {
+                gif_buffer.add_frame(&screen);
+            }
+        }
+    }
+
+    // Records every frame from now on to a Y4M file, with a silent WAV of the same length next to it
+    pub fn start_video(&mut self, path: &Path) -> io::Result<()> {
+        let (width, height) = self.screen_size();
+        self.video = Some(VideoRecorder::new(path, width, height)?);
+        Ok(())
+    }
+
+    // Finishes the files, reporting any write that failed while recording
+    pub fn stop_video(&mut self) -> io::Result<()> {
+        match self.video.take() {
+            Some(video) => video.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    // Records a GIF clip until stop_gif()
+    pub fn start_gif(&mut self) {
+        let (width, height) = self.screen_size();
+        self.gif = Some(GifRecorder::new(width, height));
+    }
+
+    // The encoded clip, None if nothing was recorded
+    pub fn stop_gif(&mut self) -> Option<Vec<u8>> {
+        self.gif.take().filter(|gif| !gif.is_empty()).map(|gif| gif.encode())
+    }
+
+    pub fn is_recording_gif(&self) -> bool {
+        self.gif.is_some()
+    }
+
+    // Keeps the last seconds of frames around for gif_buffer(), 0 turns it off
+    pub fn set_gif_buffer(&mut self, seconds: u32) {
+        let (width, height) = self.screen_size();
+        self.gif_buffer = if seconds > 0 { Some(GifRecorder::rolling(width, height, seconds)) } else { None };
+    }
+
+    // The last seconds as a GIF clip, None if the buffer is off or empty
+    pub fn gif_buffer(&self) -> Option<Vec<u8>> {
+        self.gif_buffer.as_ref().filter(|gif| !gif.is_empty()).map(|gif| gif.encode())
+    }
+
+    // Emulates however many frames belong to one displayed frame and returns that count
+    // Paused: nothing unless a frame advance was requested
+    // Fast forward: as many frames as fit in FAST_FORWARD_SLICE, only the last one is shown
+    // Otherwise: speed / 100 frames on average, so 25% runs one frame every fourth call
+    pub fn update(&mut self) -> u32 {
+        if self.paused {
+            if self.frame_advance {
+                self.frame_advance = false;
+                self.run_frame();
+                return 1;
+            }
+            return 0;
+        }
+
+        let mut frames: u32 = 0;
+        if self.fast_forward {
+            let start = Instant::now();
+            while frames == 0 || start.elapsed() < FAST_FORWARD_SLICE {
+                self.run_frame();
+                frames += 1;
+            }
+            return frames;
+        }
+
+        self.speed_credit += self.speed;
+        while self.speed_credit >= 100 {
+            self.speed_credit -= 100;
+            self.run_frame();
+            frames += 1;
+        }
+        frames
+    }
+
+    // Steps back to the previous rewind snapshot, returns false once none are left
+    pub fn rewind_step(&mut self) -> bool {
+        if self.is_movie_active() {
+            return false;
+        }
+        match self.rewind.pop() {
+            Some(snapshot) => {
+                state::load_state(&mut self.cpu, &mut self.gpu, &snapshot);
+                self.blended_screen.clear();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save_state(&self.cpu, &self.gpu)
+    }
+
+    pub fn load_state(&mut self, snapshot: &[u8]) {
+        state::load_state(&mut self.cpu, &mut self.gpu, snapshot);
+        self.blended_screen.clear();
+    }
+
+    pub fn save_state_file(&self, path: &Path) -> io::Result<()> {
+        let mut data: Vec<u8> = STATE_FILE_MAGIC.to_vec();
+        data.push(STATE_FILE_VERSION);
+        data.extend_from_slice(&self.rom_hash().to_le_bytes());
+        data.extend(self.save_state());
+        fs::write(path, data)
+    }
+
+    // Refuses files from other emulators, other versions of the format and other ROMs
+    pub fn load_state_file(&mut self, path: &Path) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if data.len() < 9 || &data[0..4] != STATE_FILE_MAGIC {
+            return Err(invalid("not a rustboy save state".to_string()));
+        }
+        if data[4] != STATE_FILE_VERSION {
+            return Err(invalid(format!("unsupported save state version {}", data[4])));
+        }
+        let rom_hash = u32::from_le_bytes([data[5], data[6], data[7], data[8]]);
+        if rom_hash != self.rom_hash() {
+            return Err(invalid(format!("save state is for a different ROM (crc32 {:08x}, loaded {:08x})", rom_hash, self.rom_hash())));
+        }
+        // Same ROM but another model or hardware configuration
+        let snapshot = &data[9..];
+        if snapshot.len() != self.save_state().len() {
+            return Err(invalid("save state doesn't match this emulator's hardware".to_string()));
+        }
+        self.load_state(snapshot);
+        Ok(())
+    }
+
+    // Mixes every frame with the ones before it like the slow LCD of a DMG, which games with flickering
+    // sprites or transparency rely on; persistence is the percentage of the previous picture that is kept
+    // Screenshots and recordings get the blended picture too, 0 turns it off
+    pub fn set_frame_blending(&mut self, persistence: u32) {
+        self.frame_blending = persistence.min(MAX_FRAME_BLENDING);
+        self.blended_screen.clear();
+    }
+
+    pub fn frame_blending(&self) -> u32 {
+        self.frame_blending
+    }
+
+    // Speed is a percentage of normal speed, clamped between MIN_SPEED and MAX_SPEED
+    pub fn set_speed(&mut self, speed: u32) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.speed_credit = 0;
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    pub fn is_fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.frame_advance = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Runs exactly one frame on the next update, only has an effect while paused
+    pub fn frame_advance(&mut self) {
+        if self.paused {
+            self.frame_advance = true;
+        }
+    }
+
+    // VRAM and OAM are blocked for the CPU while the PPU uses them, turning that off helps when debugging homebrew
+    pub fn set_ppu_access_restrictions(&mut self, enabled: bool) {
+        self.ppu_access_restrictions = enabled;
+        self.mmu.borrow_mut().ppu_access_restrictions = enabled;
+    }
+
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.colour_correction = correction;
+        self.gpu.set_colour_correction(correction);
+    }
+
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette.clone();
+        self.gpu.set_dmg_palette(palette);
+    }
+
+    pub fn dmg_palette(&self) -> &DmgPalette {
+        &self.dmg_palette
+    }
+
+    // Stands in for holding a direction plus optionally A or B during the CGB boot logo, 0 picks by title
+    // Applies straight away, like restarting with the buttons held
+    pub fn set_compat_palette(&mut self, buttons: u8) {
+        self.compat_buttons = buttons;
+        self.load_compat_palette();
+    }
+
+    // The palettes the CGB boot ROM sets up for DMG cartridges
+    fn load_compat_palette(&mut self) {
+        let mut mmu = self.mmu.borrow_mut();
+        if self.model == Model::Cgb && !mmu.is_cgb_mode() {
+            compat::load_palette(&mut mmu, &self.rom, self.compat_buttons);
+        }
+    }
+
+    // Movies
+
+    pub fn start_recording(&mut self, from_power_on: bool) {
+        let start = if from_power_on {
+            self.reset();
+            StartCondition::PowerOn
+        } else {
+            StartCondition::SaveState(state::save_state(&self.cpu, &self.gpu))
+        };
+        self.player = None;
+        self.recorder = Some(MovieRecorder::new(Movie::new(self.rom_hash(), self.model, start)));
+        self.movie_frame = 0;
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recorder.take().map(|recorder| recorder.finish())
+    }
+
+    pub fn play_movie(&mut self, movie: Movie) -> io::Result<()> {
+        if movie.rom_hash != self.rom_hash() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("movie was recorded with a different ROM (crc32 {:08x}, loaded {:08x})", movie.rom_hash, self.rom_hash()),
+            ));
+        }
+        if movie.model != self.model {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("movie was recorded on a {:?}, running as a {:?}", movie.model, self.model),
+            ));
+        }
+        if let StartCondition::SaveState(snapshot) = &movie.start {
+            if snapshot.len() != self.save_state().len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "movie's save state doesn't match this emulator's hardware"));
+            }
+        }
+
+        match &movie.start {
+            StartCondition::PowerOn => self.reset(),
+            StartCondition::SaveState(snapshot) => {
+                state::load_state(&mut self.cpu, &mut self.gpu, snapshot);
+                self.rewind.clear();
+            }
+        }
+        self.recorder = None;
+        self.player = Some(MoviePlayer::new(movie));
+        self.movie_frame = 0;
+        Ok(())
+    }
+
+    pub fn stop_playback(&mut self) {
+        self.player = None;
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        self.player.is_some()
+    }
+
+    pub fn is_recording_movie(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    fn is_movie_active(&self) -> bool {
+        self.player.is_some() || self.recorder.is_some()
+    }
+}
+
+// Moves every channel of previous towards current, leaving persistence percent of the difference
+// Truncating the difference rather than the result lets pixels that stop changing reach their colour exactly
+fn blend_frames(previous: &mut [u32], current: &[u32], persistence: u32) {
+    for (old, new) in previous.iter_mut().zip(current) {
+        let mut pixel: u32 = 0;
+        for shift in [16, 8, 0] {
+            let old_channel = ((*old >> shift) & 0xFF) as i32;
+            let new_channel = ((*new >> shift) & 0xFF) as i32;
+            let channel = new_channel + (old_channel - new_channel) * persistence as i32 / 100;
+            pixel |= (channel as u32) << shift;
+        }
+        *old = pixel;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{blend_frames, Emulator, REWIND_INTERVAL};
+    use crate::mmu::Model;
+    use crate::movie::{Movie, StartCondition};
+    use std::time::{Duration, Instant};
+
+    // Copies the joypad lines to WRAM from 0xC000 to 0xCFFF over and over, so the state depends on the input
+    pub(crate) fn joypad_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        let program = [
+            0x21, 0x00, 0xC0, // ld hl, 0xC000
+            0x3E, 0x20, // ld a, 0x20
+            0xE0, 0x00, // ldh (0x00), a
+            0xF0, 0x00, // ldh a, (0x00)
+            0x22, // ld (hl+), a
+            0x7C, // ld a, h
+            0xFE, 0xD0, // cp 0xD0
+            0x20, 0xF5, // jr nz, -11
+            0x21, 0x00, 0xC0, // ld hl, 0xC000
+            0x18, 0xF0, // jr -16
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        rom
+    }
+
+    fn run_frames(emulator: &mut Emulator, frames: u32) {
+        for frame in 0..frames {
+            emulator.set_joypad(if frame % 3 == 0 { 0x01 } else { 0x04 });
+            emulator.run_frame();
+        }
+    }
+
+    #[test]
+    fn frame_blending_fades_to_the_new_colour() {
+        let mut screen: Vec<u32> = vec![0xFFFFFF, 0x000000, 0x336699];
+        let current: Vec<u32> = vec![0x000000, 0xFFFFFF, 0x336699];
+        blend_frames(&mut screen, &current, 50);
+        assert_eq!(screen, [0x7F7F7F, 0x808080, 0x336699]);
+
+        // Pixels that stop changing end up at exactly their colour, from either side
+        for _ in 0..10 {
+            blend_frames(&mut screen, &current, 50);
+        }
+        assert_eq!(screen, current);
+    }
+
+    #[test]
+    fn ppu_access_restrictions_survive_a_reset() {
+        let mut emulator = Emulator::new(vec![0; 0x8000]);
+        emulator.set_ppu_access_restrictions(false);
+        emulator.reset();
+        assert!(!emulator.mmu.borrow().ppu_access_restrictions);
+
+        // Movies reset the emulator when they start
+        emulator.start_recording(true);
+        assert!(!emulator.mmu.borrow().ppu_access_restrictions);
+    }
+
+    #[test]
+    fn state_files_only_load_for_the_same_rom() {
+        let directory = std::env::temp_dir().join(format!("rustboy-state-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("game.state");
+
+        let mut emulator = Emulator::new(vec![0; 0x8000]);
+        for _ in 0..10 {
+            emulator.run_frame();
+        }
+        emulator.save_state_file(&path).unwrap();
+        let saved = emulator.save_state();
+        for _ in 0..10 {
+            emulator.run_frame();
+        }
+        emulator.load_state_file(&path).unwrap();
+        assert!(emulator.save_state() == saved);
+
+        let mut other = Emulator::new(vec![1; 0x8000]);
+        let before = other.save_state();
+        assert!(other.load_state_file(&path).is_err());
+        assert!(other.save_state() == before);
+
+        // A bare snapshot of the right length, as written before the header existed
+        std::fs::write(&path, &saved).unwrap();
+        assert!(emulator.load_state_file(&path).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn movies_only_play_on_matching_hardware() {
+        let mut emulator = Emulator::new(joypad_rom());
+        run_frames(&mut emulator, 2);
+        let before = emulator.save_state();
+
+        // A truncated snapshot is rejected before any state is touched
+        let movie = Movie::new(emulator.rom_hash(), Model::Dmg, StartCondition::SaveState(vec![0; 10]));
+        assert!(emulator.play_movie(movie).is_err());
+        assert!(emulator.save_state() == before);
+
+        let movie = Movie::new(emulator.rom_hash(), Model::Cgb, StartCondition::PowerOn);
+        assert!(emulator.play_movie(movie).is_err());
+        assert!(emulator.save_state() == before);
+
+        let movie = Movie::new(emulator.rom_hash(), Model::Dmg, StartCondition::SaveState(before.clone()));
+        assert!(emulator.play_movie(movie).is_ok());
+    }
+
+    #[test]
+    fn rewind_restores_the_snapshotted_state() {
+        let mut emulator = Emulator::new(joypad_rom());
+        run_frames(&mut emulator, REWIND_INTERVAL);
+        let first = emulator.save_state();
+        run_frames(&mut emulator, REWIND_INTERVAL);
+        let second = emulator.save_state();
+
+        emulator.set_joypad(0x10);
+        emulator.run_frame();
+        assert!(emulator.save_state() != second);
+
+        assert!(emulator.rewind_step());
+        assert!(emulator.save_state() == second);
+        assert!(emulator.rewind_step());
+        assert!(emulator.save_state() == first);
+    }
+
+    // Snapshots are only taken every REWIND_INTERVAL frames, spread over those they must cost less than a tenth of the frame time
+    #[test]
+    fn rewind_snapshots_are_cheap() {
+        let mut emulator = Emulator::new(joypad_rom());
+        emulator.rewind.interval = u32::MAX;
+        run_frames(&mut emulator, 10);
+
+        let (mut frame_time, mut snapshot_time) = (Duration::ZERO, Duration::ZERO);
+        for _ in 0..60 {
+            let start = Instant::now();
+            run_frames(&mut emulator, 1);
+            frame_time += start.elapsed();
+
+            let start = Instant::now();
+            let snapshot = emulator.save_state();
+            emulator.rewind.push(snapshot);
+            snapshot_time += start.elapsed();
+        }
+        assert!(snapshot_time * 10 < frame_time * REWIND_INTERVAL, "snapshots took {:?}, frames {:?}", snapshot_time, frame_time);
+    }
+}
@@ -0,0 +1,464 @@
+// Upscaling filters between the emulated screen and the window, all on the CPU
+// minifb stretches whatever comes out to the window, so filters only decide how the extra pixels look
+
+pub trait Filter {
+    // What --filter and the config file call it
+    fn name(&self) -> &'static str;
+
+    // Output pixels per input pixel in each direction
+    fn scale(&self) -> usize;
+
+    // RGB888 pixels, width * scale by height * scale of them come out
+    fn apply(&self, input: &[u32], width: usize, height: usize) -> Vec<u32>;
+}
+
+// Every filter, in the order the cycle_filter hotkey goes through them
+pub fn filters() -> Vec<Box<dyn Filter>> {
+    vec![
+        Box::new(Unfiltered),
+        Box::new(Scale2x),
+        Box::new(Scale3x),
+        Box::new(Smooth { scale: 2 }),
+        Box::new(Smooth { scale: 3 }),
+        Box::new(Smooth { scale: 4 }),
+        Box::new(LcdGrid { scale: 3 }),
+        Box::new(Scanlines { scale: 2 }),
+    ]
+}
+
+pub fn from_name(name: &str) -> Option<Box<dyn Filter>> {
+    filters().into_iter().find(|filter| filter.name() == name)
+}
+
+// The 3x3 neighbourhood of every pixel, row by row, edge pixels repeat outwards
+fn for_each_neighbourhood(input: &[u32], width: usize, height: usize, mut f: impl FnMut(usize, usize, [u32; 9])) {
+    for y in 0..height {
+        let rows = [y.saturating_sub(1), y, (y + 1).min(height - 1)];
+        for x in 0..width {
+            let columns = [x.saturating_sub(1), x, (x + 1).min(width - 1)];
+            let mut neighbours: [u32; 9] = [0; 9];
+            for (i, row) in rows.iter().enumerate() {
+                for (j, column) in columns.iter().enumerate() {
+                    neighbours[i * 3 + j] = input[row * width + column];
+                }
+            }
+            f(x, y, neighbours);
+        }
+    }
+}
+
+// Writes a scale x scale block for every input pixel
+fn scale_blocks(input: &[u32], width: usize, height: usize, scale: usize, mut block: impl FnMut([u32; 9], &mut [u32])) -> Vec<u32> {
+    let mut output: Vec<u32> = vec![0; width * height * scale * scale];
+    let mut pixels: Vec<u32> = vec![0; scale * scale];
+    for_each_neighbourhood(input, width, height, |x, y, neighbours| {
+        block(neighbours, &mut pixels);
+        for row in 0..scale {
+            let start = (y * scale + row) * width * scale + x * scale;
+            output[start..start + scale].copy_from_slice(&pixels[row * scale..(row + 1) * scale]);
+        }
+    });
+    output
+}
+
+// weight / 256 of b, the rest a, per channel
+fn mix(a: u32, b: u32, weight: u32) -> u32 {
+    let channel = |shift: u32| ((((a >> shift) & 0xFF) * (256 - weight) + ((b >> shift) & 0xFF) * weight) / 256) << shift;
+    channel(16) | channel(8) | channel(0)
+}
+
+// Plain nearest neighbour at 1x, minifb does the rest
+pub struct Unfiltered;
+
+impl Filter for Unfiltered {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn scale(&self) -> usize {
+        1
+    }
+
+    fn apply(&self, input: &[u32], _width: usize, _height: usize) -> Vec<u32> {
+        input.to_vec()
+    }
+}
+
+// EPX/AdvMAME2x: a corner takes the colour of its two neighbours when they match, rounding off staircases
+pub struct Scale2x;
+
+impl Filter for Scale2x {
+    fn name(&self) -> &'static str {
+        "scale2x"
+    }
+
+    fn scale(&self) -> usize {
+        2
+    }
+
+    fn apply(&self, input: &[u32], width: usize, height: usize) -> Vec<u32> {
+        scale_blocks(input, width, height, 2, |[_, b, _, d, e, f, _, h, _], output| {
+            output.fill(e);
+            if b != h && d != f {
+                if d == b {
+                    output[0] = d;
+                }
+                if b == f {
+                    output[1] = f;
+                }
+                if d == h {
+                    output[2] = d;
+                }
+                if h == f {
+                    output[3] = f;
+                }
+            }
+        })
+    }
+}
+
+// AdvMAME3x, Scale2x's rules extended to the edge pixels in between the corners
+pub struct Scale3x;
+
+impl Filter for Scale3x {
+    fn name(&self) -> &'static str {
+        "scale3x"
+    }
+
+    fn scale(&self) -> usize {
+        3
+    }
+
+    fn apply(&self, input: &[u32], width: usize, height: usize) -> Vec<u32> {
+        scale_blocks(input, width, height, 3, |[a, b, c, d, e, f, g, h, i], output| {
+            output.fill(e);
+            if b != h && d != f {
+                if d == b {
+                    output[0] = d;
+                }
+                if (d == b && e != c) || (b == f && e != a) {
+                    output[1] = b;
+                }
+                if b == f {
+                    output[2] = f;
+                }
+                if (d == b && e != g) || (d == h && e != a) {
+                    output[3] = d;
+                }
+                if (b == f && e != i) || (h == f && e != c) {
+                    output[5] = f;
+                }
+                if d == h {
+                    output[6] = d;
+                }
+                if (d == h && e != i) || (h == f && e != g) {
+                    output[7] = h;
+                }
+                if h == f {
+                    output[8] = f;
+                }
+            }
+        })
+    }
+}
+
+// Edge smoothing in the spirit of Maxim Stepin's hqnx without being it: colours count as equal when they are close
+// in YUV like in hqnx, and edges are blended instead of copied, but every output pixel picks its blend from the
+// neighbours around its corner of the source pixel rather than from hqnx's 256 case pattern tables
+pub struct Smooth {
+    pub scale: usize, // 2 to 4
+}
+
+// hqnx's thresholds for Y, U and V
+fn similar(a: u32, b: u32) -> bool {
+    if a == b {
+        return true;
+    }
+    let yuv = |colour: u32| {
+        let (r, g, b) = (((colour >> 16) & 0xFF) as i32, ((colour >> 8) & 0xFF) as i32, (colour & 0xFF) as i32);
+        ((r + g + b) >> 2, (r - b) >> 2, (2 * g - r - b) >> 3)
+    };
+    let (a, b) = (yuv(a), yuv(b));
+    (a.0 - b.0).abs() <= 48 && (a.1 - b.1).abs() <= 7 && (a.2 - b.2).abs() <= 6
+}
+
+impl Filter for Smooth {
+    fn name(&self) -> &'static str {
+        match self.scale {
+            2 => "smooth2x",
+            3 => "smooth3x",
+            _ => "smooth4x",
+        }
+    }
+
+    fn scale(&self) -> usize {
+        self.scale
+    }
+
+    fn apply(&self, input: &[u32], width: usize, height: usize) -> Vec<u32> {
+        let scale = self.scale as i32;
+        scale_blocks(input, width, height, self.scale, |neighbours, output| {
+            let e = neighbours[4];
+            for (i, pixel) in output.iter_mut().enumerate() {
+                // Offset from the middle of the source pixel in 1/(2 * scale)ths, and how far out that is from 0 to 1
+                let dx = 2 * (i as i32 % scale) + 1 - scale;
+                let dy = 2 * (i as i32 / scale) + 1 - scale;
+                let distance = (dx.abs() + dy.abs()) as u32 * 128 / scale as u32;
+                let edge_weight = (distance * 2).saturating_sub(128).min(256);
+                let neighbour = |x: i32, y: i32| neighbours[((y.signum() + 1) * 3 + x.signum() + 1) as usize];
+
+                *pixel = if dx != 0 && dy != 0 {
+                    // A corner cut off by a diagonal edge, straight edges stay sharp
+                    let (horizontal, vertical) = (neighbour(dx, 0), neighbour(0, dy));
+                    if similar(horizontal, vertical) && !similar(e, horizontal) {
+                        mix(e, mix(horizontal, vertical, 128), edge_weight)
+                    } else {
+                        e
+                    }
+                } else if dx != 0 || dy != 0 {
+                    // The pixels between corners at odd scales follow a diagonal edge through either corner next to them
+                    let side = neighbour(dx, dy);
+                    let (across_a, across_b) = if dx == 0 { (neighbour(-1, 0), neighbour(1, 0)) } else { (neighbour(0, -1), neighbour(0, 1)) };
+                    if !similar(e, side) && (similar(side, across_a) || similar(side, across_b)) {
+                        mix(e, side, edge_weight)
+                    } else {
+                        e
+                    }
+                } else {
+                    e
+                };
+            }
+        })
+    }
+}
+
+// Every pixel as a dot with a darker gap to the right and below it, like the cells of a real LCD
+pub struct LcdGrid {
+    pub scale: usize,
+}
+
+impl Filter for LcdGrid {
+    fn name(&self) -> &'static str {
+        "lcd"
+    }
+
+    fn scale(&self) -> usize {
+        self.scale
+    }
+
+    fn apply(&self, input: &[u32], width: usize, height: usize) -> Vec<u32> {
+        let scale = self.scale;
+        scale_blocks(input, width, height, scale, |neighbours, output| {
+            let gap = mix(neighbours[4], 0, 64);
+            for (i, pixel) in output.iter_mut().enumerate() {
+                *pixel = if i % scale == scale - 1 || i / scale == scale - 1 { gap } else { neighbours[4] };
+            }
+        })
+    }
+}
+
+// The bottom row of every block at half brightness, like the gaps between a CRT's lines
+pub struct Scanlines {
+    pub scale: usize,
+}
+
+impl Filter for Scanlines {
+    fn name(&self) -> &'static str {
+        "scanlines"
+    }
+
+    fn scale(&self) -> usize {
+        self.scale
+    }
+
+    fn apply(&self, input: &[u32], width: usize, height: usize) -> Vec<u32> {
+        let scale = self.scale;
+        scale_blocks(input, width, height, scale, |neighbours, output| {
+            output.fill(neighbours[4]);
+            output[scale * (scale - 1)..].fill(mix(neighbours[4], 0, 128));
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Greyscale images drawn with one hex digit per pixel, 0 black to F white
+    fn grey(art: &[&str]) -> Vec<u32> {
+        art.iter()
+            .flat_map(|row| row.chars().map(|c| c.to_digit(16).unwrap() * 0x111111))
+            .collect()
+    }
+
+    fn art(pixels: &[u32], width: usize) -> Vec<String> {
+        pixels
+            .chunks(width)
+            .map(|row| row.iter().map(|pixel| char::from_digit(((pixel & 0xFF) + 8) / 17, 16).unwrap().to_ascii_uppercase()).collect())
+            .collect()
+    }
+
+    // A staircase edge with a lone dot in the dark part
+    const STAIRS: [&str; 4] = ["FFF0", "FF00", "F00F", "0000"];
+
+    fn golden(filter: &dyn Filter, expected: &[&str]) {
+        let output = filter.apply(&grey(&STAIRS), 4, 4);
+        assert_eq!(output.len(), 16 * filter.scale() * filter.scale());
+        assert_eq!(art(&output, 4 * filter.scale()), expected, "{}", filter.name());
+    }
+
+    #[test]
+    fn scale2x() {
+        golden(
+            &Scale2x,
+            &[
+                "FFFFFF00",
+                "FFFFF000",
+                "FFFFF000",
+                "FFF00000",
+                "FFF000FF",
+                "F00000FF",
+                "00000000",
+                "00000000",
+            ],
+        );
+    }
+
+    #[test]
+    fn scale3x() {
+        golden(
+            &Scale3x,
+            &[
+                "FFFFFFFFF000",
+                "FFFFFFFF0000",
+                "FFFFFFFF0000",
+                "FFFFFFF00000",
+                "FFFFFF000000",
+                "FFFFF0000000",
+                "FFFF00000FFF",
+                "FFF000000FFF",
+                "F00000000FFF",
+                "000000000000",
+                "000000000000",
+                "000000000000",
+            ],
+        );
+    }
+
+    #[test]
+    fn smooth2x() {
+        golden(
+            &Smooth { scale: 2 },
+            &[
+                "FFFFFF00",
+                "FFFFF700",
+                "FFFF7000",
+                "FFF70000",
+                "FF70007F",
+                "F700007F",
+                "00000000",
+                "00000000",
+            ],
+        );
+    }
+
+    #[test]
+    fn smooth3x() {
+        golden(
+            &Smooth { scale: 3 },
+            &[
+                "FFFFFFFFF000",
+                "FFFFFFFFD000",
+                "FFFFFFFD3000",
+                "FFFFFFC20000",
+                "FFFFFD200000",
+                "FFFFD3000000",
+                "FFFC200003DF",
+                "FFD200000DFF",
+                "FD30000003DF",
+                "000000000000",
+                "000000000000",
+                "000000000000",
+            ],
+        );
+    }
+
+    #[test]
+    fn smooth4x() {
+        golden(
+            &Smooth { scale: 4 },
+            &[
+                "FFFFFFFFFFFF0000",
+                "FFFFFFFFFFFF0000",
+                "FFFFFFFFFFF70000",
+                "FFFFFFFFFF700000",
+                "FFFFFFFFF7000000",
+                "FFFFFFFF70000000",
+                "FFFFFFF700000000",
+                "FFFFFF7000000000",
+                "FFFFF700000007FF",
+                "FFFF700000007FFF",
+                "FFF7000000007FFF",
+                "FF700000000007FF",
+                "0000000000000000",
+                "0000000000000000",
+                "0000000000000000",
+                "0000000000000000",
+            ],
+        );
+    }
+
+    #[test]
+    fn lcd_grid() {
+        golden(
+            &LcdGrid { scale: 3 },
+            &[
+                "FFBFFBFFB000",
+                "FFBFFBFFB000",
+                "BBBBBBBBB000",
+                "FFBFFB000000",
+                "FFBFFB000000",
+                "BBBBBB000000",
+                "FFB000000FFB",
+                "FFB000000FFB",
+                "BBB000000BBB",
+                "000000000000",
+                "000000000000",
+                "000000000000",
+            ],
+        );
+    }
+
+    #[test]
+    fn scanlines() {
+        golden(
+            &Scanlines { scale: 2 },
+            &[
+                "FFFFFF00",
+                "77777700",
+                "FFFF0000",
+                "77770000",
+                "FF0000FF",
+                "77000077",
+                "00000000",
+                "00000000",
+            ],
+        );
+    }
+
+    #[test]
+    fn filters_are_found_by_name() {
+        for filter in filters() {
+            assert_eq!(from_name(filter.name()).unwrap().scale(), filter.scale());
+        }
+        assert!(from_name("smooth5x").is_none());
+        assert_eq!(Unfiltered.apply(&grey(&STAIRS), 4, 4), grey(&STAIRS));
+    }
+
+    #[test]
+    fn smooth_treats_close_colours_as_equal() {
+        // Black is cut off by the white pixels around it, the slightly darker white isn't
+        let output = Smooth { scale: 2 }.apply(&grey(&["0F", "FE"]), 2, 2);
+        assert_eq!(art(&output, 4), ["00FF", "07FF", "FFEE", "FFEE"]);
+    }
+}
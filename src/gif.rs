@@ -0,0 +1,402 @@
+use std::collections::{HashMap, VecDeque};
+
+// Animated GIF clips of the screen
+// Frames are kept as palette indices, a DMG frame only has a handful of colours and even a CGB one rarely
+// goes past 256, so an indexed frame is a quarter of the RGB one and the GIF colour tables stay small
+// GIF delays are in hundredths of a second and most viewers slow down anything under 2, so only
+// frames at least 2/100s apart are kept, about 40 of the 59.73 per second
+
+const CLOCK_SPEED: u64 = 4194304; // Hz
+const FRAME_CYCLES: u64 = 70224;
+const MIN_DELAY: u64 = 2; // Hundredths of a second
+
+type Area = (usize, usize, usize, usize); // Left, top, width, height
+
+struct GifFrame {
+    number: u64, // Emulated frames since recording started
+    palette: Vec<u32>,
+    pixels: Vec<u8>,
+}
+
+pub struct GifRecorder {
+    width: usize,
+    height: usize,
+    frames: VecDeque<GifFrame>,
+    max_frames: Option<u64>, // Rolling buffer length in emulated frames, None keeps everything
+    frame_number: u64,
+}
+
+// When a frame was shown, in hundredths of a second
+fn frame_time(number: u64) -> u64 {
+    number * FRAME_CYCLES * 100 / CLOCK_SPEED
+}
+
+impl GifRecorder {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { width, height, frames: VecDeque::new(), max_frames: None, frame_number: 0 }
+    }
+
+    // Keeps only the last seconds of frames
+    pub fn rolling(width: usize, height: usize, seconds: u32) -> Self {
+        let max_frames = seconds as u64 * CLOCK_SPEED / FRAME_CYCLES;
+        Self { max_frames: Some(max_frames), ..Self::new(width, height) }
+    }
+
+    // RGB888 pixels of every emulated frame
+    pub fn add_frame(&mut self, pixels: &[u32]) {
+        assert_eq!(pixels.len(), self.width * self.height);
+        let number = self.frame_number;
+        self.frame_number += 1;
+        if let Some(last) = self.frames.back() {
+            if frame_time(number) < frame_time(last.number) + MIN_DELAY {
+                return;
+            }
+        }
+
+        let (palette, pixels) = index_colours(pixels);
+        self.frames.push_back(GifFrame { number, palette, pixels });
+        if let Some(max_frames) = self.max_frames {
+            while self.frames.front().is_some_and(|frame| frame.number + max_frames <= number) {
+                self.frames.pop_front();
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // A looping GIF89a, every frame after the first only holds the rectangle that changed
+    pub fn encode(&self) -> Vec<u8> {
+        let mut gif: Vec<u8> = b"GIF89a".to_vec();
+        gif.extend_from_slice(&(self.width as u16).to_le_bytes());
+        gif.extend_from_slice(&(self.height as u16).to_le_bytes());
+        gif.extend_from_slice(&[0, 0, 0]); // No global colour table, every frame has its own
+        gif.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00"); // Loop forever
+
+        // Unchanged frames lengthen the one before instead
+        let mut parts: Vec<(&GifFrame, Area, u64)> = Vec::new();
+        let mut previous: Option<&GifFrame> = None;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let end = self.frames.get(i + 1).map_or(frame_time(frame.number) + MIN_DELAY, |next| frame_time(next.number));
+            let delay = end - frame_time(frame.number);
+            let area = match previous {
+                Some(previous) => self.changed_area(previous, frame),
+                None => Some((0, 0, self.width, self.height)),
+            };
+            match area {
+                Some(area) => {
+                    parts.push((frame, area, delay));
+                    previous = Some(frame);
+                }
+                None => parts.last_mut().unwrap().2 += delay,
+            }
+        }
+
+        for (frame, (left, top, width, height), delay) in parts {
+            // Graphic control extension: leave the frame in place for the next one to draw over
+            gif.extend_from_slice(&[0x21, 0xF9, 0x04, 0x04]);
+            gif.extend_from_slice(&(delay.min(u16::MAX as u64) as u16).to_le_bytes());
+            gif.extend_from_slice(&[0, 0]);
+
+            let table_bits = (usize::BITS - (frame.palette.len().max(2) - 1).leading_zeros()) as u8;
+            gif.push(0x2C);
+            for value in [left, top, width, height] {
+                gif.extend_from_slice(&(value as u16).to_le_bytes());
+            }
+            gif.push(0x80 | (table_bits - 1)); // Local colour table
+            for i in 0..1 << table_bits {
+                let colour = frame.palette.get(i).copied().unwrap_or(0);
+                gif.extend_from_slice(&colour.to_be_bytes()[1..]);
+            }
+
+            let mut indices: Vec<u8> = Vec::with_capacity(width * height);
+            for y in top..top + height {
+                indices.extend_from_slice(&frame.pixels[y * self.width + left..y * self.width + left + width]);
+            }
+            let min_code_size = table_bits.max(2);
+            gif.push(min_code_size);
+            for block in lzw(&indices, min_code_size).chunks(255) {
+                gif.push(block.len() as u8);
+                gif.extend_from_slice(block);
+            }
+            gif.push(0);
+        }
+
+        gif.push(0x3B);
+        gif
+    }
+
+    // Where the pixels that differ are, None when none do
+    fn changed_area(&self, previous: &GifFrame, frame: &GifFrame) -> Option<Area> {
+        let (mut left, mut top, mut right, mut bottom) = (self.width, self.height, 0, 0);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                if previous.palette[previous.pixels[i] as usize] != frame.palette[frame.pixels[i] as usize] {
+                    left = left.min(x);
+                    top = top.min(y);
+                    right = right.max(x + 1);
+                    bottom = bottom.max(y + 1);
+                }
+            }
+        }
+        if right == 0 {
+            None
+        } else {
+            Some((left, top, right - left, bottom - top))
+        }
+    }
+}
+
+// Frames with more than 256 colours, only possible with mid-frame palette changes, are cut down to RGB332
+fn index_colours(pixels: &[u32]) -> (Vec<u32>, Vec<u8>) {
+    let mut palette: Vec<u32> = Vec::new();
+    let mut lookup: HashMap<u32, u8> = HashMap::new();
+    let mut indices: Vec<u8> = Vec::with_capacity(pixels.len());
+    for pixel in pixels {
+        let index = match lookup.get(pixel) {
+            Some(index) => *index,
+            None if palette.len() < 256 => {
+                palette.push(*pixel);
+                lookup.insert(*pixel, (palette.len() - 1) as u8);
+                (palette.len() - 1) as u8
+            }
+            None => return index_rgb332(pixels),
+        };
+        indices.push(index);
+    }
+    (palette, indices)
+}
+
+fn index_rgb332(pixels: &[u32]) -> (Vec<u32>, Vec<u8>) {
+    let palette: Vec<u32> = (0..256u32)
+        .map(|i| {
+            let r = (i >> 5) * 255 / 7;
+            let g = ((i >> 2) & 7) * 255 / 7;
+            let b = (i & 3) * 255 / 3;
+            r << 16 | g << 8 | b
+        })
+        .collect();
+    let indices: Vec<u8> = pixels
+        .iter()
+        .map(|pixel| ((pixel >> 16) & 0xE0 | (pixel >> 11) & 0x1C | (pixel >> 6) & 0x03) as u8)
+        .collect();
+    (palette, indices)
+}
+
+// Variable length LZW as GIF uses it, codes of up to 12 bits packed starting with the least significant bit
+fn lzw(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear: u16 = 1 << min_code_size;
+    let end: u16 = clear + 1;
+    let mut output: Vec<u8> = Vec::new();
+    let mut bit_buffer: u32 = 0;
+    let mut bit_count: u8 = 0;
+    let mut write = |code: u16, size: u8, output: &mut Vec<u8>| {
+        bit_buffer |= (code as u32) << bit_count;
+        bit_count += size;
+        while bit_count >= 8 {
+            output.push(bit_buffer as u8);
+            bit_buffer >>= 8;
+            bit_count -= 8;
+        }
+    };
+
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code: u16 = end + 1;
+    let mut code_size: u8 = min_code_size + 1;
+    write(clear, code_size, &mut output);
+
+    let mut prefix: Option<u16> = None;
+    for index in indices {
+        let Some(current) = prefix else {
+            prefix = Some(*index as u16);
+            continue;
+        };
+        if let Some(code) = codes.get(&(current, *index)) {
+            prefix = Some(*code);
+            continue;
+        }
+        write(current, code_size, &mut output);
+        if next_code < 4096 {
+            codes.insert((current, *index), next_code);
+            next_code += 1;
+            if next_code > 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+        } else {
+            write(clear, code_size, &mut output);
+            codes.clear();
+            next_code = end + 1;
+            code_size = min_code_size + 1;
+        }
+        prefix = Some(*index as u16);
+    }
+    if let Some(current) = prefix {
+        write(current, code_size, &mut output);
+        // Decoders add an entry for this code too, which can widen the end code
+        if next_code < 4096 && next_code + 1 > 1 << code_size && code_size < 12 {
+            code_size += 1;
+        }
+    }
+    write(end, code_size, &mut output);
+    write(0, 7, &mut output); // Flushes the last partial byte
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GifRecorder;
+
+    // Draws every image of a GIF onto a canvas, returning the canvas after each one with its delay
+    fn decode_gif(gif: &[u8], width: usize, height: usize) -> Vec<(Vec<u32>, u16)> {
+        let mut canvas: Vec<u32> = vec![0; width * height];
+        let mut frames: Vec<(Vec<u32>, u16)> = Vec::new();
+        let mut delay: u16 = 0;
+        let mut i = 13 + 19; // Header, screen descriptor and the looping extension
+        while gif[i] != 0x3B {
+            if gif[i] == 0x21 {
+                delay = u16::from_le_bytes([gif[i + 4], gif[i + 5]]);
+                i += 8;
+                continue;
+            }
+            let field = |offset: usize| u16::from_le_bytes([gif[i + offset], gif[i + offset + 1]]) as usize;
+            let (left, top, image_width) = (field(1), field(3), field(5));
+            let table_size = 2 << (gif[i + 9] & 7);
+            let table: Vec<u32> = gif[i + 10..i + 10 + table_size * 3]
+                .chunks(3)
+                .map(|rgb| u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]]))
+                .collect();
+            i += 10 + table_size * 3;
+            let min_code_size = gif[i];
+            i += 1;
+            let mut data: Vec<u8> = Vec::new();
+            while gif[i] != 0 {
+                data.extend_from_slice(&gif[i + 1..i + 1 + gif[i] as usize]);
+                i += 1 + gif[i] as usize;
+            }
+            i += 1;
+
+            // LZW
+            let clear = 1usize << min_code_size;
+            let mut dictionary: Vec<Vec<u8>> = Vec::new();
+            let mut code_size = min_code_size + 1;
+            let mut previous: Option<usize> = None;
+            let mut indices: Vec<u8> = Vec::new();
+            let mut bit = 0;
+            loop {
+                let code = (0..code_size as usize).fold(0, |code, n| code | (((data[(bit + n) / 8] >> ((bit + n) % 8)) & 1) as usize) << n);
+                bit += code_size as usize;
+                if code == clear {
+                    dictionary = (0..clear).map(|index| vec![index as u8]).chain([vec![], vec![]]).collect();
+                    code_size = min_code_size + 1;
+                    previous = None;
+                    continue;
+                }
+                if code == clear + 1 {
+                    break;
+                }
+                let entry = match (dictionary.get(code), previous) {
+                    (Some(entry), _) => entry.clone(),
+                    (None, Some(previous)) => [dictionary[previous].clone(), vec![dictionary[previous][0]]].concat(),
+                    (None, None) => panic!("invalid code"),
+                };
+                if let Some(previous) = previous {
+                    if dictionary.len() < 4096 {
+                        dictionary.push([dictionary[previous].clone(), vec![entry[0]]].concat());
+                        if dictionary.len() == 1 << code_size && code_size < 12 {
+                            code_size += 1;
+                        }
+                    }
+                }
+                indices.extend_from_slice(&entry);
+                previous = Some(code);
+            }
+
+            for (n, index) in indices.iter().enumerate() {
+                canvas[(top + n / image_width) * width + left + n % image_width] = table[*index as usize];
+            }
+            frames.push((canvas.clone(), delay));
+        }
+        frames
+    }
+
+    // A white screen, optionally with a black 8x8 block at x, y
+    fn screen(block: Option<(usize, usize)>) -> Vec<u32> {
+        let mut pixels: Vec<u32> = vec![0xFFFFFF; 160 * 144];
+        if let Some((x, y)) = block {
+            for row in y..y + 8 {
+                pixels[row * 160 + x..row * 160 + x + 8].fill(0);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn gif_holds_only_changed_frames() {
+        let background = screen(None);
+        let with_block = screen(Some((20, 30)));
+
+        let mut gif = GifRecorder::new(160, 144);
+        for _ in 0..6 {
+            gif.add_frame(&background);
+        }
+        for _ in 0..6 {
+            gif.add_frame(&with_block);
+        }
+        let gif = gif.encode();
+        assert_eq!(&gif[..6], b"GIF89a");
+
+        // Frames come every 1.67/100s, only those 2/100s apart are kept and unchanged ones merge
+        let frames = decode_gif(&gif, 160, 144);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], (background, 10));
+        assert_eq!(frames[1], (with_block, 10));
+    }
+
+    #[test]
+    fn changed_frames_only_hold_the_changed_rectangle() {
+        let mut gif = GifRecorder::new(160, 144);
+        gif.add_frame(&screen(None));
+        gif.add_frame(&screen(None));
+        gif.add_frame(&screen(Some((20, 30))));
+        let gif = gif.encode();
+
+        // The second image descriptor, after the first image's data
+        let second = (13 + 19..gif.len()).filter(|i| gif[*i] == 0x2C && gif[i - 1] == 0 && gif[i - 8] == 0x21).nth(1).unwrap();
+        let field = |offset: usize| u16::from_le_bytes([gif[second + offset], gif[second + offset + 1]]);
+        assert_eq!((field(1), field(3), field(5), field(7)), (20, 30, 8, 8));
+    }
+
+    #[test]
+    fn rolling_buffer_keeps_the_last_seconds() {
+        let mut gif = GifRecorder::rolling(160, 144, 1);
+        // Three seconds with the block moving every frame, only the last second is kept, about 40 of its 60 frames
+        for frame in 0..180 {
+            gif.add_frame(&screen(Some((frame % 152, 0))));
+        }
+        let frames = decode_gif(&gif.encode(), 160, 144);
+        assert!((39..=41).contains(&frames.len()), "{} frames", frames.len());
+        // The very last frame may have come too soon after the one before to be kept
+        let last = &frames.last().unwrap().0;
+        assert!(*last == screen(Some((179 % 152, 0))) || *last == screen(Some((178 % 152, 0))));
+    }
+
+    #[test]
+    fn noisy_frames_with_many_colours_survive_encoding() {
+        // More LZW codes than fit in 12 bits, and more than 256 colours which fall back to RGB332
+        let noise: Vec<u32> = (0..160 * 144u32).map(|i| [0xFFFFFF, 0x000000, 0xFF0000, 0x00FF00][(i.wrapping_mul(2654435761) >> 28) as usize % 4]).collect();
+        let colourful: Vec<u32> = (0..160 * 144u32).map(|i| (i * 0x10101) & 0xE0E0C0).collect();
+
+        let mut gif = GifRecorder::new(160, 144);
+        gif.add_frame(&noise);
+        gif.add_frame(&noise);
+        gif.add_frame(&colourful);
+        let frames = decode_gif(&gif.encode(), 160, 144);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, noise);
+        // RGB332 keeps the top bits of every channel
+        let rgb332 = |pixel: u32| (pixel >> 16 & 0xE0, pixel >> 8 & 0xE0, pixel & 0xC0);
+        assert!(frames[1].0.iter().zip(colourful.iter()).all(|(a, b)| rgb332(*a) == rgb332(*b)));
+    }
+}
@@ -1,367 +1,1661 @@
-use crate::mmu::MMU;
+use crate::mmu::{Model, MMU};
+use crate::palette::DmgPalette;
+use crate::state::{write_u16, write_u32, StateReader};
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
 
+// Dot based PPU
+// Every scanline is 456 dots: OAM scan (mode 2) for 80 dots, pixel transfer (mode 3) until 160 pixels
+// have been pushed out of the FIFO, then H-Blank (mode 0) for the rest of the line
+// Mode 3 gets longer with SCX fine scroll, the window and sprites, just like on hardware
+
+const LINE_DOTS: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const STARTUP_DOTS: u8 = 6; // The first tile fetch of every line is thrown away
+const SPRITES_PER_LINE: usize = 10;
+
+// How CGB colours are turned into RGB, the raw values look oversaturated next to a real LCD
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColourCorrection {
+    None,
+    Cgb, // Gambatte's approximation of the CGB screen
+    Gba, // A GBA screen, which is darker and needs a gamma curve
+}
+
+impl ColourCorrection {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(ColourCorrection::None),
+            "cgb" => Some(ColourCorrection::Cgb),
+            "gba" => Some(ColourCorrection::Gba),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    colour: u8,
+    palette: u8,    // CGB only
+    priority: bool, // CGB map attribute bit 7, drawn over sprites
+}
+
+#[derive(Clone, Copy, Default)]
+struct SpritePixel {
+    colour: u8,
+    palette: u8,
+    behind_background: bool, // OAM attribute bit 7, only background colour 0 is drawn over
+    oam_index: u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+    oam_index: u8,
+    fetched: bool,
+}
+
+// Background/window fetcher, every step takes two dots:
+// get tile, get tile data low, get tile data high, then push once the FIFO is empty
+#[derive(Clone, Copy, Default)]
+struct Fetcher {
+    dot: u8,
+    tile_x: u8,
+    window: bool,
+    tile_id: u8,
+    attributes: u8, // CGB map attributes from VRAM bank 1
+    data_low: u8,
+    data_high: u8,
+}
+
 pub struct GPU {
-    scanline_counter: u16,
     mmu: Rc<RefCell<MMU>>,
+    cgb_mode: bool,
+    dmg_compatibility: bool, // DMG cartridge on a CGB, shades are looked up in CGB palettes
+    colour_table: Vec<u32>,  // RGB888 for every CGB colour
+    dmg_palette: DmgPalette,
     pub screen_data: [u32; 23040],
+    shade_data: Vec<u8>, // DMG shades of screen_data, what the SGB sees
+    sgb: bool,
+    mode: u8,
+    line: u8, // Scanline being drawn, LY can differ on line 153
+    line_dot: u16,
+    frame_ready: bool, // Set as V-Blank starts, the screen then holds a whole frame
+    stat_line: bool, // STAT interrupt sources ORed together, the interrupt fires on its rising edge
+    lx: u8, // Next pixel on the scanline
+    discard: u8,
+    startup_dots: u8,
+    sprite_stall: u8,
+    pending_sprite: Option<usize>,
+    last_sprite_tile: Option<u8>, // Background tile the last sprite fetch waited for
+    window_active: bool,
+    window_y_triggered: bool, // Set once LY has matched WY this frame
+    window_line: u8,          // Internal line counter, only advances on lines the window was drawn on
+    fetcher: Fetcher,
+    bg_fifo: VecDeque<BgPixel>,
+    sprite_fifo: VecDeque<SpritePixel>,
+    line_sprites: Vec<Sprite>,
 }
 
 impl GPU {
     pub fn new(mmu: Rc<RefCell<MMU>>) -> Self {
+        let cgb_mode = mmu.borrow().is_cgb_mode();
+        let dmg_compatibility = mmu.borrow().model == Model::Cgb && !cgb_mode;
+        let sgb = mmu.borrow().sgb.is_some();
         Self {
-            scanline_counter: 0,
-            mmu: mmu,
+            mmu,
+            cgb_mode,
+            dmg_compatibility,
+            colour_table: colour_table(ColourCorrection::None),
+            dmg_palette: DmgPalette::default(),
             screen_data: [0; 23040],
+            shade_data: vec![0; 23040],
+            sgb,
+            mode: 2,
+            line: 0,
+            line_dot: 0,
+            frame_ready: false,
+            stat_line: false,
+            lx: 0,
+            discard: 0,
+            startup_dots: 0,
+            sprite_stall: 0,
+            pending_sprite: None,
+            last_sprite_tile: None,
+            window_active: false,
+            window_y_triggered: false,
+            window_line: 0,
+            fetcher: Fetcher::default(),
+            bg_fifo: VecDeque::new(),
+            sprite_fifo: VecDeque::new(),
+            line_sprites: Vec::new(),
         }
     }
 
-    pub fn update_graphics(&mut self, cycles: u16) {
-        self.set_lcd_status();
+    // FIFOs and the sprite list are padded out so every save state has the same length
+    pub fn save_state(&self, state: &mut Vec<u8>) {
+        for pixel in self.screen_data.iter() {
+            write_u32(state, *pixel);
+        }
+        // VRAM transfers read shades from a whole frame, which can be drawn across a save state
+        if self.sgb {
+            state.extend_from_slice(&self.shade_data);
+        }
+        state.extend_from_slice(&[self.mode, self.line]);
+        write_u16(state, self.line_dot);
+        state.push(self.stat_line as u8);
+        state.extend_from_slice(&[self.lx, self.discard, self.startup_dots, self.sprite_stall]);
+        state.push(self.pending_sprite.map(|i| i as u8).unwrap_or(0xFF));
+        state.push(self.last_sprite_tile.unwrap_or(0xFF));
+        state.extend_from_slice(&[self.window_active as u8, self.window_y_triggered as u8, self.window_line]);
 
-        if self.is_lcd_enabled() > 0 {
-            self.scanline_counter += cycles;
-        } else {
-            self.mmu.borrow_mut().io_ram[0xFF44 - 0xFF00] = 0;
-            self.scanline_counter = 0;
-            return;
+        let fetcher = &self.fetcher;
+        state.extend_from_slice(&[
+            fetcher.dot,
+            fetcher.tile_x,
+            fetcher.window as u8,
+            fetcher.tile_id,
+            fetcher.attributes,
+            fetcher.data_low,
+            fetcher.data_high,
+        ]);
+
+        state.push(self.bg_fifo.len() as u8);
+        for i in 0..16 {
+            let pixel = self.bg_fifo.get(i).copied().unwrap_or_default();
+            state.extend_from_slice(&[pixel.colour, pixel.palette, pixel.priority as u8]);
         }
 
-        if self.scanline_counter >= 456 {
-            self.scanline_counter -= 456;
-            let v = self.mmu.borrow_mut().io_ram[0xFF44 - 0xFF00] + 1;
-            self.mmu.borrow_mut().io_ram[0xFF44 - 0xFF00] = v % 154;
+        state.push(self.sprite_fifo.len() as u8);
+        for i in 0..8 {
+            let pixel = self.sprite_fifo.get(i).copied().unwrap_or_default();
+            state.extend_from_slice(&[pixel.colour, pixel.palette, pixel.behind_background as u8, pixel.oam_index]);
+        }
 
-            let current_scanline: u8 = self.mmu.borrow_mut().rb(0xFF44);
+        state.push(self.line_sprites.len() as u8);
+        for i in 0..SPRITES_PER_LINE {
+            let sprite = self.line_sprites.get(i).copied().unwrap_or_default();
+            state.extend_from_slice(&[sprite.y, sprite.x, sprite.tile, sprite.attributes, sprite.oam_index, sprite.fetched as u8]);
+        }
+    }
 
-            if current_scanline == 144 {
-                // V-Blank Interrupt
-                self.mmu.borrow_mut().request_interrupt(0);
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        for pixel in self.screen_data.iter_mut() {
+            *pixel = state.read_u32();
+        }
+        if self.sgb {
+            state.read_bytes(&mut self.shade_data);
+        }
+        self.mode = state.read_u8();
+        self.mmu.borrow_mut().set_ppu_mode(self.mode);
+        self.line = state.read_u8();
+        self.line_dot = state.read_u16();
+        self.stat_line = state.read_bool();
+        self.lx = state.read_u8();
+        self.discard = state.read_u8();
+        self.startup_dots = state.read_u8();
+        self.sprite_stall = state.read_u8();
+        self.pending_sprite = match state.read_u8() {
+            0xFF => None,
+            i => Some(i as usize),
+        };
+        self.last_sprite_tile = match state.read_u8() {
+            0xFF => None,
+            tile => Some(tile),
+        };
+        self.window_active = state.read_bool();
+        self.window_y_triggered = state.read_bool();
+        self.window_line = state.read_u8();
+
+        self.fetcher = Fetcher {
+            dot: state.read_u8(),
+            tile_x: state.read_u8(),
+            window: state.read_bool(),
+            tile_id: state.read_u8(),
+            attributes: state.read_u8(),
+            data_low: state.read_u8(),
+            data_high: state.read_u8(),
+        };
+
+        let bg_fifo_length = state.read_u8() as usize;
+        self.bg_fifo.clear();
+        for i in 0..16 {
+            let pixel = BgPixel { colour: state.read_u8(), palette: state.read_u8(), priority: state.read_bool() };
+            if i < bg_fifo_length {
+                self.bg_fifo.push_back(pixel);
             }
+        }
 
-            if current_scanline < 144 {
-                self.draw_scanline();
+        let sprite_fifo_length = state.read_u8() as usize;
+        self.sprite_fifo.clear();
+        for i in 0..8 {
+            let pixel = SpritePixel {
+                colour: state.read_u8(),
+                palette: state.read_u8(),
+                behind_background: state.read_bool(),
+                oam_index: state.read_u8(),
+            };
+            if i < sprite_fifo_length {
+                self.sprite_fifo.push_back(pixel);
             }
         }
+
+        let sprite_count = state.read_u8() as usize;
+        self.line_sprites.clear();
+        for i in 0..SPRITES_PER_LINE {
+            let sprite = Sprite {
+                y: state.read_u8(),
+                x: state.read_u8(),
+                tile: state.read_u8(),
+                attributes: state.read_u8(),
+                oam_index: state.read_u8(),
+                fetched: state.read_bool(),
+            };
+            if i < sprite_count {
+                self.line_sprites.push(sprite);
+            }
+        }
+    }
+
+    // Frontend option, only affects pixels drawn afterwards
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.colour_table = colour_table(correction);
     }
 
-    fn set_lcd_status(&mut self) {
-        let mut status = self.mmu.borrow().rb(0xFF41);
+    // Frontend option for DMG games on a DMG, only affects pixels drawn afterwards
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+    }
+
+    // True once per frame, after the last visible line is drawn
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    pub fn update_graphics(&mut self, cycles: u16) {
         if self.is_lcd_enabled() == 0 {
-            self.scanline_counter = 456;
-            self.mmu.borrow_mut().io_ram[0xFF44 - 0xFF00] = 0;
-            status = self.mmu.borrow().rb(0xFF41) & 252;
-            status |= 0x01;
-            self.mmu.borrow_mut().wb(0xFF41, status);
+            self.lcd_off();
             return;
         }
 
-        let current_mode = status & 0x3;
-        let current_scanline: u8 = self.mmu.borrow().rb(0xFF44);
-        let mut new_mode = 1;
-        let mut req_int = 0;
-
-        if current_scanline > 144 {
-            // V-Blank
-            status = status & !(1 << 0);
-            status = status | (1 << 1);
-            req_int = status & (1 << 4);
-            self.mmu.borrow_mut().wb(0xFF41, status);
-        } else if self.scanline_counter >= (456 - 80) {
-            // Searching Sprites Attributes
-            new_mode = 2;
-            status = status & !(1 << 0);
-            status = status | (1 << 1);
-            req_int = status & (1 << 5);
-            self.mmu.borrow_mut().wb(0xFF41, status);
-        } else if self.scanline_counter >= (456 - 172) {
-            // Transferring Data to LCD Driver
-            new_mode = 3;
-            status = status | (1 << 0);
-            status = status | (1 << 1);
-            self.mmu.borrow_mut().wb(0xFF41, status);
-        } else {
-            // H-Blank
-            new_mode = 0;
-            status = status & !(1 << 0);
-            status = status & !(1 << 1);
-            self.mmu.borrow_mut().wb(0xFF41, status);
-            req_int = status & (1 << 3);
+        for _ in 0..cycles {
+            self.tick();
+            self.update_stat();
         }
+    }
 
-        if new_mode != current_mode && req_int > 0 {
-            self.mmu.borrow_mut().request_interrupt(1);
+    // With the LCD off LY stays at 0 and STAT reports mode 0
+    // Switching it back on starts a frame at line 0, which skips the OAM scan mode
+    fn lcd_off(&mut self) {
+        let mut mmu = self.mmu.borrow_mut();
+        mmu.io_ram[0xFF44 - 0xFF00] = 0;
+        mmu.io_ram[0xFF41 - 0xFF00] &= !0x03;
+        mmu.set_ppu_mode(0);
+        self.mode = 0;
+        self.line = 0;
+        self.line_dot = 0;
+        self.lx = 0;
+        self.stat_line = false;
+        self.window_active = false;
+        self.window_y_triggered = false;
+        self.window_line = 0;
+    }
+
+    // Advances the PPU by one dot
+    fn tick(&mut self) {
+        match self.mode {
+            2 if self.line_dot == OAM_SCAN_DOTS - 1 => {
+                self.oam_scan();
+                self.start_pixel_transfer();
+            }
+            // First line after the LCD is switched on
+            0 if self.line_dot == OAM_SCAN_DOTS - 1 && self.lx == 0 => {
+                self.oam_scan();
+                self.start_pixel_transfer();
+            }
+            3 => {
+                self.pixel_transfer_dot();
+                if self.lx >= 160 {
+                    self.mode = 0;
+                    self.mmu.borrow_mut().hblank_dma();
+                }
+            }
+            _ => (),
         }
-        // Coincidence Flag
-        if current_scanline == self.mmu.borrow().rb(0xFF45) {
-            status = status | (1 << 2);
-            if status & (1 << 6) > 0 {
-                self.mmu.borrow_mut().request_interrupt(1);
+
+        self.line_dot += 1;
+        if self.line_dot == LINE_DOTS {
+            self.line_dot = 0;
+            if self.window_active {
+                self.window_line += 1;
+                self.window_active = false;
             }
-        } else {
-            status = status & (1 << 2);
+            self.line = (self.line + 1) % 154;
+            self.mmu.borrow_mut().io_ram[0xFF44 - 0xFF00] = self.line;
+
+            if self.line == 144 {
+                // V-Blank Interrupt
+                self.mode = 1;
+                self.frame_ready = true;
+                self.window_y_triggered = false;
+                self.window_line = 0;
+                let mut mmu = self.mmu.borrow_mut();
+                mmu.request_interrupt(0);
+                if let Some(sgb) = mmu.sgb.as_mut() {
+                    sgb.frame_end(&self.shade_data);
+                }
+            } else if self.line < 144 {
+                self.mode = 2;
+            }
+        } else if self.line == 153 && self.line_dot == 4 {
+            // LY already reads 0 for most of the last line, so LYC=0 matches during line 153
+            self.mmu.borrow_mut().io_ram[0xFF44 - 0xFF00] = 0;
         }
-        self.mmu.borrow_mut().wb(0xFF41, status);
     }
+
+    // Updates the mode and coincidence bits of STAT and the STAT interrupt line
+    // Sources that are already high keep the line high, so e.g. a LYC interrupt blocks the H-Blank one on the same line
+    fn update_stat(&mut self) {
+        let mut mmu = self.mmu.borrow_mut();
+        let mut status: u8 = (mmu.io_ram[0xFF41 - 0xFF00] & !0x07) | self.mode;
+        // LY=LYC isn't compared for the first 4 dots of a line, except line 0 where LY was already 0 on line 153
+        if (self.line_dot >= 4 || self.line == 0) && mmu.io_ram[0xFF44 - 0xFF00] == mmu.io_ram[0xFF45 - 0xFF00] {
+            status |= 1 << 2;
+        }
+        mmu.io_ram[0xFF41 - 0xFF00] = status;
+        mmu.set_ppu_mode(self.mode);
+
+        let mode_source: bool = match self.mode {
+            0 => status & (1 << 3) > 0,
+            // The OAM source also fires as V-Blank starts
+            1 => status & (1 << 4) > 0 || (self.line == 144 && self.line_dot == 0 && status & (1 << 5) > 0),
+            2 => status & (1 << 5) > 0,
+            _ => false,
+        };
+        let stat_line: bool = mode_source || (status & (1 << 2) > 0 && status & (1 << 6) > 0);
+
+        if stat_line && !self.stat_line {
+            mmu.request_interrupt(1);
+        }
+        self.stat_line = stat_line;
+    }
+
     fn is_lcd_enabled(&self) -> u8 {
         self.mmu.borrow_mut().rb(0xFF40) & (1 << 7)
     }
 
-    fn draw_scanline(&mut self) {
-        let lcd_control = self.mmu.borrow_mut().rb(0xFF40);
+    // Mode 2: collect the first 10 sprites in OAM that overlap the current scanline
+    // Sprites off screen horizontally still count towards the limit
+    fn oam_scan(&mut self) {
+        let mmu = self.mmu.borrow();
+        let lcd_control: u8 = mmu.io_ram[0xFF40 - 0xFF00];
+        let current_scanline: i16 = self.line as i16;
+        let height: i16 = if lcd_control & (1 << 2) == 0 { 8 } else { 16 };
+
+        self.line_sprites.clear();
+        for i in 0..40 {
+            if self.line_sprites.len() == SPRITES_PER_LINE {
+                break;
+            }
+            let offset: usize = i * 4;
+            let y_pos: i16 = mmu.sprite_oam[offset] as i16 - 16;
+            if current_scanline >= y_pos && current_scanline < y_pos + height {
+                self.line_sprites.push(Sprite {
+                    y: mmu.sprite_oam[offset],
+                    x: mmu.sprite_oam[offset + 1],
+                    tile: mmu.sprite_oam[offset + 2],
+                    attributes: mmu.sprite_oam[offset + 3],
+                    oam_index: i as u8,
+                    fetched: false,
+                });
+            }
+        }
+    }
+
+    fn start_pixel_transfer(&mut self) {
+        let scroll_x: u8 = self.mmu.borrow().io_ram[0xFF43 - 0xFF00];
+        {
+            // WY is compared once per line, the window can then appear on any later line of the frame
+            let mmu = self.mmu.borrow();
+            if self.line == mmu.io_ram[0xFF4A - 0xFF00] {
+                self.window_y_triggered = true;
+            }
+        }
+        self.mode = 3;
+        self.lx = 0;
+        self.discard = scroll_x % 8;
+        self.startup_dots = STARTUP_DOTS;
+        self.sprite_stall = 0;
+        self.pending_sprite = None;
+        self.last_sprite_tile = None;
+        self.window_active = false;
+        self.fetcher = Fetcher::default();
+        self.bg_fifo.clear();
+        self.sprite_fifo.clear();
+    }
+
+    // Mode 3: one dot of fetching and shifting pixels out to the LCD
+    fn pixel_transfer_dot(&mut self) {
+        if self.startup_dots > 0 {
+            self.startup_dots -= 1;
+            return;
+        }
 
-        if lcd_control & (1 << 0) == 1 {
-            self.render_tiles();
+        // The background fetcher and the FIFO are paused while a sprite is fetched
+        if self.sprite_stall > 0 {
+            self.sprite_stall -= 1;
+            if self.sprite_stall == 0 {
+                if let Some(i) = self.pending_sprite.take() {
+                    self.fetch_sprite(i);
+                }
+            }
+            return;
         }
 
+        let (lcd_control, window_x, scroll_x, current_scanline) = {
+            let mmu = self.mmu.borrow();
+            (
+                mmu.io_ram[0xFF40 - 0xFF00],
+                mmu.io_ram[0xFF4B - 0xFF00],
+                mmu.io_ram[0xFF43 - 0xFF00],
+                self.line,
+            )
+        };
+
+        // A sprite reached by the FIFO stops everything for 6 to 11 dots, this one included
         if lcd_control & (1 << 1) > 0 {
-            self.render_sprites();
+            // Lowest X first, ties go to the sprite earlier in OAM
+            let next_sprite = self
+                .line_sprites
+                .iter()
+                .enumerate()
+                .filter(|(_, sprite)| !sprite.fetched && sprite.x as u16 <= self.lx as u16 + 8)
+                .min_by_key(|(_, sprite)| (sprite.x, sprite.oam_index))
+                .map(|(i, _)| i);
+            if let Some(i) = next_sprite {
+                self.line_sprites[i].fetched = true;
+                self.pending_sprite = Some(i);
+                self.sprite_stall = self.sprite_penalty(self.line_sprites[i].x, scroll_x, window_x) - 1;
+                return;
+            }
+        }
+
+        self.fetcher_dot();
+
+        // The window starts at WX - 7 once the first background tile is in the FIFO,
+        // the FIFO is cleared and fetching restarts from the window's first tile, which takes 6 dots
+        if !self.fetcher.window
+            && !self.window_active
+            && lcd_control & (1 << 5) > 0
+            && self.window_y_triggered
+            && window_x <= 166
+            && self.lx as u16 + 7 >= window_x as u16
+            && !self.bg_fifo.is_empty()
+        {
+            self.window_active = true;
+            // With WX below 7 the window's leftmost pixels are off screen
+            self.discard = 7u8.saturating_sub(window_x);
+            self.bg_fifo.clear();
+            self.fetcher = Fetcher { window: true, ..Fetcher::default() };
+            self.fetcher_dot();
+            return;
+        }
+
+        // Turning the window off mid-line sends the fetcher back to the background at the current position
+        if self.fetcher.window && lcd_control & (1 << 5) == 0 {
+            let tile_x = ((self.lx as u16 + self.bg_fifo.len() as u16 + (scroll_x % 8) as u16) / 8) as u8;
+            self.fetcher = Fetcher { tile_x, ..Fetcher::default() };
+        }
+
+        if let Some(pixel) = self.bg_fifo.pop_front() {
+            if self.discard > 0 {
+                self.discard -= 1;
+                return;
+            }
+            let sprite = self.sprite_fifo.pop_front();
+            self.draw_pixel(pixel, sprite, lcd_control, current_scanline);
+            self.lx += 1;
+        }
+    }
+
+    // 6 dots to fetch the sprite, plus up to 5 waiting for the background fetch when it's the first sprite
+    // over its background tile: the tile's pixels right of the sprite's left edge, minus 2
+    // Sprites at X 0 always take the full 11 dots
+    fn sprite_penalty(&mut self, sprite_x: u8, scroll_x: u8, window_x: u8) -> u8 {
+        if sprite_x == 0 {
+            return 11;
+        }
+        // Counted from 8 pixels left of the screen, so sprites partly off screen are on a tile too
+        let position: u16 = if self.window_active {
+            (sprite_x as u16 + 7).saturating_sub(window_x as u16)
+        } else {
+            sprite_x as u16 + scroll_x as u16
+        };
+        let tile = (position / 8) as u8;
+        if self.last_sprite_tile == Some(tile) {
+            return 6;
+        }
+        self.last_sprite_tile = Some(tile);
+        6 + 5 - (position % 8).min(5) as u8
+    }
+
+    fn fetcher_dot(&mut self) {
+        let mmu = self.mmu.borrow();
+        let lcd_control: u8 = mmu.io_ram[0xFF40 - 0xFF00];
+        let current_scanline: u8 = self.line;
+        let fetcher = &mut self.fetcher;
+
+        let y_pos: u8 = if fetcher.window {
+            self.window_line
+        } else {
+            current_scanline.wrapping_add(mmu.io_ram[0xFF42 - 0xFF00])
+        };
+
+        match fetcher.dot {
+            1 => {
+                // Location in which tile identification numbers are stored
+                let (map_bit, tile_col) = if fetcher.window {
+                    (6, fetcher.tile_x & 31)
+                } else {
+                    (3, ((mmu.io_ram[0xFF43 - 0xFF00] / 8).wrapping_add(fetcher.tile_x)) & 31)
+                };
+                let tile_map: u16 = if lcd_control & (1 << map_bit) > 0 { 0x9C00 } else { 0x9800 };
+                let address = tile_map + ((y_pos / 8) as u16) * 32 + tile_col as u16;
+                fetcher.tile_id = mmu.graphics_ram[(address - 0x8000) as usize];
+                fetcher.attributes = if self.cgb_mode { mmu.graphics_ram[0x2000 + (address - 0x8000) as usize] } else { 0 };
+            }
+            3 | 5 => {
+                let mut row: u8 = y_pos % 8;
+                if fetcher.attributes & (1 << 6) > 0 {
+                    row = 7 - row;
+                }
+                let bank: usize = if fetcher.attributes & (1 << 3) > 0 { 0x2000 } else { 0 };
+                let address = bank + (tile_data_address(lcd_control, fetcher.tile_id) - 0x8000) as usize + (row as usize) * 2;
+                if fetcher.dot == 3 {
+                    fetcher.data_low = mmu.graphics_ram[address];
+                } else {
+                    fetcher.data_high = mmu.graphics_ram[address + 1];
+                }
+            }
+            _ => (),
+        }
+
+        if fetcher.dot < 6 {
+            fetcher.dot += 1;
+            return;
+        }
+
+        // Push, only possible once the FIFO is empty
+        if self.bg_fifo.is_empty() {
+            let x_flip: bool = fetcher.attributes & (1 << 5) > 0;
+            for i in 0..8 {
+                let bit = if x_flip { i } else { 7 - i };
+                let colour = ((fetcher.data_high >> bit) & 1) << 1 | ((fetcher.data_low >> bit) & 1);
+                self.bg_fifo.push_back(BgPixel {
+                    colour,
+                    palette: fetcher.attributes & 0x7,
+                    priority: fetcher.attributes & (1 << 7) > 0,
+                });
+            }
+            fetcher.tile_x = fetcher.tile_x.wrapping_add(1);
+            fetcher.dot = 0;
+        }
+    }
+
+    fn fetch_sprite(&mut self, i: usize) {
+        let sprite = self.line_sprites[i];
+        let mmu = self.mmu.borrow();
+        let lcd_control: u8 = mmu.io_ram[0xFF40 - 0xFF00];
+        let current_scanline: i16 = self.line as i16;
+        let tall: bool = lcd_control & (1 << 2) > 0;
+        let height: i16 = if tall { 16 } else { 8 };
+        let y_flip: bool = self.test_bit(sprite.attributes, 6);
+        let x_flip: bool = self.test_bit(sprite.attributes, 5);
+
+        let mut line: i16 = current_scanline - (sprite.y as i16 - 16);
+        if y_flip {
+            line = height - 1 - line;
+        }
+
+        // 8x16 sprites use an even/odd tile pair, bit 0 of the tile index is ignored
+        let tile: u8 = if tall { sprite.tile & 0xFE } else { sprite.tile };
+        let bank: usize = if self.cgb_mode && self.test_bit(sprite.attributes, 3) { 0x2000 } else { 0 };
+        let tile_data_address: usize = bank + (tile as usize) * 16 + (line as usize) * 2;
+        let data1 = mmu.graphics_ram[tile_data_address];
+        let data2 = mmu.graphics_ram[tile_data_address + 1];
+        let palette: u8 = if self.cgb_mode { sprite.attributes & 0x7 } else { (sprite.attributes >> 4) & 1 };
+        let behind_background: bool = self.test_bit(sprite.attributes, 7);
+        // CGB games get priority by OAM position unless OPRI asks for the DMG rules
+        let oam_priority: bool = self.cgb_mode && mmu.io_ram[0xFF6C - 0xFF00] & 0x1 == 0;
+        drop(mmu);
+
+        // Sprites partly off the left edge lose their leftmost pixels
+        let clipped: usize = 8usize.saturating_sub(sprite.x as usize);
+        while self.sprite_fifo.len() < 8 {
+            self.sprite_fifo.push_back(SpritePixel::default());
+        }
+
+        for j in clipped..8 {
+            let bit: u8 = if x_flip { j as u8 } else { 7 - j as u8 };
+            let colour: u8 = self.get_bit(data2, bit as i8) << 1 | self.get_bit(data1, bit as i8);
+
+            // Colour 0 is transparent, pixels already in the FIFO came from sprites with lower X
+            let current = self.sprite_fifo[j - clipped];
+            if colour > 0 && (current.colour == 0 || (oam_priority && sprite.oam_index < current.oam_index)) {
+                self.sprite_fifo[j - clipped] = SpritePixel { colour, palette, behind_background, oam_index: sprite.oam_index };
+            }
         }
     }
 
+    fn draw_pixel(&mut self, pixel: BgPixel, sprite: Option<SpritePixel>, lcd_control: u8, current_scanline: u8) {
+        let index: usize = (current_scanline as usize) * 160 + self.lx as usize;
+        if self.cgb_mode {
+            self.screen_data[index] = self.get_cgb_colour(pixel, sprite, lcd_control);
+            return;
+        }
+
+        let colour: u8 = pixel.colour;
+        // On DMG LCDC.0 clear blanks the background and window to white, sprites are then always on top
+        let (colour, background_palette) = if lcd_control & (1 << 0) > 0 {
+            (colour, self.mmu.borrow().io_ram[0xFF47 - 0xFF00])
+        } else {
+            (0, 0)
+        };
+
+        if let Some(sprite) = sprite {
+            if sprite.colour > 0 && !(sprite.behind_background && colour > 0) {
+                let obj_palette: u8 = self.mmu.borrow().io_ram[0xFF48 - 0xFF00 + sprite.palette as usize];
+                let shade: u8 = self.get_shade(obj_palette, sprite.colour);
+                self.shade_data[index] = shade;
+                self.screen_data[index] = if self.dmg_compatibility {
+                    self.convert_colour(palette_colour(&self.mmu.borrow().obj_palette_ram, sprite.palette, shade))
+                } else {
+                    let colours = if sprite.palette == 0 { &self.dmg_palette.obj0 } else { &self.dmg_palette.obj1 };
+                    self.get_colour(colours, obj_palette, sprite.colour)
+                };
+                return;
+            }
+        }
+
+        let shade: u8 = self.get_shade(background_palette, colour);
+        self.shade_data[index] = shade;
+        self.screen_data[index] = if self.dmg_compatibility {
+            self.convert_colour(palette_colour(&self.mmu.borrow().bg_palette_ram, 0, shade))
+        } else {
+            self.get_colour(&self.dmg_palette.bg, background_palette, colour)
+        };
+    }
+
+    // On CGB LCDC.0 clear takes priority away from the background instead of blanking it
+    // Otherwise the map attribute and then the OAM attribute can put background colours 1-3 over sprites
+    fn get_cgb_colour(&self, pixel: BgPixel, sprite: Option<SpritePixel>, lcd_control: u8) -> u32 {
+        let mmu = self.mmu.borrow();
+        if let Some(sprite) = sprite {
+            let background_wins: bool =
+                lcd_control & (1 << 0) > 0 && pixel.colour > 0 && (pixel.priority || sprite.behind_background);
+            if sprite.colour > 0 && !background_wins {
+                return self.convert_colour(palette_colour(&mmu.obj_palette_ram, sprite.palette, sprite.colour));
+            }
+        }
+        self.convert_colour(palette_colour(&mmu.bg_palette_ram, pixel.palette, pixel.colour))
+    }
+
+    fn convert_colour(&self, colour: u16) -> u32 {
+        self.colour_table[colour as usize]
+    }
+
     // Redo with generics
-    fn get_bit(&mut self, byte: u8, index: i8) -> u8 {
+    fn get_bit(&self, byte: u8, index: i8) -> u8 {
         if byte & (1 << index) > 0 {
             return 1;
         }
         0
     }
 
-    fn render_tiles(&mut self) {
-        // Identify tile in background using coords
-        // Lookup tile data in tile data region
-        // Get 2 Bytes & Identify Colour
+    fn test_bit(&self, value: u8, index: u8) -> bool {
+        if value & (1 << index) > 0 {
+            return true;
+        }
+        false
+    }
+
+    // Shade (0 lightest to 3 darkest) a palette register gives a colour index
+    fn get_shade(&self, palette: u8, colour: u8) -> u8 {
+        (palette >> (colour * 2)) & 0x3
+    }
+
+    fn get_colour(&self, colours: &[u32; 4], palette: u8, colour: u8) -> u32 {
+        colours[self.get_shade(palette, colour) as usize]
+    }
+}
+
+fn palette_colour(palette_ram: &[u8; 64], palette: u8, colour: u8) -> u16 {
+    let index = (palette as usize) * 8 + (colour as usize) * 2;
+    u16::from_le_bytes([palette_ram[index], palette_ram[index + 1]]) & 0x7FFF
+}
+
+// 5 bits per channel scaled up to 8, red is in the low bits
+pub(crate) fn rgb555_to_rgb888(colour: u16) -> u32 {
+    let scale = |channel: u16| -> u32 {
+        let channel = (channel & 0x1F) as u32;
+        (channel << 3) | (channel >> 2)
+    };
+    scale(colour) << 16 | scale(colour >> 5) << 8 | scale(colour >> 10)
+}
+
+fn colour_table(correction: ColourCorrection) -> Vec<u32> {
+    let convert: fn(u16) -> u32 = match correction {
+        ColourCorrection::None => rgb555_to_rgb888,
+        ColourCorrection::Cgb => cgb_lcd_colour,
+        ColourCorrection::Gba => gba_lcd_colour,
+    };
+    (0..0x8000).map(convert).collect()
+}
+
+fn rgb_channels(colour: u16) -> (u32, u32, u32) {
+    ((colour & 0x1F) as u32, ((colour >> 5) & 0x1F) as u32, ((colour >> 10) & 0x1F) as u32)
+}
+
+// Channels bleed into each other and nothing gets fully bright, the result is at most 248 per channel
+fn cgb_lcd_colour(colour: u16) -> u32 {
+    let (r, g, b) = rgb_channels(colour);
+    let red = (r * 13 + g * 2 + b) >> 1;
+    let green = (g * 3 + b) << 1;
+    let blue = (r * 3 + g * 2 + b * 11) >> 1;
+    red << 16 | green << 8 | blue
+}
+
+// The GBA screen has a gamma of about 4, mix the channels in linear light then encode for a 2.2 gamma display
+fn gba_lcd_colour(colour: u16) -> u32 {
+    let (r, g, b) = rgb_channels(colour);
+    let linear = |channel: u32| (channel as f64 / 31.0).powf(4.0);
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let encode = |value: f64| ((value / 255.0).powf(1.0 / 2.2) * 255.0 * 255.0 / 280.0).round().min(255.0) as u32;
+    let red = encode(50.0 * g + 255.0 * r);
+    let green = encode(30.0 * b + 230.0 * g + 10.0 * r);
+    let blue = encode(220.0 * b + 10.0 * g + 50.0 * r);
+    red << 16 | green << 8 | blue
+}
+
+// 0x8000 addressing uses unsigned tile numbers, 0x8800 addressing signed ones relative to 0x9000
+fn tile_data_address(lcd_control: u8, tile_id: u8) -> u16 {
+    if lcd_control & (1 << 4) > 0 {
+        0x8000 + (tile_id as u16) * 16
+    } else {
+        (0x9000 + (tile_id as i8 as i32) * 16) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GPU;
+    use crate::mmu::{Model, MMU};
+    use crate::palette::DmgPalette;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
-        let scroll_y: u8 = self.mmu.borrow().rb(0xFF42); // Y position of BACKGROUND to draw view
-        let scroll_x: u8 = self.mmu.borrow().rb(0xFF43); // X position of BACKGROUND to draw view
+    // LCD on, window map at 0x9C00, window on, tile data at 0x8000, background on
+    const LCD_CONTROL: u8 = 0xF1;
 
-        let window_y: u8 = self.mmu.borrow().rb(0xFF4A); // Y position of VIEW to draw window
-        let window_x: u8 = self.mmu.borrow().rb(0xFF4B); // X position of VIEW to draw window
+    // LCD on, tile data at 0x8000, sprites on, background on
+    const SPRITE_LCD_CONTROL: u8 = 0x93;
 
-        let lcd_control: u8 = self.mmu.borrow().rb(0xFF40);
-        let current_scanline: u8 = self.mmu.borrow().rb(0xFF44);
-        let colour_palette: u8 = self.mmu.borrow().rb(0xFF47);
+    // Tile 0 is white, tile 1 black, tile 2 light grey and tile 3 is black on its left half only
+    // Tiles 4 and 5 are dark grey and light grey, a pair for 8x16 sprites
+    // The background map is all white, the window map is black apart from row 1 which is light grey
+    fn fixture() -> (Rc<RefCell<MMU>>, GPU) {
+        let mmu = Rc::new(RefCell::new(MMU::new(Vec::new())));
+        {
+            let mut mmu = mmu.borrow_mut();
+            for row in 0..8 {
+                mmu.graphics_ram[16 + row * 2] = 0xFF;
+                mmu.graphics_ram[16 + row * 2 + 1] = 0xFF;
+                mmu.graphics_ram[32 + row * 2] = 0xFF;
+                mmu.graphics_ram[48 + row * 2] = 0xF0;
+                mmu.graphics_ram[48 + row * 2 + 1] = 0xF0;
+                mmu.graphics_ram[64 + row * 2 + 1] = 0xFF;
+                mmu.graphics_ram[80 + row * 2] = 0xFF;
+            }
+            for i in 0..1024 {
+                mmu.graphics_ram[0x1C00 + i] = if i / 32 == 1 { 2 } else { 1 };
+            }
+            mmu.io_ram[0xFF40 - 0xFF00] = LCD_CONTROL;
+            mmu.io_ram[0xFF47 - 0xFF00] = 0xE4;
+            mmu.io_ram[0xFF48 - 0xFF00] = 0xE4;
+        }
+        let gpu = GPU::new(mmu.clone());
+        (mmu, gpu)
+    }
 
-        let tile_identity_address: u16; // Location in which tile identification numbers are stored
-        let mut tile_data_address: u16; // Location in which tile data (pixel information) is stored
+    // Places OAM entry i with its top left corner at screen position x, y
+    fn sprite(mmu: &Rc<RefCell<MMU>>, i: usize, x: u8, y: u8, tile: u8, attributes: u8) {
+        mmu.borrow_mut().sprite_oam[i * 4..i * 4 + 4].copy_from_slice(&[y + 16, x + 8, tile, attributes]);
+    }
 
-        let mut is_signed: bool = false;
-        let mut is_window: bool = false;
-        let mut y_pos: u8 = 0;
+    // Renders a frame, calling before_line with LY at the start of every line
+    fn render(mmu: &Rc<RefCell<MMU>>, gpu: &mut GPU, mut before_line: impl FnMut(u8, &mut MMU)) {
+        for line in 0..154 {
+            before_line(line, &mut mmu.borrow_mut());
+            gpu.update_graphics(456);
+        }
+    }
+
+    // ' ' white, '.' light grey, '+' dark grey, '#' black
+    fn scanline(gpu: &GPU, line: usize) -> String {
+        gpu.screen_data[line * 160..(line + 1) * 160]
+            .iter()
+            .map(|pixel| match pixel {
+                0xFFFFFF => ' ',
+                0xCCCCCC => '.',
+                0x777777 => '+',
+                _ => '#',
+            })
+            .collect()
+    }
+
+    fn expected(parts: &[(char, usize)]) -> String {
+        parts.iter().map(|(c, n)| c.to_string().repeat(*n)).collect()
+    }
 
-        if lcd_control & (1 << 5) == 1 {
-            if window_y <= current_scanline { is_window = true; }
+    #[test]
+    fn window_starts_at_wx_minus_seven() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = 87;
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(scanline(&gpu, 0), expected(&[(' ', 80), ('#', 80)]));
+        assert_eq!(scanline(&gpu, 8), expected(&[(' ', 80), ('.', 80)]));
+    }
+
+    #[test]
+    fn window_at_wx_seven_covers_the_line() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = 7;
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(scanline(&gpu, 0), expected(&[('#', 160)]));
+    }
+
+    #[test]
+    fn window_below_wx_seven_is_shifted_off_screen() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.graphics_ram[0x1C00] = 3;
+            mmu.io_ram[0xFF4B - 0xFF00] = 3;
         }
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..8], "    ####");
+    }
 
-        if is_window == false {
-            if lcd_control & (1 << 3) > 0 {
-                tile_identity_address = 0x9C00;
-            } else {
-                tile_identity_address = 0x9800;
+    #[test]
+    fn window_wx_past_the_screen_is_hidden() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = 167;
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(scanline(&gpu, 0), expected(&[(' ', 160)]));
+    }
+
+    #[test]
+    fn window_line_counter_skips_hidden_lines() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = 7;
+        // Hidden for lines 4 to 11, so line 12 draws window line 4 and line 16 window line 8
+        render(&mmu, &mut gpu, |line, mmu| {
+            mmu.io_ram[0xFF40 - 0xFF00] = if (4..12).contains(&line) { LCD_CONTROL & !(1 << 5) } else { LCD_CONTROL };
+        });
+        assert_eq!(scanline(&gpu, 3), expected(&[('#', 160)]));
+        assert_eq!(scanline(&gpu, 4), expected(&[(' ', 160)]));
+        assert_eq!(scanline(&gpu, 12), expected(&[('#', 160)]));
+        assert_eq!(scanline(&gpu, 15), expected(&[('#', 160)]));
+        assert_eq!(scanline(&gpu, 16), expected(&[('.', 160)]));
+    }
+
+    #[test]
+    fn window_off_screen_horizontally_does_not_advance_line_counter() {
+        let (mmu, mut gpu) = fixture();
+        render(&mmu, &mut gpu, |line, mmu| {
+            mmu.io_ram[0xFF4B - 0xFF00] = if line < 8 { 200 } else { 7 };
+        });
+        assert_eq!(scanline(&gpu, 7), expected(&[(' ', 160)]));
+        assert_eq!(scanline(&gpu, 8), expected(&[('#', 160)]));
+        assert_eq!(scanline(&gpu, 16), expected(&[('.', 160)]));
+    }
+
+    #[test]
+    fn window_starts_when_wy_is_written_mid_frame() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF4A - 0xFF00] = 200;
+            mmu.io_ram[0xFF4B - 0xFF00] = 7;
+        }
+        render(&mmu, &mut gpu, |line, mmu| {
+            if line == 50 {
+                mmu.io_ram[0xFF4A - 0xFF00] = 50;
             }
-        } else {
-            if lcd_control & (1 << 6) > 0 {
-                tile_identity_address = 0x9C00;
-            } else {
-                tile_identity_address = 0x9800;
+            // Once triggered, moving WY away doesn't stop the window for the rest of the frame
+            if line == 60 {
+                mmu.io_ram[0xFF4A - 0xFF00] = 255;
             }
+        });
+        assert_eq!(scanline(&gpu, 49), expected(&[(' ', 160)]));
+        assert_eq!(scanline(&gpu, 50), expected(&[('#', 160)]));
+        assert_eq!(scanline(&gpu, 58), expected(&[('.', 160)]));
+        assert_eq!(scanline(&gpu, 70), expected(&[('#', 160)]));
+    }
+
+    #[test]
+    fn window_wy_is_not_retriggered_after_a_frame() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF4A - 0xFF00] = 20;
+            mmu.io_ram[0xFF4B - 0xFF00] = 7;
         }
+        render(&mmu, &mut gpu, |_, _| ());
+        mmu.borrow_mut().io_ram[0xFF4A - 0xFF00] = 100;
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(scanline(&gpu, 20), expected(&[(' ', 160)]));
+        assert_eq!(scanline(&gpu, 99), expected(&[(' ', 160)]));
+        assert_eq!(scanline(&gpu, 100), expected(&[('#', 160)]));
+    }
 
-        if lcd_control & (1 << 4) > 0 {
-            tile_data_address = 0x8000;
-        } else {
-            tile_data_address = 0x8800;
-            is_signed = true;
+    #[test]
+    fn window_stops_when_disabled_mid_line() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = 7;
+        gpu.update_graphics(80 + 6 + 80);
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = LCD_CONTROL & !(1 << 5);
+        gpu.update_graphics(456 - 80 - 6 - 80);
+        // Window pixels up to the write, background after it
+        let line = scanline(&gpu, 0);
+        assert!(line.starts_with('#') && line.ends_with(' '));
+        assert!(line.trim_start_matches('#').trim_end_matches(' ').is_empty());
+    }
+
+    #[test]
+    fn sprites_limited_to_ten_per_line() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        for i in 0..11 {
+            sprite(&mmu, i, i as u8 * 8, 0, 1, 0);
         }
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(scanline(&gpu, 0), expected(&[('#', 80), (' ', 80)]));
+    }
 
-        // Determine vertical tile
-        if is_window {
-            // Must subtract as window_y just gives coordinates of view
-            y_pos += current_scanline - window_y;
-        } else {
-            // Must add scanline onto scroll_y as it just gives coordinates of background
-             y_pos += current_scanline.wrapping_add(scroll_y);
+    #[test]
+    fn sprites_off_screen_count_towards_the_limit() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        for i in 0..10 {
+            mmu.borrow_mut().sprite_oam[i * 4..i * 4 + 4].copy_from_slice(&[16, 0, 1, 0]);
         }
+        sprite(&mmu, 10, 0, 0, 1, 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(scanline(&gpu, 0), expected(&[(' ', 160)]));
+    }
 
-        let tile_row: u16 = (((y_pos / 8) as u16) * 32) as u16;
+    #[test]
+    fn sprite_with_lower_x_is_drawn_on_top() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        sprite(&mmu, 0, 4, 0, 2, 0);
+        sprite(&mmu, 1, 0, 0, 1, 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..16], "########....    ");
+    }
 
-        // 160 vertical pixels and 20 tiles
-        for i in 0..20 {
-            // Determine Horizontal Tile
-            // Determine Line
-            // For Each Horizontal Pixel Loop and Adjust Framebuffer
+    #[test]
+    fn sprite_earlier_in_oam_wins_at_the_same_x() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        sprite(&mmu, 0, 20, 0, 2, 0);
+        sprite(&mmu, 1, 20, 0, 1, 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[16..32], "    ........    ");
+    }
 
-            let base = i * 8;
-            let mut x_pos = base + scroll_x;
+    #[test]
+    fn sprite_colour_zero_is_transparent_regardless_of_palette() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            // Colour 1 is white in this palette but still opaque
+            mmu.io_ram[0xFF48 - 0xFF00] = 0xE0;
+            for i in 0..1024 {
+                mmu.graphics_ram[0x1800 + i] = 1;
+            }
+        }
+        sprite(&mmu, 0, 0, 0, 2, 0);
+        sprite(&mmu, 1, 8, 0, 0, 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..24], "        ################");
+    }
+
+    #[test]
+    fn sprite_uses_obp1_when_palette_bit_is_set() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            mmu.io_ram[0xFF49 - 0xFF00] = 0xFC;
+        }
+        sprite(&mmu, 0, 0, 0, 2, 0x10);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..8], "########");
+    }
 
-            if is_window { if x_pos >= window_x { x_pos -= window_x } }
+    #[test]
+    fn dmg_palette_has_colours_per_layer() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        gpu.set_dmg_palette(DmgPalette {
+            name: "test".to_string(),
+            bg: [GREEN, 0, 0, 0],
+            obj0: [0, 0, 0, RED],
+            obj1: [0, 0, 0, BLUE],
+        });
+        sprite(&mmu, 0, 0, 0, 1, 0);
+        sprite(&mmu, 1, 8, 0, 1, 0x10);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(cgb_scanline(&gpu, 0), pixels(&[(RED, 8), (BLUE, 8), (GREEN, 144)]));
+    }
 
-            let tile_col: u16 = (x_pos / 8) as u16;
-            let mut signed_tile_identifier: i8 = 0;
-            let mut unsigned_tile_identifier: u16 = 0;
-            let tile_identifier_address = tile_identity_address + tile_col + tile_row;
+    #[test]
+    fn tall_sprites_ignore_tile_bit_zero() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL | (1 << 2);
+        sprite(&mmu, 0, 0, 0, 5, 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..9], "++++++++ ");
+        assert_eq!(&scanline(&gpu, 7)[..9], "++++++++ ");
+        assert_eq!(&scanline(&gpu, 8)[..9], "........ ");
+        assert_eq!(&scanline(&gpu, 15)[..9], "........ ");
+        assert_eq!(&scanline(&gpu, 16)[..9], "         ");
+    }
 
-            if is_signed {
-                signed_tile_identifier = self.mmu.borrow_mut().rb(tile_identifier_address) as i8;
-            } else {
-                unsigned_tile_identifier = self.mmu.borrow_mut().rb(tile_identifier_address) as u16;
+    #[test]
+    fn tall_sprites_flip_vertically_across_both_tiles() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL | (1 << 2);
+            // Mark the first row of the top tile so the flip within a tile is visible too
+            mmu.graphics_ram[64] = 0xFF;
+        }
+        sprite(&mmu, 0, 0, 0, 4, 0x40);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..8], "........");
+        assert_eq!(&scanline(&gpu, 8)[..8], "++++++++");
+        assert_eq!(&scanline(&gpu, 15)[..8], "########");
+    }
+
+    #[test]
+    fn sprite_behind_background_only_covers_colour_zero() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            for i in 0..1024 {
+                mmu.graphics_ram[0x1800 + i] = 3;
             }
+        }
+        sprite(&mmu, 0, 0, 0, 2, 0x80);
+        sprite(&mmu, 1, 8, 0, 2, 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..16], "####............");
+    }
 
-            if is_signed {
-                tile_data_address += ((signed_tile_identifier as i16) + 128 * 16) as u16;
-            } else {
-                tile_data_address = 0x8000 + (unsigned_tile_identifier * 16);
+    // Background map row 0 is tiles 1, 0x80, 0xFF, 0x7F and 2, with different tiles at each of their possible addresses
+    fn addressing_fixture() -> (Rc<RefCell<MMU>>, GPU) {
+        let (mmu, gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            for row in 0..8 {
+                mmu.graphics_ram[0x1010 + row * 2] = 0xFF;
+                mmu.graphics_ram[0x0800 + row * 2 + 1] = 0xFF;
+                mmu.graphics_ram[0x0FF0 + row * 2] = 0xFF;
+                mmu.graphics_ram[0x0FF0 + row * 2 + 1] = 0xFF;
+                mmu.graphics_ram[0x17F0 + row * 2] = 0xFF;
             }
+            mmu.graphics_ram[0x1800..0x1805].copy_from_slice(&[0x01, 0x80, 0xFF, 0x7F, 0x02]);
+        }
+        (mmu, gpu)
+    }
 
-            let mut line: u16 = (y_pos % 8) as u16;
-            line *= 2;
+    #[test]
+    fn background_unsigned_tile_addressing() {
+        let (mmu, mut gpu) = addressing_fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = 0x91;
+        render(&mmu, &mut gpu, |_, _| ());
+        let tiles = expected(&[('#', 8), ('+', 8), ('#', 8), (' ', 8), ('.', 8)]);
+        assert_eq!(&scanline(&gpu, 0)[..40], tiles);
+        assert_eq!(&scanline(&gpu, 7)[..40], tiles);
+    }
 
-            let data1 = self.mmu.borrow_mut().rb(tile_data_address + line);
-            let data2 = self.mmu.borrow_mut().rb(tile_data_address + line + 1);
-            for mut j in (0..8).rev() {
-                let data_colour: u8 = self.get_bit(data2, j) << 1 | self.get_bit(data1, j);
+    #[test]
+    fn background_signed_tile_addressing() {
+        let (mmu, mut gpu) = addressing_fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = 0x81;
+        render(&mmu, &mut gpu, |_, _| ());
+        let tiles = expected(&[('.', 8), ('+', 8), ('#', 8), ('.', 8), (' ', 8)]);
+        assert_eq!(&scanline(&gpu, 0)[..40], tiles);
+        assert_eq!(&scanline(&gpu, 7)[..40], tiles);
+    }
 
-                let rgb = match data_colour {
-                    0b00 => self.get_colour(colour_palette, 0),
-                    0b01 => self.get_colour(colour_palette, 2),
-                    0b10 => self.get_colour(colour_palette, 4),
-                    0b11 => self.get_colour(colour_palette, 6),
-                    _ => {
-                        panic!("Wrong Combination");
-                    }
-                };
+    #[test]
+    fn window_signed_tile_addressing() {
+        let (mmu, mut gpu) = addressing_fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = LCD_CONTROL & !(1 << 4);
+            mmu.io_ram[0xFF4B - 0xFF00] = 7;
+            mmu.graphics_ram[0x1C00..0x1C03].copy_from_slice(&[0x01, 0xFF, 0x02]);
+        }
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..24], expected(&[('.', 8), ('#', 8), (' ', 8)]));
+    }
 
-                j -= 7;
-                j *= -1;
+    #[test]
+    fn background_disabled_blanks_background_and_window() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = LCD_CONTROL & !(1 << 0);
+            // Colour 0 is black in BGP, blanking still gives white
+            mmu.io_ram[0xFF47 - 0xFF00] = 0x1B;
+            mmu.io_ram[0xFF4B - 0xFF00] = 87;
+            for i in 0..1024 {
+                mmu.graphics_ram[0x1800 + i] = 1;
+            }
+        }
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(scanline(&gpu, 0), expected(&[(' ', 160)]));
+    }
 
-                let mut res: u32 = 0;
-                res = res << 8 | (rgb.0 as u32);
-                res = res << 8 | (rgb.1 as u32);
-                res = res << 8 | (rgb.2 as u32);
+    #[test]
+    fn background_disabled_keeps_sprites_on_top() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL & !(1 << 0);
+            for i in 0..1024 {
+                mmu.graphics_ram[0x1800 + i] = 1;
+            }
+        }
+        sprite(&mmu, 0, 0, 0, 2, 0x80);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(&scanline(&gpu, 0)[..16], "........        ");
+    }
 
-                let test: usize = j as usize + base as usize;
-                if current_scanline <= 143 && test <= 159 {
-                    let index: usize =
-                        ((current_scanline) as usize * 160) + (j as usize + base as usize);
-                    self.screen_data[index] = res;
-                }
+    const FRAME_DOTS: usize = 154 * 456;
+
+    // Runs the PPU dot by dot, returning the line and dot of every STAT interrupt
+    fn stat_interrupts(mmu: &Rc<RefCell<MMU>>, gpu: &mut GPU, dots: usize) -> Vec<(u8, u16)> {
+        let mut interrupts = Vec::new();
+        for _ in 0..dots {
+            gpu.update_graphics(1);
+            let mut mmu = mmu.borrow_mut();
+            if mmu.io_ram[0xFF0F - 0xFF00] & (1 << 1) > 0 {
+                interrupts.push((gpu.line, gpu.line_dot));
+                mmu.io_ram[0xFF0F - 0xFF00] &= !(1 << 1);
             }
         }
+        interrupts
     }
 
-    fn test_bit(&self, value: u8, index: u8) -> bool {
-        if value & (1 << index) > 0 {
-            return true;
+    fn set_stat(mmu: &Rc<RefCell<MMU>>, sources: u8, lyc: u8) {
+        let mut mmu = mmu.borrow_mut();
+        mmu.wb(0xFF41, sources);
+        mmu.io_ram[0xFF45 - 0xFF00] = lyc;
+    }
+
+    #[test]
+    fn stat_lyc_interrupt_fires_once() {
+        let (mmu, mut gpu) = fixture();
+        set_stat(&mmu, 1 << 6, 10);
+        assert_eq!(stat_interrupts(&mmu, &mut gpu, FRAME_DOTS), vec![(10, 4)]);
+    }
+
+    #[test]
+    fn stat_coincidence_flag_follows_ly() {
+        let (mmu, mut gpu) = fixture();
+        set_stat(&mmu, 0, 10);
+        gpu.update_graphics(10 * 456 + 4);
+        assert!(mmu.borrow().rb(0xFF41) & (1 << 2) > 0);
+        gpu.update_graphics(456);
+        assert_eq!(mmu.borrow().rb(0xFF41) & (1 << 2), 0);
+    }
+
+    #[test]
+    fn stat_reports_the_current_mode() {
+        let (mmu, mut gpu) = fixture();
+        let mut modes = Vec::new();
+        for _ in 0..456 {
+            gpu.update_graphics(1);
+            let mode = mmu.borrow().rb(0xFF41) & 0x03;
+            if modes.last() != Some(&mode) {
+                modes.push(mode);
+            }
         }
-        false
+        assert_eq!(modes, vec![2, 3, 0, 2]);
+        gpu.update_graphics(143 * 456);
+        assert_eq!(mmu.borrow().rb(0xFF41) & 0x03, 1);
     }
 
-    pub fn render_sprites(&mut self) {
-        let lcd_control: u8 = self.mmu.borrow_mut().rb(0xFF40);
-        let current_scanline: u8 = self.mmu.borrow_mut().rb(0xFF44);
-        let is_8x8: bool = lcd_control & (1 << 2) == 0;
+    #[test]
+    fn stat_hblank_interrupt_is_blocked_by_lyc() {
+        let (mmu, mut gpu) = fixture();
+        set_stat(&mmu, (1 << 3) | (1 << 6), 10);
+        let interrupts = stat_interrupts(&mmu, &mut gpu, FRAME_DOTS);
+        assert_eq!(interrupts.len(), 144);
+        assert_eq!(interrupts.iter().filter(|(line, _)| *line == 10).collect::<Vec<_>>(), vec![&(10, 4)]);
+        assert!(interrupts.iter().all(|(line, dot)| *line == 10 || *dot > 80));
+    }
 
-        for i in 0..40 {
-            // Check whether 8*8 or 8*16
-            // Check if position is in the scanline
-            // Get tile data and then edit framebuffer
-            // edit scanline using x_pos
-
-            let offset: u16 = (39 - i) * (4);
-            let y_pos: u8 = self
-                .mmu
-                .borrow()
-                .rb(offset.wrapping_add(0xFE00))
-                .wrapping_sub(16);
-            let x_pos: u8 = self
-                .mmu
-                .borrow()
-                .rb(offset.wrapping_add(0xFE00).wrapping_add(1))
-                .wrapping_sub(8);
-            let tile_identifier: u16 =
-                self.mmu
-                    .borrow()
-                    .rb(offset.wrapping_add(0xFE00).wrapping_add(2)) as u16;
-            let attributes: u8 = self
-                .mmu
-                .borrow()
-                .rb(offset.wrapping_add(0xFE00).wrapping_add(3));
-            let y_flip: bool = self.test_bit(attributes, 6);
-            let x_flip: bool = self.test_bit(attributes, 5);
-
-            let mut current_colour_palette: u8 = self.mmu.borrow_mut().rb(0xFF48);
-            if (attributes & (1 << 4)) == 0 {
-                current_colour_palette = self.mmu.borrow_mut().rb(0xFF49);
-            }
-
-            let y_offset = if is_8x8 { 8 } else { 16 };
-
-            if current_scanline >= y_pos && current_scanline < (y_pos + y_offset) {
-                let mut line: i32 = (current_scanline as i32) - (y_pos as i32);
-
-                if y_flip {
-                    line -= y_offset as i32;
-                    line *= -1;
-                }
+    #[test]
+    fn stat_vblank_interrupt() {
+        let (mmu, mut gpu) = fixture();
+        set_stat(&mmu, 1 << 4, 200);
+        assert_eq!(stat_interrupts(&mmu, &mut gpu, FRAME_DOTS), vec![(144, 0)]);
+    }
 
-                line *= 2;
-                let tile_data_address: u16 = 0x8000 + (tile_identifier * 16) + (line as u16);
-
-                let data1 = self.mmu.borrow_mut().rb(tile_data_address);
-                let data2 = self.mmu.borrow_mut().rb(tile_data_address + 1);
-                for mut j in (0..8).rev() {
-                    if x_flip {
-                        j -= 7;
-                        j *= -1;
-                    }
-                    let data_colour: u8 = self.get_bit(data2, j) << 1 | self.get_bit(data1, j);
-
-                    let rgb = match data_colour {
-                        0b00 => self.get_colour(current_colour_palette, 0),
-                        0b01 => self.get_colour(current_colour_palette, 2),
-                        0b10 => self.get_colour(current_colour_palette, 4),
-                        0b11 => self.get_colour(current_colour_palette, 6),
-                        _ => {
-                            panic!("Wrong Combination");
-                        }
-                    };
-
-                    // White is transparent
-                    if rgb.0 == 0xFF || rgb.1 == 0xFF || rgb.2 == 0xFF {
-                        continue;
-                    }
-
-                    j -= 7;
-                    j *= -1;
-
-                    let mut res: u32 = 0;
-                    res = res << 8 | (rgb.0 as u32);
-                    res = res << 8 | (rgb.1 as u32);
-                    res = res << 8 | (rgb.2 as u32);
-
-                    let test: usize =
-                        ((current_scanline) as usize * 160) + j as usize + x_pos as usize;
-                    let index: usize = test;
-                    if current_scanline < 144 && (j as usize + x_pos as usize) < 160 {
-                        self.screen_data[index] = res;
-                    }
-                }
+    #[test]
+    fn frame_ready_once_per_frame_as_vblank_starts() {
+        let (mmu, mut gpu) = fixture();
+        let mut ready: Vec<(u8, u16)> = Vec::new();
+        for _ in 0..FRAME_DOTS * 3 {
+            gpu.update_graphics(1);
+            if gpu.take_frame_ready() {
+                ready.push((mmu.borrow().rb(0xFF44), gpu.line_dot));
+            }
+        }
+        assert_eq!(ready, vec![(144, 0); 3]);
+    }
+
+    #[test]
+    fn stat_oam_interrupt_also_fires_at_vblank() {
+        let (mmu, mut gpu) = fixture();
+        set_stat(&mmu, 1 << 5, 200);
+        gpu.update_graphics(1);
+        mmu.borrow_mut().io_ram[0xFF0F - 0xFF00] = 0;
+        let interrupts = stat_interrupts(&mmu, &mut gpu, FRAME_DOTS);
+        assert_eq!(interrupts.len(), 145);
+        assert_eq!(interrupts[143], (144, 0));
+        assert_eq!(interrupts[144], (0, 0));
+    }
+
+    #[test]
+    fn stat_ly_reads_zero_during_line_153() {
+        let (mmu, mut gpu) = fixture();
+        for _ in 0..153 {
+            gpu.update_graphics(456);
+        }
+        gpu.update_graphics(2);
+        assert_eq!(mmu.borrow().rb(0xFF44), 153);
+        gpu.update_graphics(4);
+        assert_eq!(mmu.borrow().rb(0xFF44), 0);
+        assert_eq!(gpu.line, 153);
+    }
+
+    #[test]
+    fn stat_lyc_zero_fires_during_line_153() {
+        let (mmu, mut gpu) = fixture();
+        set_stat(&mmu, 1 << 6, 0);
+        gpu.update_graphics(1);
+        mmu.borrow_mut().io_ram[0xFF0F - 0xFF00] = 0;
+        assert_eq!(stat_interrupts(&mmu, &mut gpu, 2 * FRAME_DOTS), vec![(153, 4), (153, 4)]);
+    }
+
+    #[test]
+    fn stat_lcd_off_resets_ly_and_mode() {
+        let (mmu, mut gpu) = fixture();
+        gpu.update_graphics(50 * 456 + 100);
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = LCD_CONTROL & !(1 << 7);
+        gpu.update_graphics(4);
+        assert_eq!(mmu.borrow().rb(0xFF44), 0);
+        assert_eq!(mmu.borrow().rb(0xFF41) & 0x03, 0);
+    }
+
+    #[test]
+    fn stat_lcd_on_skips_oam_scan_on_first_line() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = LCD_CONTROL & !(1 << 7);
+        gpu.update_graphics(4);
+        set_stat(&mmu, 1 << 5, 200);
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = LCD_CONTROL;
+        gpu.update_graphics(79);
+        assert_eq!(mmu.borrow().rb(0xFF41) & 0x03, 0);
+        gpu.update_graphics(1);
+        assert_eq!(mmu.borrow().rb(0xFF41) & 0x03, 3);
+        assert_eq!(stat_interrupts(&mmu, &mut gpu, 456 - 80), vec![(1, 0)]);
+    }
+
+    const RED: u32 = 0xFF0000;
+    const GREEN: u32 = 0x00FF00;
+    const BLUE: u32 = 0x0000FF;
+    const WHITE: u32 = 0xFFFFFF;
+
+    // The same tiles and window map as fixture() on a CGB, all palette RAM is white
+    fn cgb_fixture() -> (Rc<RefCell<MMU>>, GPU) {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mmu = Rc::new(RefCell::new(MMU::with_model(rom, Model::Cgb)));
+        {
+            let (_, gpu) = fixture();
+            let dmg = gpu.mmu.borrow();
+            let mut mmu = mmu.borrow_mut();
+            mmu.graphics_ram[..0x2000].copy_from_slice(&dmg.graphics_ram[..0x2000]);
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        }
+        let gpu = GPU::new(mmu.clone());
+        (mmu, gpu)
+    }
+
+    fn set_cgb_colour(palette_ram: &mut [u8; 64], palette: usize, colour: usize, rgb555: u16) {
+        palette_ram[palette * 8 + colour * 2..palette * 8 + colour * 2 + 2].copy_from_slice(&rgb555.to_le_bytes());
+    }
+
+    fn cgb_scanline(gpu: &GPU, line: usize) -> &[u32] {
+        &gpu.screen_data[line * 160..(line + 1) * 160]
+    }
+
+    fn pixels(parts: &[(u32, usize)]) -> Vec<u32> {
+        parts.iter().flat_map(|(colour, n)| vec![*colour; *n]).collect()
+    }
+
+    #[test]
+    fn cgb_background_palettes_from_attributes() {
+        let (mmu, mut gpu) = cgb_fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            set_cgb_colour(&mut mmu.bg_palette_ram, 0, 3, 0x001F);
+            set_cgb_colour(&mut mmu.bg_palette_ram, 2, 3, 0x03E0);
+            mmu.graphics_ram[0x1800..0x1802].copy_from_slice(&[1, 1]);
+            mmu.graphics_ram[0x3801] = 2;
+        }
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(cgb_scanline(&gpu, 0)[..17], pixels(&[(RED, 8), (GREEN, 8), (WHITE, 1)]));
+    }
+
+    #[test]
+    fn cgb_background_tile_bank_and_flips() {
+        let (mmu, mut gpu) = cgb_fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            set_cgb_colour(&mut mmu.bg_palette_ram, 0, 3, 0x7C00);
+            // Tile 3 in bank 1 is black on its top row only
+            mmu.graphics_ram[0x2030..0x2032].copy_from_slice(&[0xFF, 0xFF]);
+            mmu.graphics_ram[0x1800..0x1803].copy_from_slice(&[3, 3, 3]);
+            mmu.graphics_ram[0x3800..0x3803].copy_from_slice(&[0, 1 << 5, (1 << 3) | (1 << 6)]);
+        }
+        render(&mmu, &mut gpu, |_, _| ());
+        let line = cgb_scanline(&gpu, 0);
+        assert_eq!(line[..8], pixels(&[(BLUE, 4), (WHITE, 4)]));
+        assert_eq!(line[8..16], pixels(&[(WHITE, 4), (BLUE, 4)]));
+        assert_eq!(line[16..24], [WHITE; 8]);
+        assert_eq!(cgb_scanline(&gpu, 7)[16..24], [BLUE; 8]);
+    }
+
+    #[test]
+    fn cgb_sprite_palettes_and_bank() {
+        let (mmu, mut gpu) = cgb_fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            set_cgb_colour(&mut mmu.obj_palette_ram, 5, 1, 0x001F);
+            set_cgb_colour(&mut mmu.obj_palette_ram, 5, 3, 0x03E0);
+            mmu.graphics_ram[0x2010..0x2020].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        }
+        sprite(&mmu, 0, 0, 0, 1, 5);
+        sprite(&mmu, 1, 8, 0, 1, 5 | (1 << 3));
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(cgb_scanline(&gpu, 0)[..16], pixels(&[(GREEN, 8), (RED, 8)]));
+    }
+
+    #[test]
+    fn cgb_sprites_use_oam_priority() {
+        let (mmu, mut gpu) = cgb_fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            set_cgb_colour(&mut mmu.obj_palette_ram, 0, 3, 0x001F);
+            set_cgb_colour(&mut mmu.obj_palette_ram, 1, 3, 0x03E0);
+        }
+        sprite(&mmu, 0, 4, 0, 1, 0);
+        sprite(&mmu, 1, 0, 0, 1, 1);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(cgb_scanline(&gpu, 0)[..12], pixels(&[(GREEN, 4), (RED, 8)]));
+
+        // OPRI bit 0 switches to the DMG's X ordering
+        mmu.borrow_mut().wb(0xFF6C, 1);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(cgb_scanline(&gpu, 0)[..12], pixels(&[(GREEN, 8), (RED, 4)]));
+    }
+
+    #[test]
+    fn cgb_background_priority() {
+        let (mmu, mut gpu) = cgb_fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            set_cgb_colour(&mut mmu.bg_palette_ram, 0, 3, 0x001F);
+            set_cgb_colour(&mut mmu.obj_palette_ram, 0, 3, 0x03E0);
+            // Tile 3 is black on its left half, the map attribute gives it priority
+            mmu.graphics_ram[0x1800] = 3;
+            mmu.graphics_ram[0x3800] = 1 << 7;
+        }
+        sprite(&mmu, 0, 0, 0, 1, 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(cgb_scanline(&gpu, 0)[..8], pixels(&[(RED, 4), (GREEN, 4)]));
+
+        // LCDC.0 clear puts sprites on top of everything
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL & !(1 << 0);
+        render(&mmu, &mut gpu, |_, _| ());
+        assert_eq!(cgb_scanline(&gpu, 0)[..8], [GREEN; 8]);
+        assert_eq!(cgb_scanline(&gpu, 0)[8], WHITE);
+    }
+
+    #[test]
+    fn colour_correction() {
+        let (mmu, mut gpu) = cgb_fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = LCD_CONTROL;
+        set_cgb_colour(&mut mmu.borrow_mut().bg_palette_ram, 0, 3, 0x001F);
+        gpu.set_colour_correction(super::ColourCorrection::Cgb);
+        render(&mmu, &mut gpu, |_, _| {});
+        // Full red bleeds into the other channels, white never reaches 0xFF
+        assert_eq!(cgb_scanline(&gpu, 0), pixels(&[(0xC9002E, 160)]));
+        assert_eq!(cgb_scanline(&gpu, 8), pixels(&[(0xF8F8F8, 160)]));
+
+        // The GBA curve mixes less and white stays below 0xFF there too
+        gpu.set_colour_correction(super::ColourCorrection::Gba);
+        render(&mmu, &mut gpu, |_, _| {});
+        assert_eq!(cgb_scanline(&gpu, 0), pixels(&[(0xE8356F, 160)]));
+        assert_eq!(cgb_scanline(&gpu, 8), pixels(&[(0xFCEEF2, 160)]));
+
+        gpu.set_colour_correction(super::ColourCorrection::None);
+        render(&mmu, &mut gpu, |_, _| {});
+        assert_eq!(cgb_scanline(&gpu, 0), pixels(&[(RED, 160)]));
+    }
+
+    #[test]
+    fn dmg_cartridge_on_cgb_uses_compat_palettes() {
+        let mmu = Rc::new(RefCell::new(MMU::with_model(vec![0; 0x8000], Model::Cgb)));
+        {
+            let (dmg, _) = fixture();
+            let dmg = dmg.borrow();
+            let mut mmu = mmu.borrow_mut();
+            mmu.graphics_ram[..0x2000].copy_from_slice(&dmg.graphics_ram[..0x2000]);
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL | (1 << 3);
+            mmu.io_ram[0xFF47 - 0xFF00] = 0xE4;
+            mmu.io_ram[0xFF49 - 0xFF00] = 0x1B;
+            set_cgb_colour(&mut mmu.bg_palette_ram, 0, 3, 0x001F);
+            set_cgb_colour(&mut mmu.bg_palette_ram, 0, 1, 0x03E0);
+            set_cgb_colour(&mut mmu.obj_palette_ram, 1, 0, 0x7C00);
+        }
+        let mut gpu = GPU::new(mmu.clone());
+        // OBP1 maps colour 3 to shade 0 of OBJ palette 1
+        sprite(&mmu, 0, 0, 0, 1, 1 << 4);
+        render(&mmu, &mut gpu, |_, _| {});
+        assert_eq!(cgb_scanline(&gpu, 0), pixels(&[(BLUE, 8), (RED, 152)]));
+        assert_eq!(cgb_scanline(&gpu, 8), pixels(&[(GREEN, 160)]));
+    }
+
+    // Steps line 0 dot by dot up to the given dot
+    fn run_to_dot(gpu: &mut GPU, dot: u16) {
+        while gpu.line_dot < dot {
+            gpu.update_graphics(1);
+        }
+    }
+
+    fn finish_line(gpu: &mut GPU) {
+        gpu.update_graphics(456 - gpu.line_dot);
+    }
+
+    // The dot of line 0 where mode 3 ends and HBlank starts
+    fn hblank_dot(mmu: &Rc<RefCell<MMU>>, gpu: &mut GPU) -> u16 {
+        let mut transferring = false;
+        loop {
+            gpu.update_graphics(1);
+            match mmu.borrow().rb(0xFF41) & 0x03 {
+                3 => transferring = true,
+                0 if transferring => return gpu.line_dot,
+                _ => (),
             }
         }
     }
 
-    fn get_colour(&mut self, palette: u8, index: i8) -> (u8, u8, u8) {
-        let bit: u8 = self.get_bit(palette, index) << 1 | self.get_bit(palette, index + 1);
+    // Mode 3 is 172 dots with nothing on the line: 6 dots of startup, the first tile fetch and 160 pixels
+    const HBLANK_DOT: u16 = 80 + 172;
+
+    #[test]
+    fn pixel_transfer_takes_longer_with_fine_scroll() {
+        for scroll_x in 0..16 {
+            let (mmu, mut gpu) = fixture();
+            mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            mmu.borrow_mut().io_ram[0xFF43 - 0xFF00] = scroll_x;
+            assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT + (scroll_x % 8) as u16, "SCX {}", scroll_x);
+        }
+    }
+
+    #[test]
+    fn pixel_transfer_takes_six_dots_longer_with_the_window() {
+        for window_x in [7, 8, 50, 166] {
+            let (mmu, mut gpu) = fixture();
+            mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = window_x;
+            assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT + 6, "WX {}", window_x);
+        }
+
+        // Below 7 the off screen window pixels are still shifted out, one per dot
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = 0;
+        assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT + 6 + 7);
+
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF4B - 0xFF00] = 167;
+        assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT);
+    }
+
+    #[test]
+    fn sprite_penalty_depends_on_position_in_the_background_tile() {
+        // Screen X, SCX and the dots the sprite adds: 6 plus the tile's pixels right of the sprite minus 2
+        for (x, scroll_x, penalty) in [(0, 0, 11), (1, 0, 10), (3, 0, 8), (5, 0, 6), (7, 0, 6), (36, 0, 7), (0, 3, 8), (152, 0, 11)] {
+            let (mmu, mut gpu) = fixture();
+            mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            mmu.borrow_mut().io_ram[0xFF43 - 0xFF00] = scroll_x;
+            sprite(&mmu, 0, x, 0, 1, 0);
+            let dot = hblank_dot(&mmu, &mut gpu);
+            assert_eq!(dot, HBLANK_DOT + scroll_x as u16 + penalty, "sprite at {} with SCX {}", x, scroll_x);
+        }
+    }
+
+    #[test]
+    fn sprite_penalty_at_oam_x_zero_and_off_the_right_edge() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        mmu.borrow_mut().sprite_oam[..4].copy_from_slice(&[16, 0, 1, 0]);
+        assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT + 11);
+
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        sprite(&mmu, 0, 160, 0, 1, 0);
+        assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT);
+    }
+
+    #[test]
+    fn sprites_sharing_a_background_tile_cost_six_dots_after_the_first() {
+        for count in 1..=10 {
+            let (mmu, mut gpu) = fixture();
+            mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            for i in 0..count {
+                sprite(&mmu, i, 0, 0, 1, 0);
+            }
+            assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT + 11 + 6 * (count as u16 - 1), "{} sprites", count);
+        }
+
+        // Same tile at different positions, then a sprite in the next tile pays the full penalty again
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        sprite(&mmu, 0, 0, 0, 1, 0);
+        sprite(&mmu, 1, 4, 0, 1, 0);
+        sprite(&mmu, 2, 8, 0, 1, 0);
+        assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT + 11 + 6 + 11);
+    }
+
+    #[test]
+    fn sprites_on_other_lines_cost_nothing() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        sprite(&mmu, 0, 0, 8, 1, 0);
+        assert_eq!(hblank_dot(&mmu, &mut gpu), HBLANK_DOT);
+    }
+
+    // Pixel lx is shifted out at dot 92 + lx: 80 dots of OAM scan, 6 of startup and 6 fetching the first tile
+    const WRITE_DOT: u16 = 92 + 40;
+
+    #[test]
+    fn palette_write_mid_line_only_changes_later_pixels() {
+        let (mmu, mut gpu) = fixture();
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+        run_to_dot(&mut gpu, WRITE_DOT);
+        mmu.borrow_mut().io_ram[0xFF47 - 0xFF00] = 0xE7;
+        finish_line(&mut gpu);
+        assert_eq!(scanline(&gpu, 0), expected(&[(' ', 40), ('#', 120)]));
+    }
+
+    #[test]
+    fn background_disabled_mid_line_only_blanks_later_pixels() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            mmu.graphics_ram[0x1800..0x1820].fill(1);
+        }
+        run_to_dot(&mut gpu, WRITE_DOT);
+        mmu.borrow_mut().io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL & !(1 << 0);
+        finish_line(&mut gpu);
+        assert_eq!(scanline(&gpu, 0), expected(&[('#', 40), (' ', 120)]));
+    }
 
-        match bit {
-            0b00 => (0xFF, 0xFF, 0xFF),
-            0b01 => (0xCC, 0xCC, 0xCC),
-            0b10 => (0x77, 0x77, 0x77),
-            0b11 => (0, 0, 0),
-            _ => {
-                panic!("Wrong Combination");
+    #[test]
+    fn scroll_write_mid_line_only_moves_later_tile_fetches() {
+        let (mmu, mut gpu) = fixture();
+        {
+            let mut mmu = mmu.borrow_mut();
+            mmu.io_ram[0xFF40 - 0xFF00] = SPRITE_LCD_CONTROL;
+            for column in 0..32 {
+                mmu.graphics_ram[0x1800 + column] = (column % 2) as u8;
             }
         }
+        let stripes = expected(&[(' ', 8), ('#', 8)]).repeat(10);
+        run_to_dot(&mut gpu, WRITE_DOT);
+        // Only the coarse scroll is picked up mid-line, the fine scroll of 4 waits for the next line
+        mmu.borrow_mut().io_ram[0xFF43 - 0xFF00] = 12;
+        finish_line(&mut gpu);
+        let line = scanline(&gpu, 0);
+        assert_eq!(line[..40], stripes[..40]);
+        assert_eq!(line[56..], stripes[64..].to_string() + &stripes[..8]);
     }
 }
@@ -0,0 +1,189 @@
+// Input processing that happens before the joypad state reaches the MMU
+// Everything works on joypad masks (see movie.rs) and advances once per emulated frame so it stays deterministic
+
+// Names used for the joypad bits in config files and macros
+pub const BUTTON_NAMES: [&str; 8] = ["right", "left", "up", "down", "a", "b", "select", "start"];
+
+pub const TURBO_ON_FRAMES: u32 = 2;
+pub const TURBO_OFF_FRAMES: u32 = 2;
+
+// Autofire: held turbo buttons are pressed for on_frames then released for off_frames
+pub struct Turbo {
+    on_frames: u32,
+    off_frames: u32,
+    held: u8,
+    counter: u32,
+}
+
+impl Turbo {
+    pub fn new(on_frames: u32, off_frames: u32) -> Self {
+        let mut turbo = Self { on_frames: 1, off_frames: 1, held: 0, counter: 0 };
+        turbo.set_period(on_frames, off_frames);
+        turbo
+    }
+
+    // Both halves last at least a frame, the cycle restarts from the pressed half
+    pub fn set_period(&mut self, on_frames: u32, off_frames: u32) {
+        self.on_frames = on_frames.max(1);
+        self.off_frames = off_frames.max(1);
+        self.counter = 0;
+    }
+
+    pub fn period(&self) -> (u32, u32) {
+        (self.on_frames, self.off_frames)
+    }
+
+    pub fn set_held(&mut self, held: u8) {
+        self.held = held;
+    }
+
+    // Buttons pressed by turbo this frame, the cycle restarts whenever no turbo button is held
+    pub fn next_frame(&mut self) -> u8 {
+        if self.held == 0 {
+            self.counter = 0;
+            return 0;
+        }
+        let pressed = if self.counter < self.on_frames { self.held } else { 0 };
+        self.counter = (self.counter + 1) % (self.on_frames + self.off_frames);
+        pressed
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MacroStep {
+    pub joypad: u8,
+    pub frames: u32,
+}
+
+// A named sequence of joypad states, each held for a number of frames
+#[derive(Clone, Debug)]
+pub struct InputMacro {
+    pub name: String,
+    pub steps: Vec<MacroStep>,
+}
+
+impl InputMacro {
+    // Steps are written as buttons joined with '+' and an optional frame count, e.g. "right:160", "a+b:2" or "none:30"
+    pub fn parse(name: &str, steps: &[&str]) -> Result<Self, String> {
+        let mut parsed: Vec<MacroStep> = Vec::new();
+        for step in steps {
+            let (buttons, frames) = match step.split_once(':') {
+                Some((buttons, frames)) => {
+                    let frames: u32 = frames.trim().parse().map_err(|_| format!("invalid frame count in macro step \"{}\"", step))?;
+                    (buttons, frames)
+                }
+                None => (*step, 1),
+            };
+
+            let mut joypad: u8 = 0;
+            for button in buttons.split('+').map(|button| button.trim().to_lowercase()) {
+                if button.is_empty() || button == "none" {
+                    continue;
+                }
+                let bit = BUTTON_NAMES
+                    .iter()
+                    .position(|name| *name == button)
+                    .ok_or_else(|| format!("unknown button \"{}\" in macro step \"{}\"", button, step))?;
+                joypad |= 1 << bit;
+            }
+            parsed.push(MacroStep { joypad, frames });
+        }
+        Ok(Self { name: name.to_string(), steps: parsed })
+    }
+}
+
+pub struct MacroPlayer {
+    steps: Vec<MacroStep>,
+    step: usize,
+    frames_left: u32,
+}
+
+impl MacroPlayer {
+    pub fn new(input_macro: &InputMacro) -> Self {
+        Self {
+            steps: input_macro.steps.clone(),
+            step: 0,
+            frames_left: input_macro.steps.first().map(|step| step.frames).unwrap_or(0),
+        }
+    }
+
+    // Joypad state for this frame, None once the macro has finished
+    pub fn next_frame(&mut self) -> Option<u8> {
+        while self.frames_left == 0 {
+            self.step += 1;
+            self.frames_left = self.steps.get(self.step)?.frames;
+        }
+        self.frames_left -= 1;
+        Some(self.steps[self.step].joypad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputMacro, MacroPlayer, MacroStep, Turbo};
+
+    fn frames(turbo: &mut Turbo, count: usize) -> Vec<u8> {
+        (0..count).map(|_| turbo.next_frame()).collect()
+    }
+
+    #[test]
+    fn turbo_duty_cycle() {
+        let mut turbo = Turbo::new(2, 3);
+        turbo.set_held(0x10);
+        assert_eq!(frames(&mut turbo, 10), [0x10, 0x10, 0, 0, 0, 0x10, 0x10, 0, 0, 0]);
+
+        // Letting go restarts the cycle from the pressed half
+        turbo.set_held(0);
+        assert_eq!(frames(&mut turbo, 2), [0, 0]);
+        turbo.set_held(0x30);
+        assert_eq!(frames(&mut turbo, 3), [0x30, 0x30, 0]);
+    }
+
+    #[test]
+    fn turbo_period_is_at_least_a_frame() {
+        let mut turbo = Turbo::new(0, 0);
+        assert_eq!(turbo.period(), (1, 1));
+        turbo.set_held(0x01);
+        assert_eq!(frames(&mut turbo, 4), [0x01, 0, 0x01, 0]);
+
+        turbo.set_period(3, 0);
+        assert_eq!(turbo.period(), (3, 1));
+        assert_eq!(frames(&mut turbo, 5), [0x01, 0x01, 0x01, 0, 0x01]);
+    }
+
+    #[test]
+    fn macro_steps_parse_to_joypad_masks() {
+        let input_macro = InputMacro::parse("test", &["right:160", "a+b:2", "none:30", "Start", " select + up : 4 "]).unwrap();
+        assert_eq!(
+            input_macro.steps,
+            vec![
+                MacroStep { joypad: 0x01, frames: 160 },
+                MacroStep { joypad: 0x30, frames: 2 },
+                MacroStep { joypad: 0x00, frames: 30 },
+                MacroStep { joypad: 0x80, frames: 1 },
+                MacroStep { joypad: 0x44, frames: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_parse_errors() {
+        let error = InputMacro::parse("test", &["a:2", "jump:3"]).unwrap_err();
+        assert!(error.contains("unknown button \"jump\""), "{}", error);
+        let error = InputMacro::parse("test", &["a:two"]).unwrap_err();
+        assert!(error.contains("invalid frame count"), "{}", error);
+        assert!(InputMacro::parse("test", &["a:-1"]).is_err());
+    }
+
+    #[test]
+    fn macro_player_holds_every_step_for_its_frames() {
+        let input_macro = InputMacro::parse("test", &["right:3", "none:0", "a:1", "b+a:2"]).unwrap();
+        let mut player = MacroPlayer::new(&input_macro);
+        let played: Vec<u8> = std::iter::from_fn(|| player.next_frame()).collect();
+        assert_eq!(played, [0x01, 0x01, 0x01, 0x10, 0x30, 0x30]);
+        assert_eq!(player.next_frame(), None);
+
+        let mut empty = MacroPlayer::new(&InputMacro::parse("empty", &[]).unwrap());
+        assert_eq!(empty.next_frame(), None);
+    }
+}
@@ -0,0 +1,21 @@
+pub mod bk2;
+pub mod capture;
+pub mod compat;
+pub mod cpu;
+pub mod emulator;
+pub mod filter;
+pub mod gif;
+pub mod gpu;
+pub mod hash;
+pub mod input;
+pub mod mmu;
+pub mod movie;
+pub mod palette;
+pub mod png;
+pub mod registers;
+pub mod rewind;
+pub mod sgb;
+pub mod state;
+pub mod vbm;
+pub mod video;
+pub mod zip;
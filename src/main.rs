@@ -1,23 +1,27 @@
-mod cpu;
-mod gpu;
-mod mmu;
-mod registers;
+mod config;
 
-use std::cell::RefCell;
 use std::fs::File;
 use std::io::Read;
-use std::rc::Rc;
 use std::env;
+use std::path::{Path, PathBuf};
 
-use cpu::CPU;
-use gpu::GPU;
-use mmu::MMU;
+use rustboy::capture;
+use rustboy::compat;
+use rustboy::emulator::{Emulator, REWIND_BUDGET, REWIND_INTERVAL};
+use rustboy::filter::{self, Filter};
+use rustboy::gpu::ColourCorrection;
+use rustboy::mmu::Model;
+use rustboy::movie::Movie;
+use rustboy::palette::{self, DmgPalette};
+use rustboy::{bk2, vbm};
+use rustboy::rewind::Rewind;
 
 extern crate minifb;
 use minifb::{Key, Window, WindowOptions, KeyRepeat};
 
-const WIDTH: usize = 160;
-const HEIGHT: usize = 144;
+use config::{Config, Hotkey};
+
+const FRAME_TIME: u64 = 16600; // Microseconds
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -25,20 +29,154 @@ fn main() {
         panic!("No ROM chosen");
     }
     let mut file_content: Vec<u8> = Vec::new();
+    let mut rewind_interval: u32 = REWIND_INTERVAL;
+    let mut rewind_budget: usize = REWIND_BUDGET / (1024 * 1024); // Megabytes
+    let mut speed: u32 = 100;
+    let mut record_movie: Option<String> = None;
+    let mut play_movie: Option<String> = None;
+    let mut record_video: Option<String> = None;
+    let mut config_path: PathBuf = config::default_path();
+    let mut ppu_access_restrictions: bool = true;
+    let mut model: Option<Model> = None;
+    let mut colour_correction: ColourCorrection = ColourCorrection::None;
+    let mut compat_buttons: u8 = 0;
+    let mut palette_name: Option<String> = None;
+    let mut filter_name: String = "none".to_string();
+    let mut frame_blending: Option<u32> = None;
+    let mut i: usize = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--rewind-interval" => {
+                rewind_interval = args.get(i + 1).and_then(|v| v.parse().ok()).expect("--rewind-interval takes a number of frames");
+                i += 1;
+            }
+            "--rewind-budget" => {
+                rewind_budget = args.get(i + 1).and_then(|v| v.parse().ok()).expect("--rewind-budget takes a size in megabytes");
+                i += 1;
+            }
+            "--speed" => {
+                speed = args.get(i + 1).and_then(|v| v.parse().ok()).expect("--speed takes a percentage between 25 and 800");
+                i += 1;
+            }
+            "--record-movie" => {
+                record_movie = Some(args.get(i + 1).expect("--record-movie takes a file name").clone());
+                i += 1;
+            }
+            "--play-movie" => {
+                play_movie = Some(args.get(i + 1).expect("--play-movie takes a file name").clone());
+                i += 1;
+            }
+            "--record-video" => {
+                record_video = Some(args.get(i + 1).expect("--record-video takes a file name").clone());
+                i += 1;
+            }
+            "--config" => {
+                config_path = PathBuf::from(args.get(i + 1).expect("--config takes a file name"));
+                i += 1;
+            }
+            "--no-ppu-restrictions" => ppu_access_restrictions = false,
+            "--model" => {
+                model = match args.get(i + 1).map(|v| v.as_str()) {
+                    Some("dmg") => Some(Model::Dmg),
+                    Some("cgb") => Some(Model::Cgb),
+                    Some("sgb") => Some(Model::Sgb),
+                    _ => panic!("--model takes dmg, cgb or sgb"),
+                };
+                i += 1;
+            }
+            "--colour-correction" => {
+                colour_correction = args.get(i + 1).and_then(|v| ColourCorrection::from_name(v)).expect("--colour-correction takes none, cgb or gba");
+                i += 1;
+            }
+            "--dmg-palette" => {
+                compat_buttons = args.get(i + 1).and_then(|v| compat::parse_combo(v)).expect("--dmg-palette takes a direction plus optionally a or b, such as up+a");
+                i += 1;
+            }
+            "--palette" => {
+                palette_name = Some(args.get(i + 1).expect("--palette takes a palette name").clone());
+                i += 1;
+            }
+            "--frame-blending" => {
+                frame_blending = Some(args.get(i + 1).and_then(|v| v.parse().ok()).expect("--frame-blending takes a percentage, 0 turns it off"));
+                i += 1;
+            }
+            "--filter" => {
+                filter_name = args.get(i + 1).expect("--filter takes a filter name").clone();
+                i += 1;
+            }
+            _ => panic!("Unknown argument {}", args[i]),
+        }
+        i += 1;
+    }
+
+    let config = Config::load(&config_path).unwrap_or_else(|e| panic!("{}", e));
+
+    // Palettes from the config file replace built in ones with the same name
+    let mut palettes: Vec<DmgPalette> = palette::presets();
+    palettes.retain(|preset| !config.palettes.iter().any(|palette| palette.name == preset.name));
+    palettes.extend(config.palettes.iter().cloned());
+    let palette_index: usize = match &palette_name {
+        Some(name) => palettes.iter().position(|palette| palette.name == *name).unwrap_or_else(|| {
+            let names: Vec<&str> = palettes.iter().map(|palette| palette.name.as_str()).collect();
+            panic!("Unknown palette {}, expected one of {}", name, names.join(", "))
+        }),
+        None => 0,
+    };
+    let filters: Vec<Box<dyn Filter>> = filter::filters();
+    let filter_index: usize = filters.iter().position(|filter| filter.name() == filter_name).unwrap_or_else(|| {
+        let names: Vec<&str> = filters.iter().map(|filter| filter.name()).collect();
+        panic!("Unknown filter {}, expected one of {}", filter_name, names.join(", "))
+    });
+
     let mut file: File = File::open(&args[1]).unwrap();
     file.read_to_end(&mut file_content).unwrap();
-    let mmu: Rc<RefCell<MMU>> = Rc::new(RefCell::new(MMU::new(file_content)));
 
-    let cpu = Rc::new(RefCell::new(CPU::new(Rc::clone(&mmu))));
-    let gpu = GPU::new(Rc::clone(&mmu));
+    let mut emulator = match model {
+        Some(model) => Emulator::with_model(file_content, model),
+        None => Emulator::new(file_content),
+    };
+    emulator.rewind = Rewind::new(rewind_interval, rewind_budget * 1024 * 1024);
+    emulator.set_speed(speed);
+    emulator.set_ppu_access_restrictions(ppu_access_restrictions);
+    emulator.set_colour_correction(colour_correction);
+    emulator.set_compat_palette(compat_buttons);
+    let palettes = Selection { options: palettes, index: palette_index };
+    emulator.set_dmg_palette(palettes.current().clone());
+    emulator.set_gif_buffer(config.gif_buffer_seconds);
+    match frame_blending {
+        Some(persistence) => emulator.set_frame_blending(persistence),
+        None if config.frame_blending_games.contains(&emulator.rom_title()) => emulator.set_frame_blending(config.frame_blending),
+        None => (),
+    }
+    emulator.set_turbo_period(config.turbo_on_frames, config.turbo_off_frames);
+
+    if let Some(path) = &play_movie {
+        let movie = if path.ends_with(".bk2") {
+            bk2::import(path, emulator.rom())
+        } else if path.ends_with(".vbm") {
+            vbm::import(path, emulator.rom())
+        } else {
+            Movie::load(path)
+        };
+        let movie = movie.unwrap_or_else(|e| panic!("Couldn't load movie {}: {}", path, e));
+        emulator.play_movie(movie).unwrap_or_else(|e| panic!("Couldn't play movie {}: {}", path, e));
+    } else if record_movie.is_some() {
+        emulator.start_recording(true);
+    }
 
+    if let Some(path) = &record_video {
+        emulator.start_video(Path::new(path)).unwrap_or_else(|e| panic!("Couldn't record video to {}: {}", path, e));
+    }
+
+    // The SGB picture with its border is larger, so it starts out at a smaller scale
+    let (width, height) = emulator.screen_size();
     let mut window = Window::new(
         "Gameboy Emulator - ESC to exit",
-        WIDTH,
-        HEIGHT,
+        width,
+        height,
         WindowOptions {
             resize: true,
-            scale: minifb::Scale::X4,
+            scale: if width > 160 { minifb::Scale::X2 } else { minifb::Scale::X4 },
             ..WindowOptions::default()
         },
     )
@@ -46,47 +184,137 @@ fn main() {
         panic!("{}", e);
     });
 
-    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
+    window.limit_update_rate(Some(std::time::Duration::from_micros(FRAME_TIME)));
+
+    let state_path = PathBuf::from(format!("{}.state", args[1]));
+    let filters = Selection { options: filters, index: filter_index };
+    cycle(&mut emulator, window, &config, &state_path, palettes, filters);
 
-    cycle(cpu, RefCell::new(gpu), window);
+    if let (Some(path), Some(movie)) = (&record_movie, emulator.stop_recording()) {
+        let result = if path.ends_with(".bk2") { bk2::export(&movie, emulator.rom(), path) } else { movie.save(path) };
+        result.unwrap_or_else(|e| panic!("Couldn't save movie {}: {}", path, e));
+    }
+    if let Some(path) = &record_video {
+        emulator.stop_video().unwrap_or_else(|e| panic!("Couldn't record video to {}: {}", path, e));
+    }
 }
 
-// pc is incremented in fetch_byte() so to get actual value, -1
-fn cycle(cpu: Rc<RefCell<CPU>>, gpu: RefCell<GPU>, mut window: Window) {
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-    const MAXCYCLES: u32 = 70221 * 1;
-    let mut cycles_elapsed: u32 = 0;
-    let mut _total_cycles: u32 = 0;
-    let mut cycles: u16 = 0;
+// The options a hotkey steps through, wrapping around at the end
+struct Selection<T> {
+    options: Vec<T>,
+    index: usize,
+}
+
+impl<T> Selection<T> {
+    fn current(&self) -> &T {
+        &self.options[self.index]
+    }
+
+    fn next(&mut self) -> &T {
+        self.index = (self.index + 1) % self.options.len();
+        self.current()
+    }
+}
+
+// Key bindings come from the config file, see config.rs for the defaults
+fn cycle(emulator: &mut Emulator, mut window: Window, config: &Config, state_path: &Path, mut palettes: Selection<DmgPalette>, mut filters: Selection<Box<dyn Filter>>) {
+    let (width, height) = emulator.screen_size();
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        while cycles_elapsed < MAXCYCLES {
-            if cpu.borrow().is_halted == false {
-                let opcode = cpu.borrow_mut().fetch_byte();
+        if is_hotkey_pressed(&window, config, Hotkey::Pause, KeyRepeat::No) {
+            emulator.set_paused(!emulator.is_paused());
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::FrameAdvance, KeyRepeat::Yes) {
+            emulator.frame_advance();
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::SaveState, KeyRepeat::No) {
+            if let Err(e) = emulator.save_state_file(state_path) {
+                eprintln!("Couldn't save state to {}: {}", state_path.display(), e);
+            }
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::LoadState, KeyRepeat::No) {
+            if let Err(e) = emulator.load_state_file(state_path) {
+                eprintln!("Couldn't load state from {}: {}", state_path.display(), e);
+            }
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::CyclePalette, KeyRepeat::No) {
+            emulator.set_dmg_palette(palettes.next().clone());
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::FrameBlending, KeyRepeat::No) {
+            let persistence = if emulator.frame_blending() > 0 { 0 } else { config.frame_blending };
+            emulator.set_frame_blending(persistence);
+            println!("Frame blending {}", if persistence > 0 { "on" } else { "off" });
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::CycleFilter, KeyRepeat::No) {
+            println!("Filter: {}", filters.next().name());
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::Screenshot, KeyRepeat::No) {
+            match emulator.save_screenshot(&config.screenshot_directory, config.screenshot_scale) {
+                Ok((path, _)) => println!("Saved screenshot to {}", path.display()),
+                Err(e) => eprintln!("Couldn't save screenshot to {}: {}", config.screenshot_directory.display(), e),
+            }
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::RecordGif, KeyRepeat::No) {
+            if emulator.is_recording_gif() {
+                if let Some(gif) = emulator.stop_gif() {
+                    save_gif(config, &gif);
+                }
+            } else {
+                emulator.start_gif();
+                println!("Recording GIF");
+            }
+        }
+        if is_hotkey_pressed(&window, config, Hotkey::SaveGifBuffer, KeyRepeat::No) {
+            if let Some(gif) = emulator.gif_buffer() {
+                save_gif(config, &gif);
+            }
+        }
 
-                cycles = (cpu.borrow_mut().execute(opcode) as u16) * 4;
-                cycles_elapsed += cycles as u32;
-                _total_cycles += cycles as u32;
+        for (keys, input_macro) in config.macros.iter() {
+            if keys.iter().any(|key| window.is_key_pressed(*key, KeyRepeat::No)) {
+                emulator.play_macro(input_macro);
             }
+        }
 
-            cpu.borrow_mut().mmu.borrow_mut().update_timers(cycles);
-            gpu.borrow_mut().update_graphics(cycles);
-            cpu.borrow_mut().do_interrupts();
+        let fast_forward = is_hotkey_down(&window, config, Hotkey::FastForward);
+        if fast_forward != emulator.is_fast_forward() {
+            emulator.set_fast_forward(fast_forward);
+            let frame_time = if fast_forward { None } else { Some(std::time::Duration::from_micros(FRAME_TIME)) };
+            window.limit_update_rate(frame_time);
         }
 
-        let keys = vec![Key::Right, Key::Left, Key::Up, Key::Down, Key::A, Key::S, Key::Space, Key::Enter];
-        for (i, key) in keys.iter().enumerate() {
-            if window.is_key_pressed(*key, KeyRepeat::No) {
-                cpu.borrow_mut().mmu.borrow_mut().poll_key_pressed(i as u8);
-            } else {
-                cpu.borrow_mut().mmu.borrow_mut().poll_key_released(i as u8);
-            }
+        // Input goes in before the frame runs, so it shows up in the very next picture
+        emulator.set_joypad(config.joypad(&window));
+        emulator.set_turbo(config.turbo(&window));
+        for player in 2..=4 {
+            emulator.set_player_joypad(player, config.player_joypad(player, &window));
         }
 
-        for (i, pixel) in gpu.borrow().screen_data.iter().enumerate() {
-            buffer[i] = *pixel;
+        // Holding rewind steps back one snapshot per displayed frame
+        // There is no sound emulation yet so rewinding is silent
+        if is_hotkey_down(&window, config, Hotkey::Rewind) {
+            emulator.rewind_step();
+        } else {
+            emulator.update();
         }
-        window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
-        cycles_elapsed = 0;
+
+        // minifb stretches the filtered picture to the window
+        let filter = filters.current();
+        let screen = filter.apply(&emulator.screen(), width, height);
+        window.update_with_buffer(&screen, width * filter.scale(), height * filter.scale()).unwrap();
+    }
+}
+
+fn save_gif(config: &Config, gif: &[u8]) {
+    match capture::save_capture(&config.screenshot_directory, "gif", gif) {
+        Ok(path) => println!("Saved GIF to {}", path.display()),
+        Err(e) => eprintln!("Couldn't save GIF to {}: {}", config.screenshot_directory.display(), e),
     }
 }
 
+fn is_hotkey_down(window: &Window, config: &Config, hotkey: Hotkey) -> bool {
+    config.hotkey(hotkey).iter().any(|key| window.is_key_down(*key))
+}
+
+fn is_hotkey_pressed(window: &Window, config: &Config, hotkey: Hotkey, repeat: KeyRepeat) -> bool {
+    config.hotkey(hotkey).iter().any(|key| window.is_key_pressed(*key, repeat))
+}
@@ -1,33 +1,107 @@
+use crate::sgb::Sgb;
+use crate::state::{write_u16, StateReader};
+
+const DMA_LENGTH: u8 = 0xA0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+    Sgb,
+}
+
+impl Model {
+    // Cartridges with bit 7 of the CGB flag (0x143) set are run on a CGB
+    // SGB games have 0x03 in the SGB flag (0x146) and the old licensee code (0x14B) set to 0x33
+    pub fn from_rom(rom: &[u8]) -> Self {
+        match rom.get(0x143) {
+            Some(flag) if flag & 0x80 > 0 => Model::Cgb,
+            _ if rom.get(0x146) == Some(&0x03) && rom.get(0x14B) == Some(&0x33) => Model::Sgb,
+            _ => Model::Dmg,
+        }
+    }
+}
+
 pub struct MMU {
     pub rom: [u8; 32769],
-    pub graphics_ram: [u8; 8192],
+    pub graphics_ram: [u8; 16384], // Two banks on CGB
     pub external_ram: [u8; 8192],
-    pub working_ram: [u8; 8192],
+    pub working_ram: [u8; 32768], // Bank 0 and seven switchable banks on CGB
     pub sprite_oam: [u8; 160],
     pub io_ram: [u8; 128],
     pub high_ram: [u8; 127], // Stack
     pub interrupt_enabled_register: u8,
-    joypad_state: u8,
+    joypad_state: [u8; 4], // One per SGB controller, only the first without MLT_REQ
     joypad_req: u8,
     timer_counter: u16,
     divider_counter: u16,
+    ppu_mode: u8,
+    pub ppu_access_restrictions: bool, // Can be turned off for debugging
+    dma_requested: Option<u16>,
+    dma_start_delay: u8,
+    dma_next_source: u16,
+    dma_source: u16,
+    dma_index: Option<u8>, // Next byte to copy while a transfer is running
+    dma_byte: u8,          // Last byte copied, what the CPU sees on the bus in use
+    pub model: Model,
+    cgb_mode: bool, // CGB hardware running a CGB cartridge, otherwise it behaves like a DMG
+    vram_bank: u8,
+    wram_bank: u8,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    pub bg_palette_ram: [u8; 64],  // 8 palettes of 4 little endian BGR555 colours
+    pub obj_palette_ram: [u8; 64],
+    hdma_source: u16,
+    hdma_destination: u16,
+    hdma_blocks_left: u8,
+    hdma_hblank_active: bool,
+    dma_stall: u16, // Cycles the CPU has to wait for a VRAM DMA
+    pub sgb: Option<Sgb>,
 }
 
 impl MMU {
     pub fn new(rom: Vec<u8>) -> Self {
+        let model = Model::from_rom(&rom);
+        Self::with_model(rom, model)
+    }
+
+    pub fn with_model(rom: Vec<u8>, model: Model) -> Self {
+        let cgb_mode: bool = model == Model::Cgb && Model::from_rom(&rom) == Model::Cgb;
         let mut mmu = Self {
             rom: [0; 32769],
-            graphics_ram: [0; 8192],
-            working_ram: [0; 8192],
+            graphics_ram: [0; 16384],
+            working_ram: [0; 32768],
             external_ram: [0; 8192],
             sprite_oam: [0; 160],
             io_ram: [0; 128],
             high_ram: [0; 127],
             interrupt_enabled_register: 0,
-            joypad_state: 0xFF,
+            joypad_state: [0xFF; 4],
             joypad_req: 0x00,
             timer_counter: 1024,
             divider_counter: 0,
+            ppu_mode: 0,
+            ppu_access_restrictions: true,
+            dma_requested: None,
+            dma_start_delay: 0,
+            dma_next_source: 0,
+            dma_source: 0,
+            dma_index: None,
+            dma_byte: 0xFF,
+            model,
+            cgb_mode,
+            vram_bank: 0,
+            wram_bank: 1,
+            double_speed: false,
+            speed_switch_armed: false,
+            bg_palette_ram: [0xFF; 64],
+            obj_palette_ram: [0xFF; 64],
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_blocks_left: 0,
+            hdma_hblank_active: false,
+            dma_stall: 0,
+            sgb: if model == Model::Sgb { Some(Sgb::new()) } else { None },
         };
 
         mmu.wb(0xFF05, 0x00);
@@ -73,16 +147,103 @@ impl MMU {
         mmu
     }
 
+    // ROM is never written so it is left out of save states
+    pub fn save_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&self.graphics_ram);
+        state.extend_from_slice(&self.external_ram);
+        state.extend_from_slice(&self.working_ram);
+        state.extend_from_slice(&self.sprite_oam);
+        state.extend_from_slice(&self.io_ram);
+        state.extend_from_slice(&self.high_ram);
+        state.push(self.interrupt_enabled_register);
+        state.extend_from_slice(&self.joypad_state);
+        state.push(self.joypad_req);
+        write_u16(state, self.timer_counter);
+        write_u16(state, self.divider_counter);
+        state.push(self.dma_requested.is_some() as u8);
+        write_u16(state, self.dma_requested.unwrap_or(0));
+        state.push(self.dma_start_delay);
+        write_u16(state, self.dma_next_source);
+        write_u16(state, self.dma_source);
+        state.push(self.dma_index.unwrap_or(0xFF));
+        state.push(self.dma_byte);
+        state.extend_from_slice(&[self.vram_bank, self.wram_bank, self.double_speed as u8, self.speed_switch_armed as u8]);
+        state.extend_from_slice(&self.bg_palette_ram);
+        state.extend_from_slice(&self.obj_palette_ram);
+        write_u16(state, self.hdma_source);
+        write_u16(state, self.hdma_destination);
+        state.extend_from_slice(&[self.hdma_blocks_left, self.hdma_hblank_active as u8]);
+        write_u16(state, self.dma_stall);
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(state);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.read_bytes(&mut self.graphics_ram);
+        state.read_bytes(&mut self.external_ram);
+        state.read_bytes(&mut self.working_ram);
+        state.read_bytes(&mut self.sprite_oam);
+        state.read_bytes(&mut self.io_ram);
+        state.read_bytes(&mut self.high_ram);
+        self.interrupt_enabled_register = state.read_u8();
+        state.read_bytes(&mut self.joypad_state);
+        self.joypad_req = state.read_u8();
+        self.timer_counter = state.read_u16();
+        self.divider_counter = state.read_u16();
+        let dma_requested = state.read_bool();
+        let dma_requested_source = state.read_u16();
+        self.dma_requested = if dma_requested { Some(dma_requested_source) } else { None };
+        self.dma_start_delay = state.read_u8();
+        self.dma_next_source = state.read_u16();
+        self.dma_source = state.read_u16();
+        self.dma_index = match state.read_u8() {
+            0xFF => None,
+            index => Some(index),
+        };
+        self.dma_byte = state.read_u8();
+        self.vram_bank = state.read_u8();
+        self.wram_bank = state.read_u8();
+        self.double_speed = state.read_bool();
+        self.speed_switch_armed = state.read_bool();
+        state.read_bytes(&mut self.bg_palette_ram);
+        state.read_bytes(&mut self.obj_palette_ram);
+        self.hdma_source = state.read_u16();
+        self.hdma_destination = state.read_u16();
+        self.hdma_blocks_left = state.read_u8();
+        self.hdma_hblank_active = state.read_bool();
+        self.dma_stall = state.read_u16();
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.load_state(state);
+        }
+    }
+
     pub fn rb(&self, address: u16) -> u8 {
         match address {
+            0x0000..=0xFDFF if self.is_dma_bus_conflict(address) => self.dma_byte,
+            0xFE00..=0xFE9F if self.dma_index.is_some() => 0xFF,
             0x0000..=0x7FFF => self.rom[address as usize],
-            0x8000..=0x9FFF => self.graphics_ram[(address - 0x8000) as usize],
+            0x8000..=0x9FFF if self.is_vram_blocked() => 0xFF,
+            0x8000..=0x9FFF => self.graphics_ram[self.vram_index(address)],
             0xA000..=0xBFFF => self.external_ram[(address - 0xA000) as usize],
-            0xC000..=0xDFFF => self.working_ram[(address - 0xC000) as usize],
-            0xE000..=0xFDFF => self.working_ram[(address - 0xE000) as usize],
+            0xC000..=0xFDFF => self.working_ram[self.wram_index(address)],
+            0xFE00..=0xFE9F if self.is_oam_blocked() => 0xFF,
             0xFE00..=0xFE9F => self.sprite_oam[(address - 0xFE00) as usize],
             0xFF00 => self.get_joypad_state(),
             0xFF04 => self.divider_counter as u8,
+            0xFF41 => self.io_ram[0xFF41 - 0xFF00] | 0x80,
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6C | 0xFF70 | 0xFF74 if !self.cgb_mode => 0xFF,
+            0xFF51..=0xFF54 => 0xFF,
+            0xFF55 => (!self.hdma_hblank_active as u8) << 7 | (self.hdma_blocks_left.wrapping_sub(1) & 0x7F),
+            0xFF4D => 0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8,
+            0xFF68 | 0xFF6A => self.io_ram[(address - 0xFF00) as usize] | 0x40,
+            0xFF69 | 0xFF6B if self.is_vram_blocked() => 0xFF,
+            0xFF69 => self.bg_palette_ram[(self.io_ram[0xFF68 - 0xFF00] & 0x3F) as usize],
+            0xFF6B => self.obj_palette_ram[(self.io_ram[0xFF6A - 0xFF00] & 0x3F) as usize],
+            0xFF6C => 0xFE | self.io_ram[0xFF6C - 0xFF00],
+            0xFF4F => 0xFE | self.vram_bank,
+            0xFF70 => 0xF8 | self.wram_bank,
+            0xFF75 => 0x8F | self.io_ram[0xFF75 - 0xFF00],
             0xFF00..=0xFF7F => self.io_ram[(address - 0xFF00) as usize],
             0xFF80..=0xFFFE => self.high_ram[(address - 0xFF80) as usize],
             0xFFFF => self.interrupt_enabled_register,
@@ -96,10 +257,14 @@ impl MMU {
 
     pub fn wb(&mut self, address: u16, value: u8) {
         match address {
-            0x8000..=0x9FFF => self.graphics_ram[(address - 0x8000) as usize] = value,
+            0x0000..=0xFDFF if self.is_dma_bus_conflict(address) => (),
+            0xFE00..=0xFE9F if self.dma_index.is_some() => (),
+            0x8000..=0x9FFF if self.is_vram_blocked() => (),
+            0x8000..=0x9FFF => self.graphics_ram[self.vram_index(address)] = value,
             0xA000..=0xBFFF => self.external_ram[(address - 0xA000) as usize] = value,
-            0xC000..=0xDFFF => self.working_ram[(address - 0xC000) as usize] = value,
-            0xE000..=0xFDFF => self.working_ram[(address - 0xE000) as usize] = value,
+            0xC000..=0xFDFF => self.working_ram[self.wram_index(address)] = value,
+            0xFE00..=0xFE9F if self.is_oam_blocked() => (),
+            0xFE00..=0xFE9F => self.sprite_oam[(address - 0xFE00) as usize] = value,
             0xFF04 => self.io_ram[0xFF04 - 0xFF00] = 0, // Divisor Register
             0xFF07 => {
                 let current_frequency = self.rb(0xFF07) & 0x3;
@@ -109,9 +274,26 @@ impl MMU {
                     self.set_timer_counter();
                 }
             }
+            0xFF41 => self.io_ram[0xFF41 - 0xFF00] = (value & 0x78) | (self.io_ram[0xFF41 - 0xFF00] & 0x07), // Mode and coincidence bits are read only
             0xFF44 => self.io_ram[0xFF44 - 0xFF00] = 0,
-            0xFF46 => self.dma_transfer(value as u16),
-            0xFF00 =>  self.joypad_req = value,
+            0xFF46 => {
+                self.io_ram[0xFF46 - 0xFF00] = value;
+                self.dma_requested = Some((value as u16) << 8);
+            }
+            0xFF00 => self.set_joypad_select(value),
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6C | 0xFF70 | 0xFF74 if !self.cgb_mode => (),
+            0xFF51 => self.hdma_source = (self.hdma_source & 0x00FF) | (value as u16) << 8,
+            0xFF52 => self.hdma_source = (self.hdma_source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.hdma_destination = (self.hdma_destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+            0xFF54 => self.hdma_destination = (self.hdma_destination & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 => self.start_hdma(value),
+            0xFF4D => self.speed_switch_armed = value & 0x1 > 0,
+            0xFF68 | 0xFF6A => self.io_ram[(address - 0xFF00) as usize] = value & 0xBF,
+            0xFF69 | 0xFF6B => self.write_palette_data(address - 1, value),
+            0xFF6C => self.io_ram[0xFF6C - 0xFF00] = value & 0x1,
+            0xFF4F => self.vram_bank = value & 0x1,
+            0xFF70 => self.wram_bank = value & 0x7,
+            0xFF75 => self.io_ram[0xFF75 - 0xFF00] = value & 0x70,
             0xFF00..=0xFF7F => self.io_ram[(address - 0xFF00) as usize] = value,
             0xFF80 => (),
             0xFF80..=0xFFFE => self.high_ram[(address - 0xFF80) as usize] = value,
@@ -125,13 +307,175 @@ impl MMU {
         self.wb(address + 1, (value >> 8) as u8);
     }
 
-    fn dma_transfer(&mut self, data: u16) {
-        let address: u16 = data << 8;
-        for i in 0x00..0xA0 {
-            self.sprite_oam[i] = self.rb((address + i as u16) as u16);
+    // CGB banking
+    // VBK (0xFF4F) picks the VRAM bank, SVBK (0xFF70) the work RAM bank at 0xD000 where 0 selects bank 1
+    // Both stay at their DMG layout unless a CGB cartridge runs on CGB hardware
+
+    fn vram_index(&self, address: u16) -> usize {
+        (self.vram_bank as usize) * 0x2000 + (address - 0x8000) as usize
+    }
+
+    // Also covers echo RAM at 0xE000-0xFDFF
+    fn wram_index(&self, address: u16) -> usize {
+        let offset = (address & 0x1FFF) as usize;
+        if offset < 0x1000 {
+            offset
+        } else {
+            (self.wram_bank.max(1) as usize) * 0x1000 + offset - 0x1000
+        }
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called on STOP, switches speed if KEY1 (0xFF4D) was armed
+    pub fn speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        true
+    }
+
+    // CGB palettes
+    // BCPS/OCPS (0xFF68/0xFF6A) hold the palette RAM index in bits 0-5, bit 7 increments it after every data write
+    // Like VRAM, palette RAM can't be reached during pixel transfer, the index still increments
+
+    fn write_palette_data(&mut self, specification: u16, value: u8) {
+        let specification_index = (specification - 0xFF00) as usize;
+        let index = (self.io_ram[specification_index] & 0x3F) as usize;
+        if !self.is_vram_blocked() {
+            if specification == 0xFF68 {
+                self.bg_palette_ram[index] = value;
+            } else {
+                self.obj_palette_ram[index] = value;
+            }
+        }
+        if self.io_ram[specification_index] & 0x80 > 0 {
+            self.io_ram[specification_index] = 0x80 | ((index as u8 + 1) & 0x3F);
+        }
+    }
+
+    // VRAM DMA (HDMA1-5, 0xFF51-0xFF55)
+    // Copies blocks of 16 bytes to VRAM, either all at once (general purpose) or one block per H-Blank
+    // The CPU waits 8 M-cycles per block, twice as many in double speed since the copy runs at normal speed
+    // Writing 0xFF55 with bit 7 clear during an H-Blank DMA stops it, 0xFF55 then reads the blocks left with bit 7 set
+
+    fn start_hdma(&mut self, value: u8) {
+        if self.hdma_hblank_active && value & 0x80 == 0 {
+            self.hdma_hblank_active = false;
+            return;
+        }
+
+        self.hdma_blocks_left = (value & 0x7F) + 1;
+        if value & 0x80 > 0 {
+            self.hdma_hblank_active = true;
+            // With the LCD off no H-Blank is coming, so the first block is copied straight away
+            if self.io_ram[0xFF40 - 0xFF00] & 0x80 == 0 {
+                self.hblank_dma();
+            }
+        } else {
+            while self.hdma_blocks_left > 0 {
+                self.hdma_block();
+            }
+        }
+    }
+
+    // Called by the GPU as every visible line enters H-Blank
+    pub fn hblank_dma(&mut self) {
+        if self.hdma_hblank_active {
+            self.hdma_block();
+            self.hdma_hblank_active = self.hdma_blocks_left > 0;
+        }
+    }
+
+    fn hdma_block(&mut self) {
+        for _ in 0..16 {
+            let value = self.read_dma_source(self.hdma_source);
+            let index = self.vram_index(0x8000 | (self.hdma_destination & 0x1FFF));
+            self.graphics_ram[index] = value;
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_destination = (self.hdma_destination + 1) & 0x1FFF;
+        }
+        self.hdma_blocks_left -= 1;
+        self.dma_stall += if self.double_speed { 64 } else { 32 };
+    }
+
+    // Cycles the CPU is held up for by VRAM DMA since the last call
+    pub fn take_dma_stall(&mut self) -> u16 {
+        std::mem::take(&mut self.dma_stall)
+    }
+
+    // OAM DMA
+    // Writing 0xFF46 copies 160 bytes to OAM, one per M-cycle after a setup M-cycle
+    // Meanwhile the CPU only has HRAM and IO: OAM reads 0xFF and the bus being copied from returns the byte in transit
+    // A new write restarts the transfer, the old one keeps going until the new one has been set up
+
+    pub fn update_dma(&mut self, cycles: u16) {
+        // The instruction that wrote 0xFF46 has already used up its cycles
+        let requested = self.dma_requested.take();
+        for _ in 0..cycles / 4 {
+            self.dma_cycle();
+        }
+        if let Some(source) = requested {
+            self.dma_next_source = source;
+            self.dma_start_delay = 1;
+        }
+    }
+
+    fn dma_cycle(&mut self) {
+        if let Some(index) = self.dma_index {
+            self.dma_byte = self.read_dma_source(self.dma_source + index as u16);
+            self.sprite_oam[index as usize] = self.dma_byte;
+            self.dma_index = if index + 1 < DMA_LENGTH { Some(index + 1) } else { None };
+        }
+        if self.dma_start_delay > 0 {
+            self.dma_start_delay -= 1;
+            if self.dma_start_delay == 0 {
+                self.dma_source = self.dma_next_source;
+                self.dma_index = Some(0);
+            }
+        }
+    }
+
+    // DMA reads memory directly, sources above 0xDFFF end up in work RAM
+    fn read_dma_source(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => self.rom[address as usize],
+            0x8000..=0x9FFF => self.graphics_ram[self.vram_index(address)],
+            0xA000..=0xBFFF => self.external_ram[(address - 0xA000) as usize],
+            _ => self.working_ram[self.wram_index(address)],
         }
     }
 
+    // VRAM has its own bus, everything else below OAM shares the external bus
+    fn is_dma_bus_conflict(&self, address: u16) -> bool {
+        let is_vram = |address: u16| (0x8000..=0x9FFF).contains(&address);
+        self.dma_index.is_some() && is_vram(address) == is_vram(self.dma_source)
+    }
+
+    // PPU access
+    // The GPU keeps ppu_mode up to date, the CPU can't reach VRAM during pixel transfer (mode 3)
+    // and OAM during OAM scan and pixel transfer (modes 2 and 3), reads return 0xFF and writes are ignored
+
+    pub fn set_ppu_mode(&mut self, mode: u8) {
+        self.ppu_mode = mode;
+    }
+
+    fn is_vram_blocked(&self) -> bool {
+        self.ppu_access_restrictions && self.ppu_mode == 3
+    }
+
+    fn is_oam_blocked(&self) -> bool {
+        self.ppu_access_restrictions && self.ppu_mode >= 2
+    }
+
     // Interrupts
 
     pub fn request_interrupt(&mut self, index: u8) {
@@ -140,49 +484,61 @@ impl MMU {
     }
 
     // Joypad
+    // joypad_state holds one bit per key (0 = pressed): 0-3 Right, Left, Up, Down and 4-7 A, B, Select, Start
+    // Bit 4 of P1 low selects the directions and bit 5 low selects the buttons, both can be selected at once
 
     pub fn poll_key_pressed(&mut self, key: u8) {
-        let mut previously_unset: bool = false;
-
-        if (self.joypad_state & (1 << key)) == 0 {
-            previously_unset = true;
-        }
+        let previous_lines = self.get_joypad_lines();
+        self.joypad_state[0] &= !(1 << key);
+        self.check_joypad_interrupt(previous_lines);
+    }
 
-        self.joypad_state &= !(1 << key);
+    pub fn poll_key_released(&mut self, key: u8) {
+        self.joypad_state[0] |= 1 << key;
+    }
 
-        let mut is_standard_button: bool = false;
+    // Controllers 2-4 of an SGB, joypad is a mask of held buttons like the movie format uses
+    pub fn set_player_joypad(&mut self, player: usize, joypad: u8) {
+        self.joypad_state[player] = !joypad;
+    }
 
-        if key > 3 {
-            is_standard_button = true;
+    fn set_joypad_select(&mut self, value: u8) {
+        let previous_lines = self.get_joypad_lines();
+        self.joypad_req = value & 0x30;
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.write_joypad(self.joypad_req);
         }
+        self.check_joypad_interrupt(previous_lines);
+    }
 
-        let mut request_interrupt: bool = false;
-
-        if is_standard_button && self.joypad_req == 0x20 {
-            // Standard
-            request_interrupt = true;
-        } else if !is_standard_button && (self.joypad_req == 0x10) {
-            // Directional
-            request_interrupt = true;
+    // Input lines P10-P13 as seen through the selected groups
+    fn get_joypad_lines(&self) -> u8 {
+        let player: usize = self.sgb.as_ref().map_or(0, |sgb| sgb.player());
+        let mut lines: u8 = 0x0F;
+        if self.joypad_req & (1 << 4) == 0 {
+            lines &= self.joypad_state[player] & 0x0F;
         }
-
-        if request_interrupt == true && previously_unset == false {
-            self.request_interrupt(4);
+        if self.joypad_req & (1 << 5) == 0 {
+            lines &= self.joypad_state[player] >> 4;
+        }
+        if self.joypad_req == 0x30 {
+            if let Some(id) = self.sgb.as_ref().and_then(|sgb| sgb.controller_id()) {
+                lines = id;
+            }
         }
+        lines
     }
 
+    // Bits 6 and 7 are unused and always read as 1
     fn get_joypad_state(&self) -> u8 {
-        let mut res = 0;
-        if self.joypad_req == 0x10 {
-            res = self.joypad_state >> 4;
-        } else if self.joypad_req == 0x20 {
-            res = self.joypad_state & 0xF;
-        }
-        res
+        0xC0 | self.joypad_req | self.get_joypad_lines()
     }
 
-    pub fn poll_key_released(&mut self, key: u8) {
-        self.joypad_state |= 1 << key;
+    // The interrupt fires when any input line goes from high to low
+    fn check_joypad_interrupt(&mut self, previous_lines: u8) {
+        if previous_lines & !self.get_joypad_lines() & 0x0F > 0 {
+            self.request_interrupt(4);
+        }
     }
 
     // Timer
@@ -237,3 +593,556 @@ impl MMU {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Model, MMU, Sgb};
+
+    const RIGHT: u8 = 0;
+    const UP: u8 = 2;
+    const A: u8 = 4;
+    const B: u8 = 5;
+    const START: u8 = 7;
+
+    fn joypad_interrupt_requested(mmu: &MMU) -> bool {
+        mmu.rb(0xFF0F) & (1 << 4) > 0
+    }
+
+    #[test]
+    fn unused_bits_read_as_one() {
+        let mut mmu = MMU::new(Vec::new());
+        for select in [0x00, 0x10, 0x20, 0x30] {
+            mmu.wb(0xFF00, select);
+            assert_eq!(mmu.rb(0xFF00) & 0xC0, 0xC0);
+        }
+    }
+
+    #[test]
+    fn only_select_bits_are_written() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0xFF);
+        assert_eq!(mmu.rb(0xFF00), 0xFF);
+        mmu.wb(0xFF00, 0xEF);
+        assert_eq!(mmu.rb(0xFF00), 0xEF);
+        mmu.wb(0xFF00, 0x0A);
+        assert_eq!(mmu.rb(0xFF00), 0xCF);
+    }
+
+    #[test]
+    fn nothing_selected_reads_released() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x30);
+        for key in 0..8 {
+            mmu.poll_key_pressed(key);
+        }
+        assert_eq!(mmu.rb(0xFF00), 0xFF);
+    }
+
+    #[test]
+    fn directions_selected() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x20);
+        mmu.poll_key_pressed(RIGHT);
+        mmu.poll_key_pressed(UP);
+        mmu.poll_key_pressed(A);
+        assert_eq!(mmu.rb(0xFF00), 0xEA);
+
+        mmu.poll_key_released(UP);
+        assert_eq!(mmu.rb(0xFF00), 0xEE);
+    }
+
+    #[test]
+    fn buttons_selected() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x10);
+        mmu.poll_key_pressed(A);
+        mmu.poll_key_pressed(START);
+        mmu.poll_key_pressed(RIGHT);
+        assert_eq!(mmu.rb(0xFF00), 0xD6);
+    }
+
+    #[test]
+    fn both_groups_selected() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x00);
+        mmu.poll_key_pressed(RIGHT);
+        mmu.poll_key_pressed(B);
+        assert_eq!(mmu.rb(0xFF00), 0xCC);
+    }
+
+    #[test]
+    fn interrupt_on_press_of_selected_key() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x20);
+        mmu.wb(0xFF0F, 0x00);
+
+        mmu.poll_key_pressed(A);
+        assert!(!joypad_interrupt_requested(&mmu));
+
+        mmu.poll_key_pressed(RIGHT);
+        assert!(joypad_interrupt_requested(&mmu));
+    }
+
+    #[test]
+    fn no_interrupt_on_held_key_or_release() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x20);
+        mmu.poll_key_pressed(RIGHT);
+        mmu.wb(0xFF0F, 0x00);
+
+        mmu.poll_key_pressed(RIGHT);
+        mmu.poll_key_released(RIGHT);
+        assert!(!joypad_interrupt_requested(&mmu));
+    }
+
+    #[test]
+    fn no_interrupt_when_line_already_low() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x00);
+        mmu.poll_key_pressed(RIGHT);
+        mmu.wb(0xFF0F, 0x00);
+
+        // A shares P10 with Right, which is already pulling it low
+        mmu.poll_key_pressed(A);
+        assert!(!joypad_interrupt_requested(&mmu));
+    }
+
+    #[test]
+    fn interrupt_when_selecting_held_key() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFF00, 0x30);
+        mmu.poll_key_pressed(A);
+        mmu.wb(0xFF0F, 0x00);
+
+        mmu.wb(0xFF00, 0x10);
+        assert!(joypad_interrupt_requested(&mmu));
+    }
+
+    #[test]
+    fn stat_mode_and_coincidence_bits_are_read_only() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.io_ram[0xFF41 - 0xFF00] = 0x06;
+        mmu.wb(0xFF41, 0xFF);
+        assert_eq!(mmu.rb(0xFF41), 0xFE);
+        mmu.wb(0xFF41, 0x00);
+        assert_eq!(mmu.rb(0xFF41), 0x86);
+    }
+
+    #[test]
+    fn vram_blocked_during_pixel_transfer() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0x8000, 0x12);
+        mmu.set_ppu_mode(3);
+        assert_eq!(mmu.rb(0x8000), 0xFF);
+        mmu.wb(0x8000, 0x34);
+        mmu.set_ppu_mode(2);
+        assert_eq!(mmu.rb(0x8000), 0x12);
+    }
+
+    #[test]
+    fn oam_blocked_during_oam_scan_and_pixel_transfer() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.wb(0xFE00, 0x12);
+        for mode in [2, 3] {
+            mmu.set_ppu_mode(mode);
+            assert_eq!(mmu.rb(0xFE00), 0xFF);
+            mmu.wb(0xFE00, 0x34);
+        }
+        for mode in [0, 1] {
+            mmu.set_ppu_mode(mode);
+            assert_eq!(mmu.rb(0xFE00), 0x12);
+        }
+    }
+
+    #[test]
+    fn ppu_access_restrictions_can_be_turned_off() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.ppu_access_restrictions = false;
+        mmu.set_ppu_mode(3);
+        mmu.wb(0x8000, 0x12);
+        mmu.wb(0xFE00, 0x34);
+        assert_eq!(mmu.rb(0x8000), 0x12);
+        assert_eq!(mmu.rb(0xFE00), 0x34);
+    }
+
+    fn start_dma(mmu: &mut MMU, source: u8) {
+        mmu.wb(0xFF46, source);
+        // The writing instruction, then the setup M-cycle
+        mmu.update_dma(4);
+        mmu.update_dma(4);
+    }
+
+    #[test]
+    fn dma_copies_one_byte_per_m_cycle() {
+        let mut mmu = MMU::new(Vec::new());
+        for i in 0..0xA0 {
+            mmu.working_ram[i] = i as u8 + 1;
+        }
+        start_dma(&mut mmu, 0xC0);
+        assert_eq!(mmu.sprite_oam[0], 0);
+        mmu.update_dma(159 * 4);
+        assert_eq!(mmu.sprite_oam[158], 159);
+        assert_eq!(mmu.sprite_oam[159], 0);
+        mmu.update_dma(4);
+        assert_eq!(mmu.sprite_oam[159], 160);
+        assert_eq!(mmu.rb(0xFE00), 1);
+    }
+
+    #[test]
+    fn dma_restricts_cpu_to_hram() {
+        let mut mmu = MMU::new(vec![0x42; 0x8000]);
+        mmu.working_ram[..0xA0].copy_from_slice(&[0x99; 0xA0]);
+        mmu.high_ram[1] = 0x12;
+        mmu.graphics_ram[0] = 0x34;
+        start_dma(&mut mmu, 0xC0);
+        mmu.update_dma(4);
+        assert_eq!(mmu.rb(0xFF81), 0x12);
+        assert_eq!(mmu.rb(0xFE00), 0xFF);
+        // ROM and work RAM share the bus the transfer is using
+        assert_eq!(mmu.rb(0x0000), 0x99);
+        assert_eq!(mmu.rb(0xC100), 0x99);
+        assert_eq!(mmu.rb(0x8000), 0x34);
+        assert_eq!(mmu.rb(0xFF46), 0xC0);
+        mmu.wb(0xC100, 0x56);
+        assert_eq!(mmu.working_ram[0x100], 0);
+    }
+
+    #[test]
+    fn dma_from_vram_blocks_vram() {
+        let mut mmu = MMU::new(vec![0x42; 0x8000]);
+        mmu.graphics_ram[..0xA0].copy_from_slice(&[0x99; 0xA0]);
+        start_dma(&mut mmu, 0x80);
+        mmu.update_dma(4);
+        assert_eq!(mmu.rb(0x9000), 0x99);
+        assert_eq!(mmu.rb(0x0000), 0x42);
+    }
+
+    #[test]
+    fn dma_restart_keeps_old_transfer_until_setup() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.working_ram[..0xA0].copy_from_slice(&[0x11; 0xA0]);
+        mmu.working_ram[0x1000..0x10A0].copy_from_slice(&[0x22; 0xA0]);
+        start_dma(&mut mmu, 0xC0);
+        mmu.update_dma(50 * 4);
+        start_dma(&mut mmu, 0xD0);
+        assert_eq!(&mmu.sprite_oam[..52], &[0x11; 52]);
+        assert_eq!(mmu.sprite_oam[52], 0);
+        assert_eq!(mmu.rb(0xFE00), 0xFF);
+        mmu.update_dma(160 * 4);
+        assert_eq!(mmu.sprite_oam, [0x22; 0xA0]);
+    }
+
+    #[test]
+    fn dma_sources_above_work_ram_are_echoed() {
+        let mut mmu = MMU::new(Vec::new());
+        mmu.working_ram[0x1E00..0x1EA0].copy_from_slice(&[0x33; 0xA0]);
+        start_dma(&mut mmu, 0xFE);
+        mmu.update_dma(160 * 4);
+        assert_eq!(mmu.sprite_oam, [0x33; 0xA0]);
+    }
+
+    fn cgb_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        rom
+    }
+
+    #[test]
+    fn model_comes_from_the_header() {
+        assert_eq!(MMU::new(cgb_rom()).model, Model::Cgb);
+        assert_eq!(MMU::new(vec![0; 0x8000]).model, Model::Dmg);
+        assert!(!MMU::with_model(vec![0; 0x8000], Model::Cgb).is_cgb_mode());
+        assert!(!MMU::with_model(cgb_rom(), Model::Dmg).is_cgb_mode());
+    }
+
+    #[test]
+    fn vram_bank_switching() {
+        let mut mmu = MMU::new(cgb_rom());
+        mmu.wb(0x8000, 0x12);
+        mmu.wb(0xFF4F, 0x01);
+        assert_eq!(mmu.rb(0xFF4F), 0xFF);
+        assert_eq!(mmu.rb(0x8000), 0x00);
+        mmu.wb(0x8000, 0x34);
+        mmu.wb(0xFF4F, 0x00);
+        assert_eq!(mmu.rb(0xFF4F), 0xFE);
+        assert_eq!(mmu.rb(0x8000), 0x12);
+        assert_eq!(mmu.graphics_ram[0x2000], 0x34);
+    }
+
+    #[test]
+    fn wram_bank_switching() {
+        let mut mmu = MMU::new(cgb_rom());
+        for bank in 1..8 {
+            mmu.wb(0xFF70, bank);
+            mmu.wb(0xD000, bank * 0x10);
+        }
+        mmu.wb(0xC000, 0x01);
+        for bank in 1..8 {
+            mmu.wb(0xFF70, bank);
+            assert_eq!(mmu.rb(0xD000), bank * 0x10);
+            assert_eq!(mmu.rb(0xF000), bank * 0x10);
+            assert_eq!(mmu.rb(0xC000), 0x01);
+        }
+        // Bank 0 selects bank 1
+        mmu.wb(0xFF70, 0x00);
+        assert_eq!(mmu.rb(0xFF70), 0xF8);
+        assert_eq!(mmu.rb(0xD000), 0x10);
+    }
+
+    #[test]
+    fn cgb_registers_are_locked_on_dmg() {
+        let mut mmu = MMU::new(vec![0; 0x8000]);
+        for address in [0xFF4D, 0xFF4F, 0xFF70, 0xFF74] {
+            mmu.wb(address, 0x01);
+            assert_eq!(mmu.rb(address), 0xFF);
+        }
+        mmu.wb(0xFF70, 0x02);
+        mmu.wb(0xD000, 0x12);
+        assert_eq!(mmu.working_ram[0x1000], 0x12);
+        assert!(!mmu.speed_switch());
+    }
+
+    #[test]
+    fn speed_switch_needs_key1_armed() {
+        let mut mmu = MMU::new(cgb_rom());
+        assert_eq!(mmu.rb(0xFF4D), 0x7E);
+        assert!(!mmu.speed_switch());
+        mmu.wb(0xFF4D, 0x01);
+        assert_eq!(mmu.rb(0xFF4D), 0x7F);
+        assert!(mmu.speed_switch());
+        assert!(mmu.is_double_speed());
+        assert_eq!(mmu.rb(0xFF4D), 0xFE);
+        mmu.wb(0xFF4D, 0x01);
+        assert!(mmu.speed_switch());
+        assert!(!mmu.is_double_speed());
+    }
+
+    #[test]
+    fn undocumented_cgb_registers() {
+        let mut mmu = MMU::new(cgb_rom());
+        for address in [0xFF72, 0xFF73, 0xFF74] {
+            mmu.wb(address, 0x5A);
+            assert_eq!(mmu.rb(address), 0x5A);
+        }
+        mmu.wb(0xFF75, 0xFF);
+        assert_eq!(mmu.rb(0xFF75), 0xFF);
+        mmu.wb(0xFF75, 0x00);
+        assert_eq!(mmu.rb(0xFF75), 0x8F);
+    }
+
+    #[test]
+    fn palette_data_auto_increments() {
+        let mut mmu = MMU::new(cgb_rom());
+        mmu.wb(0xFF68, 0x80 | 0x3E);
+        for value in [0x1F, 0x00, 0xE0] {
+            mmu.wb(0xFF69, value);
+        }
+        assert_eq!(mmu.bg_palette_ram[0x3E..], [0x1F, 0x00]);
+        assert_eq!(mmu.bg_palette_ram[0], 0xE0);
+        assert_eq!(mmu.rb(0xFF68), 0xC1);
+
+        mmu.wb(0xFF6A, 0x05);
+        mmu.wb(0xFF6B, 0x12);
+        mmu.wb(0xFF6B, 0x34);
+        assert_eq!(mmu.rb(0xFF6A), 0x45);
+        assert_eq!(mmu.rb(0xFF6B), 0x34);
+    }
+
+    #[test]
+    fn palette_data_blocked_during_pixel_transfer() {
+        let mut mmu = MMU::new(cgb_rom());
+        mmu.wb(0xFF68, 0x80);
+        mmu.set_ppu_mode(3);
+        mmu.wb(0xFF69, 0x12);
+        assert_eq!(mmu.rb(0xFF69), 0xFF);
+        mmu.set_ppu_mode(0);
+        assert_eq!(mmu.bg_palette_ram[0], 0xFF);
+        assert_eq!(mmu.rb(0xFF68), 0xC1);
+    }
+
+    fn setup_hdma(mmu: &mut MMU) {
+        for i in 0..0x100 {
+            mmu.working_ram[i] = i as u8;
+        }
+        mmu.wb(0xFF51, 0xC0);
+        mmu.wb(0xFF52, 0x00);
+        mmu.wb(0xFF53, 0x81);
+        mmu.wb(0xFF54, 0x00);
+    }
+
+    #[test]
+    fn general_purpose_dma_copies_and_stalls() {
+        let mut mmu = MMU::new(cgb_rom());
+        setup_hdma(&mut mmu);
+        mmu.wb(0xFF55, 0x01);
+        assert_eq!(mmu.graphics_ram[0x100..0x120], mmu.working_ram[..0x20]);
+        assert_eq!(mmu.graphics_ram[0x120], 0);
+        assert_eq!(mmu.take_dma_stall(), 2 * 32);
+        assert_eq!(mmu.take_dma_stall(), 0);
+        assert_eq!(mmu.rb(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn general_purpose_dma_in_double_speed() {
+        let mut mmu = MMU::new(cgb_rom());
+        mmu.wb(0xFF4D, 0x01);
+        mmu.speed_switch();
+        setup_hdma(&mut mmu);
+        mmu.wb(0xFF55, 0x00);
+        assert_eq!(mmu.take_dma_stall(), 64);
+    }
+
+    #[test]
+    fn hblank_dma_copies_a_block_per_hblank() {
+        let mut mmu = MMU::new(cgb_rom());
+        setup_hdma(&mut mmu);
+        mmu.wb(0xFF55, 0x82);
+        assert_eq!(mmu.rb(0xFF55), 0x02);
+        assert_eq!(mmu.graphics_ram[0x101], 0);
+        for blocks_left in [0x01, 0x00, 0xFF] {
+            mmu.hblank_dma();
+            assert_eq!(mmu.rb(0xFF55), blocks_left);
+        }
+        mmu.hblank_dma();
+        assert_eq!(mmu.graphics_ram[0x100..0x130], mmu.working_ram[..0x30]);
+        assert_eq!(mmu.graphics_ram[0x130], 0);
+        assert_eq!(mmu.take_dma_stall(), 3 * 32);
+    }
+
+    #[test]
+    fn hblank_dma_with_the_lcd_off_copies_a_block_at_once() {
+        let mut mmu = MMU::new(cgb_rom());
+        setup_hdma(&mut mmu);
+        mmu.wb(0xFF40, 0x11);
+        mmu.wb(0xFF55, 0x81);
+        assert_eq!(mmu.rb(0xFF55), 0x00);
+        assert_eq!(mmu.graphics_ram[0x100..0x110], mmu.working_ram[..0x10]);
+        assert_eq!(mmu.graphics_ram[0x110], 0);
+        assert_eq!(mmu.take_dma_stall(), 32);
+
+        // The rest waits for H-Blanks once the LCD is back on
+        mmu.hblank_dma();
+        assert_eq!(mmu.rb(0xFF55), 0xFF);
+        assert_eq!(mmu.graphics_ram[0x110..0x120], mmu.working_ram[0x10..0x20]);
+    }
+
+    #[test]
+    fn hblank_dma_can_be_cancelled() {
+        let mut mmu = MMU::new(cgb_rom());
+        setup_hdma(&mut mmu);
+        mmu.wb(0xFF55, 0x83);
+        mmu.hblank_dma();
+        mmu.wb(0xFF55, 0x00);
+        assert_eq!(mmu.rb(0xFF55), 0x82);
+        mmu.hblank_dma();
+        assert_eq!(mmu.graphics_ram[0x110], 0);
+    }
+
+    #[test]
+    fn hdma_destination_is_masked_to_vram_bank() {
+        let mut mmu = MMU::new(cgb_rom());
+        setup_hdma(&mut mmu);
+        mmu.wb(0xFF4F, 0x01);
+        mmu.wb(0xFF53, 0xFF);
+        mmu.wb(0xFF54, 0xFF);
+        mmu.wb(0xFF55, 0x00);
+        assert_eq!(mmu.graphics_ram[0x3FF0..0x4000], mmu.working_ram[..0x10]);
+    }
+
+    #[test]
+    fn hdma_registers_are_locked_on_dmg() {
+        let mut mmu = MMU::new(vec![0; 0x8000]);
+        setup_hdma(&mut mmu);
+        mmu.wb(0xFF55, 0x00);
+        assert_eq!(mmu.graphics_ram[0x100], 0);
+        assert_eq!(mmu.rb(0xFF55), 0xFF);
+    }
+
+    fn sgb_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        rom
+    }
+
+    // Pulses P14/P15 like a game does, padding the packet out with zeros
+    fn send_packet(mmu: &mut MMU, bytes: &[u8]) {
+        mmu.wb(0xFF00, 0x00);
+        mmu.wb(0xFF00, 0x30);
+        for i in 0..16 * 8 + 1 {
+            let bit = bytes.get(i / 8).map_or(0, |byte| (byte >> (i % 8)) & 1);
+            mmu.wb(0xFF00, if bit > 0 && i < 128 { 0x10 } else { 0x20 });
+            mmu.wb(0xFF00, 0x30);
+        }
+    }
+
+    // The pixel at x, y of the 160x144 picture inside the border
+    fn sgb_pixel(mmu: &MMU, shade: u8, x: usize, y: usize) -> u32 {
+        let mut sgb = Sgb::new();
+        let mut state: Vec<u8> = Vec::new();
+        mmu.sgb.as_ref().unwrap().save_state(&mut state);
+        sgb.load_state(&mut crate::state::StateReader::new(&state));
+        sgb.frame_end(&[shade; 160 * 144]);
+        let mut screen = vec![0; 256 * 224];
+        sgb.render(&mut screen);
+        screen[(40 + y) * 256 + 48 + x]
+    }
+
+    #[test]
+    fn sgb_model_comes_from_the_header() {
+        assert_eq!(MMU::new(sgb_rom()).model, Model::Sgb);
+        assert!(MMU::new(sgb_rom()).sgb.is_some());
+        assert!(MMU::new(vec![0; 0x8000]).sgb.is_none());
+    }
+
+    #[test]
+    fn sgb_palette_packet() {
+        let mut mmu = MMU::new(sgb_rom());
+        // PAL01: colour 0, palette 0 colours 1-3, palette 1 colours 1-3
+        send_packet(&mut mmu, &[0x01, 0xFF, 0x7F, 0x1F, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0xE0, 0x03, 0xE0, 0x03, 0xE0, 0x03]);
+        assert_eq!(sgb_pixel(&mmu, 0, 0, 0), 0xFFFFFF);
+        assert_eq!(sgb_pixel(&mmu, 3, 0, 0), 0xFF0000);
+    }
+
+    #[test]
+    fn sgb_attribute_block() {
+        let mut mmu = MMU::new(sgb_rom());
+        send_packet(&mut mmu, &[0x01, 0xFF, 0x7F, 0x1F, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0xE0, 0x03, 0xE0, 0x03, 0xE0, 0x03]);
+        // ATTR_BLK: one block from cell 1,1 to 2,2, palette 1 inside (and so on the edge)
+        send_packet(&mut mmu, &[0x21, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x02]);
+        assert_eq!(sgb_pixel(&mmu, 1, 7, 7), 0xFF0000);
+        assert_eq!(sgb_pixel(&mmu, 1, 8, 8), 0x00FF00);
+        assert_eq!(sgb_pixel(&mmu, 1, 23, 23), 0x00FF00);
+        assert_eq!(sgb_pixel(&mmu, 1, 24, 16), 0xFF0000);
+    }
+
+    #[test]
+    fn sgb_palette_transfer_and_set() {
+        let mut mmu = MMU::new(sgb_rom());
+        // PAL_TRN with a frame of shade 3 loads 0x7FFF everywhere, PAL_SET then picks system palettes
+        send_packet(&mut mmu, &[0x59]);
+        mmu.sgb.as_mut().unwrap().frame_end(&[3; 160 * 144]);
+        send_packet(&mut mmu, &[0x51]);
+        assert_eq!(sgb_pixel(&mmu, 0, 0, 0), 0xFFFFFF);
+        assert_eq!(sgb_pixel(&mmu, 2, 0, 0), 0xFFFFFF);
+    }
+
+    #[test]
+    fn sgb_multiple_controllers() {
+        let mut mmu = MMU::new(sgb_rom());
+        mmu.set_player_joypad(1, 1 << A);
+        mmu.wb(0xFF00, 0x30);
+        assert_eq!(mmu.rb(0xFF00), 0xFF);
+        // MLT_REQ for two players, a rising P15 selects the next controller
+        send_packet(&mut mmu, &[0x89, 0x01]);
+        assert_eq!(mmu.rb(0xFF00), 0xFF);
+        mmu.wb(0xFF00, 0x10);
+        assert_eq!(mmu.rb(0xFF00), 0xDF);
+        mmu.wb(0xFF00, 0x30);
+        assert_eq!(mmu.rb(0xFF00), 0xFE);
+        mmu.wb(0xFF00, 0x10);
+        assert_eq!(mmu.rb(0xFF00), 0xDE);
+        mmu.wb(0xFF00, 0x30);
+        assert_eq!(mmu.rb(0xFF00), 0xFF);
+    }
+}
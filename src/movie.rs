@@ -0,0 +1,309 @@
+use crate::mmu::Model;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+// Input movies record every change of the joypad together with the frame it happened on
+// Joypad states are button masks where a set bit means pressed:
+// bit 0 Right, 1 Left, 2 Up, 3 Down, 4 A, 5 B, 6 Select, 7 Start
+
+const MAGIC: &[u8; 4] = b"RBMV";
+const FORMAT_VERSION: u8 = 1;
+
+pub enum StartCondition {
+    PowerOn,
+    SaveState(Vec<u8>),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InputEvent {
+    pub frame: u32,
+    pub joypad: u8,
+}
+
+pub struct Movie {
+    pub rom_hash: u32,
+    pub model: Model, // Replaying on another model desyncs
+    pub emulator_version: String,
+    pub start: StartCondition,
+    pub length: u32, // Frames
+    pub events: Vec<InputEvent>,
+}
+
+impl Movie {
+    pub fn new(rom_hash: u32, model: Model, start: StartCondition) -> Self {
+        Self {
+            rom_hash,
+            model,
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            start,
+            length: 0,
+            events: Vec::new(),
+        }
+    }
+
+    // Joypad state in effect while the given frame is emulated
+    pub fn joypad_at(&self, frame: u32) -> u8 {
+        let mut joypad: u8 = 0;
+        for event in self.events.iter() {
+            if event.frame > frame {
+                break;
+            }
+            joypad = event.joypad;
+        }
+        joypad
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let version_length = u16::try_from(self.emulator_version.len()).map_err(|_| invalid("emulator version is too long for a movie"))?;
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.push(FORMAT_VERSION);
+        data.extend_from_slice(&self.rom_hash.to_le_bytes());
+        data.push(match self.model {
+            Model::Dmg => 0,
+            Model::Cgb => 1,
+            Model::Sgb => 2,
+        });
+        data.extend_from_slice(&version_length.to_le_bytes());
+        data.extend_from_slice(self.emulator_version.as_bytes());
+
+        match &self.start {
+            StartCondition::PowerOn => data.push(0),
+            StartCondition::SaveState(state) => {
+                data.push(1);
+                data.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                data.extend_from_slice(state);
+            }
+        }
+
+        data.extend_from_slice(&self.length.to_le_bytes());
+        data.extend_from_slice(&(self.events.len() as u32).to_le_bytes());
+        for event in self.events.iter() {
+            data.extend_from_slice(&event.frame.to_le_bytes());
+            data.push(event.joypad);
+        }
+
+        File::create(path)?.write_all(&data)
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut data: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let mut reader = MovieReader { data: &data, position: 0 };
+
+        if reader.take(4)? != MAGIC {
+            return Err(invalid("not a rustboy movie"));
+        }
+        if reader.u8()? != FORMAT_VERSION {
+            return Err(invalid("unsupported movie format version"));
+        }
+
+        let rom_hash = reader.u32()?;
+        let model = match reader.u8()? {
+            0 => Model::Dmg,
+            1 => Model::Cgb,
+            2 => Model::Sgb,
+            _ => return Err(invalid("unknown movie model")),
+        };
+        let version_length = reader.u16()? as usize;
+        let emulator_version = String::from_utf8_lossy(reader.take(version_length)?).to_string();
+
+        let start = match reader.u8()? {
+            0 => StartCondition::PowerOn,
+            1 => {
+                let state_length = reader.u32()? as usize;
+                StartCondition::SaveState(reader.take(state_length)?.to_vec())
+            }
+            _ => return Err(invalid("unknown movie start condition")),
+        };
+
+        let length = reader.u32()?;
+        let event_count = reader.u32()?;
+        let mut events: Vec<InputEvent> = Vec::new();
+        for _ in 0..event_count {
+            let frame = reader.u32()?;
+            let joypad = reader.u8()?;
+            events.push(InputEvent { frame, joypad });
+        }
+
+        Ok(Self { rom_hash, model, emulator_version, start, length, events })
+    }
+}
+
+// Records joypad changes as they happen, frames are counted from the start of the recording
+pub struct MovieRecorder {
+    pub movie: Movie,
+    last_joypad: Option<u8>,
+}
+
+impl MovieRecorder {
+    pub fn new(movie: Movie) -> Self {
+        Self { movie, last_joypad: None }
+    }
+
+    pub fn record(&mut self, frame: u32, joypad: u8) {
+        if self.last_joypad != Some(joypad) {
+            self.movie.events.push(InputEvent { frame, joypad });
+            self.last_joypad = Some(joypad);
+        }
+        self.movie.length = frame + 1;
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// Feeds the joypad states of a movie back frame by frame
+pub struct MoviePlayer {
+    pub movie: Movie,
+    next_event: usize,
+    joypad: u8,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        Self { movie, next_event: 0, joypad: 0 }
+    }
+
+    // Frames must be asked for in order, returns None once the movie has ended
+    pub fn joypad(&mut self, frame: u32) -> Option<u8> {
+        if frame >= self.movie.length {
+            return None;
+        }
+        while self.next_event < self.movie.events.len() && self.movie.events[self.next_event].frame <= frame {
+            self.joypad = self.movie.events[self.next_event].joypad;
+            self.next_event += 1;
+        }
+        Some(self.joypad)
+    }
+}
+
+struct MovieReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> MovieReader<'a> {
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.position + length > self.data.len() {
+            return Err(invalid("movie file is truncated"));
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputEvent, Movie, MovieRecorder, StartCondition};
+    use crate::emulator::tests::joypad_rom;
+    use crate::emulator::Emulator;
+    use crate::mmu::Model;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn movie_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustboy-movie-{}-{}.rbm", name, std::process::id()))
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut recorder = MovieRecorder::new(Movie::new(0x12345678, Model::Cgb, StartCondition::SaveState(vec![1, 2, 3, 4, 5])));
+        for (frame, joypad) in [0x00, 0x00, 0x10, 0x11, 0x11, 0x00].into_iter().enumerate() {
+            recorder.record(frame as u32, joypad);
+        }
+        let movie = recorder.finish();
+
+        let path = movie_path("round-trip");
+        movie.save(path.to_str().unwrap()).unwrap();
+        let loaded = Movie::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.rom_hash, 0x12345678);
+        assert_eq!(loaded.model, Model::Cgb);
+        assert_eq!(loaded.emulator_version, env!("CARGO_PKG_VERSION"));
+        assert!(matches!(loaded.start, StartCondition::SaveState(ref state) if state == &[1, 2, 3, 4, 5]));
+        assert_eq!(loaded.length, 6);
+        assert_eq!(
+            loaded.events,
+            [InputEvent { frame: 0, joypad: 0x00 }, InputEvent { frame: 2, joypad: 0x10 }, InputEvent { frame: 3, joypad: 0x11 }, InputEvent { frame: 5, joypad: 0x00 }]
+        );
+    }
+
+    #[test]
+    fn long_emulator_version_is_kept_whole() {
+        let mut movie = Movie::new(0, Model::Dmg, StartCondition::PowerOn);
+        movie.emulator_version = "1.0.0+".to_string() + &"build".repeat(100);
+        let path = movie_path("long-version");
+        movie.save(path.to_str().unwrap()).unwrap();
+        let loaded = Movie::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.emulator_version, movie.emulator_version);
+
+        movie.emulator_version = "x".repeat(70000);
+        assert!(movie.save(path.to_str().unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Replaying a recording ends in exactly the state the recording did
+    fn assert_replay_matches(from_power_on: bool) {
+        let mut emulator = Emulator::new(joypad_rom());
+        for _ in 0..30 {
+            emulator.run_frame();
+        }
+        emulator.start_recording(from_power_on);
+        for frame in 0..120u32 {
+            emulator.set_joypad(if frame % 7 < 3 { 0x10 } else { (frame as u8) & 0x30 });
+            emulator.run_frame();
+        }
+        let end = emulator.save_state();
+        let movie = emulator.stop_recording().unwrap();
+
+        let path = movie_path(if from_power_on { "power-on" } else { "save-state" });
+        movie.save(path.to_str().unwrap()).unwrap();
+        let movie = Movie::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(movie.length, 120);
+
+        let mut replay = Emulator::new(joypad_rom());
+        for _ in 0..50 {
+            replay.run_frame();
+        }
+        replay.play_movie(movie).unwrap();
+        for _ in 0..120 {
+            // Ignored while the movie plays
+            replay.set_joypad(0xFF);
+            replay.run_frame();
+        }
+        assert!(replay.save_state() == end);
+    }
+
+    #[test]
+    fn replay_from_power_on_is_deterministic() {
+        assert_replay_matches(true);
+    }
+
+    #[test]
+    fn replay_from_a_save_state_is_deterministic() {
+        assert_replay_matches(false);
+    }
+}
@@ -0,0 +1,75 @@
+// Colours a DMG's four shades are drawn with, background, OBJ0 and OBJ1 each have their own set
+// Only used on a DMG, CGB games and DMG games on a CGB use the CGB's palette RAM
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DmgPalette {
+    pub name: String,
+    pub bg: [u32; 4], // RGB888 from lightest to darkest shade
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+impl DmgPalette {
+    // The same colours for background and sprites
+    pub fn uniform(name: &str, colours: [u32; 4]) -> Self {
+        Self { name: name.to_string(), bg: colours, obj0: colours, obj1: colours }
+    }
+}
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        Self::uniform("grey", [0xFFFFFF, 0xCCCCCC, 0x777777, 0x000000])
+    }
+}
+
+// Built in palettes, the first one is the default
+pub fn presets() -> Vec<DmgPalette> {
+    vec![
+        DmgPalette::default(),
+        DmgPalette::uniform("dmg", [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]),
+        DmgPalette::uniform("pocket", [0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]),
+        DmgPalette::uniform("light", [0x00B581, 0x009A71, 0x00694A, 0x004F3B]),
+        DmgPalette::uniform("high-contrast", [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]),
+    ]
+}
+
+// "#RRGGBB" for each shade, lightest first
+pub fn parse_colours(colours: &[&str]) -> Result<[u32; 4], String> {
+    if colours.len() != 4 {
+        return Err(format!("expected 4 colours, found {}", colours.len()));
+    }
+    let mut parsed: [u32; 4] = [0; 4];
+    for (i, colour) in colours.iter().enumerate() {
+        parsed[i] = colour
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("expected a colour like \"#9BBC0F\", found \"{}\"", colour))?;
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_colours;
+
+    #[test]
+    fn parses_four_colours() {
+        assert_eq!(parse_colours(&["#9BBC0F", "#8bac0f", "#306230", "#0F380F"]), Ok([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]));
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        for colour in ["9BBC0F", "#9BBC0", "#9BBC0F0", "#9BBCXF", "#+BBC0F", "# BBC0F", "#", ""] {
+            let error = parse_colours(&["#FFFFFF", colour, "#777777", "#000000"]).unwrap_err();
+            assert!(error.contains(&format!("found \"{}\"", colour)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_colours() {
+        assert_eq!(parse_colours(&["#FFFFFF", "#777777", "#000000"]), Err("expected 4 colours, found 3".to_string()));
+        assert_eq!(parse_colours(&["#FFFFFF"; 5]), Err("expected 4 colours, found 5".to_string()));
+        assert!(parse_colours(&[]).is_err());
+    }
+}
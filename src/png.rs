@@ -0,0 +1,117 @@
+use crate::hash::{adler32, crc32};
+use crate::zip::deflate;
+
+// Lossless screenshots, 8 bit RGB PNGs with every pixel scaled up to a scale x scale block
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+pub const MAX_SCALE: usize = 8;
+
+// RGB888 pixels like screen_data, row by row
+pub fn encode(pixels: &[u32], width: usize, height: usize, scale: usize) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height);
+    let scale = scale.clamp(1, MAX_SCALE);
+
+    // Every row starts with its filter type, 0 leaves the bytes as they are
+    let mut raw: Vec<u8> = Vec::with_capacity((width * scale * 3 + 1) * height * scale);
+    for row in pixels.chunks(width) {
+        let start = raw.len();
+        raw.push(0);
+        for pixel in row {
+            for _ in 0..scale {
+                raw.extend_from_slice(&pixel.to_be_bytes()[1..]);
+            }
+        }
+        for _ in 1..scale {
+            raw.extend_from_within(start..start + width * scale * 3 + 1);
+        }
+    }
+
+    // zlib stream: header for a 32KB window and no preset dictionary, deflated data, Adler-32
+    let mut zlib: Vec<u8> = vec![0x78, 0x01];
+    zlib.extend(deflate(&raw));
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&((width * scale) as u32).to_be_bytes());
+    header.extend_from_slice(&((height * scale) as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits per channel, RGB, deflate, filters per row, not interlaced
+
+    let mut png: Vec<u8> = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, MAX_SCALE};
+    use crate::hash::{adler32, crc32};
+    use crate::zip::inflate;
+
+    // Stripes of a few colours with some noise, so the image has both long matches and literals
+    fn image() -> Vec<u32> {
+        (0..160 * 144u32)
+            .map(|i| if i % 97 == 0 { i.wrapping_mul(2654435761) & 0xFFFFFF } else { [0xE0F8D0, 0x88C070, 0x346856, 0x081820][(i / 160 / 8 % 4) as usize] })
+            .collect()
+    }
+
+    // Each chunk as its type and data, checking the CRCs on the way
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut i = 8;
+        while i < png.len() {
+            let length = u32::from_be_bytes(png[i..i + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(png[i + 8 + length..i + 12 + length].try_into().unwrap());
+            assert_eq!(crc, crc32(&png[i + 4..i + 8 + length]));
+            chunks.push((png[i + 4..i + 8].try_into().unwrap(), png[i + 8..i + 8 + length].to_vec()));
+            i += 12 + length;
+        }
+        chunks
+    }
+
+    #[test]
+    fn screenshot_decodes_to_the_scaled_screen() {
+        let pixels = image();
+        let png = encode(&pixels, 160, 144, 2);
+        assert_eq!(png[..8], [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+
+        let chunks = chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        let header = &chunks[0].1;
+        assert_eq!(u32::from_be_bytes(header[0..4].try_into().unwrap()), 320);
+        assert_eq!(u32::from_be_bytes(header[4..8].try_into().unwrap()), 288);
+        assert_eq!(header[8..], [8, 2, 0, 0, 0]);
+
+        let zlib = &chunks[1].1;
+        assert_eq!((zlib[0] as u16 * 256 + zlib[1] as u16) % 31, 0, "zlib header check bits");
+        let raw = inflate(&zlib[2..zlib.len() - 4]).unwrap();
+        assert_eq!(zlib[zlib.len() - 4..], adler32(&raw).to_be_bytes());
+
+        assert_eq!(raw.len(), 288 * (320 * 3 + 1));
+        for (y, row) in raw.chunks(320 * 3 + 1).enumerate() {
+            assert_eq!(row[0], 0);
+            for (x, pixel) in row[1..].chunks(3).enumerate() {
+                assert_eq!(pixel, &pixels[y / 2 * 160 + x / 2].to_be_bytes()[1..]);
+            }
+        }
+    }
+
+    #[test]
+    fn scale_is_clamped() {
+        let width = |png: &[u8]| u32::from_be_bytes(png[16..20].try_into().unwrap());
+        assert_eq!(width(&encode(&[0; 4], 2, 2, 0)), 2);
+        assert_eq!(width(&encode(&[0; 4], 2, 2, 100)), 2 * MAX_SCALE as u32);
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+
+// Ring buffer of machine snapshots used to play the game backwards
+// Only the newest snapshot is kept whole, every older one is stored as a compressed delta against the snapshot after it
+// Once the newest snapshot and the deltas take up more than the memory budget the oldest deltas are dropped
+
+pub struct Rewind {
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    memory_used: usize, // Newest snapshot and deltas
+    pub memory_budget: usize,
+    pub interval: u32,
+    frames_since_snapshot: u32,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, memory_budget: usize) -> Self {
+        Self {
+            newest: None,
+            deltas: VecDeque::new(),
+            memory_used: 0,
+            memory_budget,
+            interval: interval.max(1),
+            frames_since_snapshot: 0,
+        }
+    }
+
+    // Called once per emulated frame, returns true when a snapshot should be taken
+    pub fn frame_elapsed(&mut self) -> bool {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot >= self.interval {
+            self.frames_since_snapshot = 0;
+            return true;
+        }
+        false
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            self.memory_used -= previous.len();
+            if previous.len() == state.len() {
+                let delta = compress_delta(&previous, &state);
+                self.memory_used += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.clear();
+            }
+        }
+        self.memory_used += state.len();
+        self.newest = Some(state);
+
+        while self.memory_used > self.memory_budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.memory_used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Returns the newest snapshot and steps the buffer back by one
+    // The oldest snapshot is never removed so holding rewind stays on it
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                self.memory_used -= delta.len();
+                let mut previous = newest.clone();
+                apply_delta(&mut previous, &delta);
+                self.newest = Some(previous);
+            }
+            None => self.newest = Some(newest.clone()),
+        }
+        self.frames_since_snapshot = 0;
+        Some(newest)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.memory_used = 0;
+        self.frames_since_snapshot = 0;
+    }
+}
+
+// Delta encoding: XOR both snapshots, then run length encode the result
+// Output is a list of (zero run, literal length, literal bytes) records, lengths are LEB128 encoded
+
+fn compress_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut i: usize = 0;
+
+    while i < newer.len() {
+        let zero_start = i;
+        // Most of a snapshot is unchanged, so matching bytes are skipped 8 at a time first
+        while i + 8 <= newer.len() && read_word(older, i) == read_word(newer, i) {
+            i += 8;
+        }
+        while i < newer.len() && older[i] == newer[i] {
+            i += 1;
+        }
+        let zero_run = i - zero_start;
+
+        let literal_start = i;
+        // Short runs of matching bytes are cheaper to store as literals
+        while i < newer.len() {
+            if older[i] == newer[i] {
+                let run_end = (i..newer.len().min(i + 4)).find(|&k| older[k] != newer[k]);
+                match run_end {
+                    Some(_) => i += 1,
+                    None => break,
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        write_length(&mut output, zero_run);
+        write_length(&mut output, i - literal_start);
+        for k in literal_start..i {
+            output.push(older[k] ^ newer[k]);
+        }
+    }
+
+    output
+}
+
+fn read_word(bytes: &[u8], i: usize) -> u64 {
+    u64::from_ne_bytes(bytes[i..i + 8].try_into().unwrap())
+}
+
+// Turns the newer snapshot back into the older one (XOR is its own inverse)
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut position: usize = 0;
+    let mut i: usize = 0;
+
+    while i < delta.len() {
+        position += read_length(delta, &mut i);
+        let literal_length = read_length(delta, &mut i);
+        for byte in &delta[i..i + literal_length] {
+            state[position] ^= *byte;
+            position += 1;
+        }
+        i += literal_length;
+    }
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let byte = (length & 0x7F) as u8;
+        length >>= 7;
+        if length == 0 {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+fn read_length(input: &[u8], i: &mut usize) -> usize {
+    let mut length: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = input[*i];
+        *i += 1;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, compress_delta, read_length, write_length, Rewind};
+
+    // Deterministic bytes that change a little from one seed to the next, like consecutive snapshots
+    fn snapshot(seed: u32, length: usize) -> Vec<u8> {
+        (0..length as u32)
+            .map(|i| if i % 64 < 4 { (i.wrapping_mul(31) ^ seed.wrapping_mul(2654435761)) as u8 } else { (i / 7) as u8 })
+            .collect()
+    }
+
+    #[test]
+    fn leb128_lengths() {
+        for (length, encoded) in [(0, vec![0x00]), (127, vec![0x7F]), (128, vec![0x80, 0x01]), (300, vec![0xAC, 0x02]), (16384, vec![0x80, 0x80, 0x01])] {
+            let mut output: Vec<u8> = Vec::new();
+            write_length(&mut output, length);
+            assert_eq!(output, encoded, "{}", length);
+            let mut i = 0;
+            assert_eq!(read_length(&output, &mut i), length);
+            assert_eq!(i, encoded.len());
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        write_length(&mut output, usize::MAX);
+        let mut i = 0;
+        assert_eq!(read_length(&output, &mut i), usize::MAX);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let older = snapshot(1, 5000);
+        let cases = [
+            older.clone(),
+            snapshot(2, 5000),
+            vec![0; 5000],
+            // Changes at both ends and ones separated by short matching runs
+            older.iter().enumerate().map(|(i, byte)| if i == 0 || i == 4999 || (100..120).contains(&i) && i % 3 == 0 { !byte } else { *byte }).collect(),
+        ];
+        for newer in cases.iter() {
+            let delta = compress_delta(&older, newer);
+            let mut state = newer.clone();
+            apply_delta(&mut state, &delta);
+            assert!(state == older);
+        }
+    }
+
+    #[test]
+    fn delta_of_identical_snapshots_is_tiny() {
+        let state = snapshot(1, 100_000);
+        assert_eq!(compress_delta(&state, &state), [0xA0, 0x8D, 0x06, 0x00]);
+        // A few changed bytes cost a few bytes
+        assert!(compress_delta(&state, &snapshot(2, 100_000)).len() < 100_000 / 8);
+    }
+
+    #[test]
+    fn push_then_pop_gives_back_every_snapshot() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for seed in 0..5 {
+            rewind.push(snapshot(seed, 2000));
+        }
+        for seed in (0..5).rev() {
+            assert!(rewind.pop().unwrap() == snapshot(seed, 2000));
+        }
+        // The oldest stays put
+        assert!(rewind.pop().unwrap() == snapshot(0, 2000));
+    }
+
+    #[test]
+    fn memory_budget_counts_the_newest_snapshot() {
+        let mut rewind = Rewind::new(1, 10_000);
+        rewind.push(snapshot(0, 4000));
+        assert_eq!(rewind.memory_used, 4000);
+
+        for seed in 1..200 {
+            rewind.push(snapshot(seed, 4000));
+            assert!(rewind.memory_used <= 10_000);
+            let deltas: usize = rewind.deltas.iter().map(|delta| delta.len()).sum();
+            assert_eq!(rewind.memory_used, 4000 + deltas);
+        }
+
+        // The oldest snapshots were dropped, the kept ones still come back in order
+        let kept = rewind.deltas.len() as u32;
+        assert!(kept > 0 && kept < 199);
+        for seed in (199 - kept..200).rev() {
+            assert!(rewind.pop().unwrap() == snapshot(seed, 4000));
+        }
+        assert!(rewind.pop().unwrap() == snapshot(199 - kept, 4000));
+    }
+
+    #[test]
+    fn snapshot_bigger_than_the_budget_keeps_no_deltas() {
+        let mut rewind = Rewind::new(1, 1000);
+        rewind.push(snapshot(0, 4000));
+        rewind.push(snapshot(1, 4000));
+        assert!(rewind.deltas.is_empty());
+        assert!(rewind.pop().unwrap() == snapshot(1, 4000));
+    }
+}
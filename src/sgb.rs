@@ -0,0 +1,432 @@
+use crate::gpu::rgb555_to_rgb888;
+use crate::state::{write_u16, StateReader};
+
+// Super Game Boy
+// Games send 16 byte packets by pulsing P14/P15: both low starts a packet, P14 low sends a 0 bit and P15 low a 1 bit,
+// with both high in between. Bytes go least significant bit first and a 0 stop bit ends the packet
+// The first byte holds the command and how many packets (1-7) it spans
+// The DMG picture is coloured with four palettes picked per 8x8 cell and framed by a 256x224 border
+// VRAM transfers (PAL_TRN, CHR_TRN, PCT_TRN) read 4KB of tile data from the next frame the DMG displays
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 224;
+const GAME_X: usize = 48;
+const GAME_Y: usize = 40;
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+const PACKET_LENGTH: usize = 16;
+const MAX_PACKETS: usize = 7;
+const TRANSFER_LENGTH: usize = 4096;
+
+// SGB palette 1-A, used until the game sets its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+// MASK_EN
+const MASK_NONE: u8 = 0;
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8 = 2;
+const MASK_COLOUR_0: u8 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transfer {
+    Palettes,     // PAL_TRN, 512 system palettes for PAL_SET
+    Tiles(usize), // CHR_TRN, border tiles 0x00-0x7F or 0x80-0xFF
+    Border,       // PCT_TRN, border map and palettes 4-7
+}
+
+pub struct Sgb {
+    packets: [u8; PACKET_LENGTH * MAX_PACKETS],
+    packets_received: usize,
+    packet_bits: Option<usize>, // Bits received of the packet being sent
+    select: u8,                 // Last P14/P15 write
+    palettes: [[u16; 4]; 4],    // Colour 0 of palette 0 is used for all four
+    system_palettes: Vec<u16>,
+    attributes: [u8; CELLS_X * CELLS_Y],
+    mask: u8,
+    transfer: Option<Transfer>,
+    border_tiles: Vec<u8>, // SNES 4bpp tiles, 32 bytes each
+    border_map: Vec<u16>,  // 32x28 tiles: tile, palette in bits 10-12, X flip in bit 14 and Y flip in bit 15
+    border_palettes: [[u16; 16]; 4],
+    shades: Vec<u8>, // The last frame as DMG shades
+    players: u8,     // MLT_REQ
+    player: u8,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            packets: [0; PACKET_LENGTH * MAX_PACKETS],
+            packets_received: 0,
+            packet_bits: None,
+            select: 0x30,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; 512 * 4],
+            attributes: [0; CELLS_X * CELLS_Y],
+            mask: MASK_NONE,
+            transfer: None,
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 32],
+            border_palettes: [[0; 16]; 4],
+            shades: vec![0; 160 * 144],
+            players: 1,
+            player: 0,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&self.packets);
+        state.push(self.packets_received as u8);
+        write_u16(state, self.packet_bits.map(|bits| bits as u16).unwrap_or(0xFFFF));
+        state.push(self.select);
+        for colour in self.palettes.iter().flatten().chain(self.system_palettes.iter()) {
+            write_u16(state, *colour);
+        }
+        state.extend_from_slice(&self.attributes);
+        state.push(self.mask);
+        state.push(match self.transfer {
+            None => 0,
+            Some(Transfer::Palettes) => 1,
+            Some(Transfer::Tiles(bank)) => 2 + bank as u8,
+            Some(Transfer::Border) => 4,
+        });
+        state.extend_from_slice(&self.border_tiles);
+        for entry in self.border_map.iter().chain(self.border_palettes.iter().flatten()) {
+            write_u16(state, *entry);
+        }
+        state.extend_from_slice(&self.shades);
+        state.extend_from_slice(&[self.players, self.player]);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        state.read_bytes(&mut self.packets);
+        self.packets_received = state.read_u8() as usize;
+        self.packet_bits = match state.read_u16() {
+            0xFFFF => None,
+            bits => Some(bits as usize),
+        };
+        self.select = state.read_u8();
+        for colour in self.palettes.iter_mut().flatten().chain(self.system_palettes.iter_mut()) {
+            *colour = state.read_u16();
+        }
+        state.read_bytes(&mut self.attributes);
+        self.mask = state.read_u8();
+        self.transfer = match state.read_u8() {
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Tiles(0)),
+            3 => Some(Transfer::Tiles(1)),
+            4 => Some(Transfer::Border),
+            _ => None,
+        };
+        state.read_bytes(&mut self.border_tiles);
+        for entry in self.border_map.iter_mut().chain(self.border_palettes.iter_mut().flatten()) {
+            *entry = state.read_u16();
+        }
+        state.read_bytes(&mut self.shades);
+        self.players = state.read_u8();
+        self.player = state.read_u8();
+    }
+
+    // Packets
+
+    // Called with P14/P15 (bits 4 and 5) on every P1 write
+    pub fn write_joypad(&mut self, select: u8) {
+        let previous = self.select;
+        self.select = select;
+
+        if select == 0x00 {
+            self.packet_bits = Some(0);
+            return;
+        }
+        let bits = match self.packet_bits {
+            Some(bits) => bits,
+            None => {
+                // With several controllers a rising P15 moves on to the next one
+                if previous & 0x20 == 0 && select & 0x20 > 0 {
+                    self.player = (self.player + 1) % self.players;
+                }
+                return;
+            }
+        };
+        // Bits are only taken when leaving the idle state, so a held line counts once
+        if previous != 0x30 || select == 0x30 {
+            return;
+        }
+
+        let bit: u8 = (select == 0x10) as u8;
+        if bits == PACKET_LENGTH * 8 {
+            // Stop bit
+            self.packet_bits = None;
+            if bit == 0 {
+                self.receive_packet();
+            }
+            return;
+        }
+        let byte = &mut self.packets[self.packets_received * PACKET_LENGTH + bits / 8];
+        if bits % 8 == 0 {
+            *byte = 0;
+        }
+        *byte |= bit << (bits % 8);
+        self.packet_bits = Some(bits + 1);
+    }
+
+    fn receive_packet(&mut self) {
+        self.packets_received += 1;
+        let length = (self.packets[0] as usize & 0x07).max(1);
+        if self.packets_received >= length {
+            self.packets_received = 0;
+            let data = self.packets;
+            self.execute(&data[..length * PACKET_LENGTH]);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attribute_blocks(data),
+            0x05 => self.attribute_lines(data),
+            0x06 => self.attribute_division(data),
+            0x07 => self.attribute_cells(data),
+            0x0A => self.set_system_palettes(data),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.transfer = Some(Transfer::Tiles((data[1] & 0x01) as usize)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x17 => self.mask = data[1] & 0x03,
+            // Sound, ATTR_TRN/ATTR_SET and the SNES side commands aren't emulated
+            _ => (),
+        }
+    }
+
+    // PAL01, PAL23, PAL03 and PAL12: the shared colour 0, then colours 1-3 of both palettes
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let colour = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x7FFF;
+        self.palettes[0][0] = colour(0);
+        for i in 0..3 {
+            self.palettes[first][i + 1] = colour(1 + i);
+            self.palettes[second][i + 1] = colour(4 + i);
+        }
+    }
+
+    // PAL_SET: four palettes out of the ones PAL_TRN loaded, bit 6 of byte 9 also cancels MASK_EN
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let number = (u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x1FF) as usize;
+            palette.copy_from_slice(&self.system_palettes[number * 4..number * 4 + 4]);
+        }
+        if data[9] & 0x40 > 0 {
+            self.mask = MASK_NONE;
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < CELLS_X && y < CELLS_Y {
+            self.attributes[y * CELLS_X + x] = palette & 0x03;
+        }
+    }
+
+    // ATTR_BLK: rectangles with separate palettes inside, on the edge and outside
+    // When only inside or only outside is set the edge takes that palette as well
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for block in data[2..].chunks_exact(6).take(count) {
+            let control = block[0] & 0x07;
+            let (inside, edge, outside) = (block[1] & 0x03, (block[1] >> 2) & 0x03, (block[1] >> 4) & 0x03);
+            let edge = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 > 0 => Some(edge),
+                _ => None,
+            };
+            let (x1, y1, x2, y2) = (block[2] as usize, block[3] as usize, block[4] as usize, block[5] as usize);
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_edge {
+                        edge
+                    } else if within {
+                        Some(inside).filter(|_| control & 0x01 > 0)
+                    } else {
+                        Some(outside).filter(|_| control & 0x04 > 0)
+                    };
+                    if let Some(palette) = palette {
+                        self.set_attribute(x, y, palette);
+                    }
+                }
+            }
+        }
+    }
+
+    // ATTR_LIN: whole rows (bit 7 set) or columns, line number in bits 0-4 and palette in bits 5-6
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for line in data[2..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 > 0 {
+                for x in 0..CELLS_X {
+                    self.set_attribute(x, number, palette);
+                }
+            } else {
+                for y in 0..CELLS_Y {
+                    self.set_attribute(number, y, palette);
+                }
+            }
+        }
+    }
+
+    // ATTR_DIV: splits the screen at a row (bit 6 set) or column, the cells on the split get their own palette
+    fn attribute_division(&mut self, data: &[u8]) {
+        let (after, before, on_line) = (data[1] & 0x03, (data[1] >> 2) & 0x03, (data[1] >> 4) & 0x03);
+        let split = data[2] as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if data[1] & 0x40 > 0 { y } else { x };
+                let palette = match position.cmp(&split) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    // ATTR_CHR: palettes for single cells from a starting cell, left to right or top to bottom
+    // Two bits per cell, four cells per byte starting with the top bits
+    fn attribute_cells(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 0x01 > 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            self.set_attribute(x, y, byte >> (6 - (i % 4) * 2));
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    // Multiple controllers
+
+    // Reading P1 with neither group selected gives the controller number, 0xF for the first one
+    pub fn controller_id(&self) -> Option<u8> {
+        (self.players > 1).then(|| 0x0F - self.player)
+    }
+
+    pub fn player(&self) -> usize {
+        self.player as usize
+    }
+
+    // Frames
+
+    // Called at V-Blank with the frame's shades, which is when VRAM transfers happen
+    pub fn frame_end(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = transfer_data(shades);
+            let word = |i: usize| u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+            match transfer {
+                Transfer::Palettes => {
+                    for (i, colour) in self.system_palettes.iter_mut().enumerate() {
+                        *colour = word(i) & 0x7FFF;
+                    }
+                }
+                Transfer::Tiles(bank) => {
+                    self.border_tiles[bank * TRANSFER_LENGTH..(bank + 1) * TRANSFER_LENGTH].copy_from_slice(&data);
+                }
+                Transfer::Border => {
+                    for (i, entry) in self.border_map.iter_mut().enumerate() {
+                        *entry = word(i);
+                    }
+                    for (i, colour) in self.border_palettes.iter_mut().flatten().enumerate() {
+                        *colour = word(0x400 + i) & 0x7FFF;
+                    }
+                }
+            }
+        }
+        if self.mask != MASK_FREEZE {
+            self.shades.copy_from_slice(shades);
+        }
+    }
+
+    // The 256x224 picture: border on top of the coloured DMG screen, both on top of colour 0
+    pub fn render(&self, screen: &mut [u32]) {
+        let backdrop = rgb555_to_rgb888(self.palettes[0][0]);
+        screen.fill(backdrop);
+
+        for y in 0..144 {
+            for x in 0..160 {
+                let shade = self.shades[y * 160 + x] as usize;
+                let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                screen[(GAME_Y + y) * SCREEN_WIDTH + GAME_X + x] = match self.mask {
+                    MASK_BLACK => 0,
+                    MASK_COLOUR_0 => backdrop,
+                    _ if shade == 0 => backdrop,
+                    _ => rgb555_to_rgb888(self.palettes[palette][shade]),
+                };
+            }
+        }
+
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let entry = self.border_map[(y / 8) * 32 + x / 8];
+                let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+                let row = if entry & 0x8000 > 0 { 7 - y % 8 } else { y % 8 };
+                let bit = if entry & 0x4000 > 0 { x % 8 } else { 7 - x % 8 };
+                let colour = [tile[row * 2], tile[row * 2 + 1], tile[16 + row * 2], tile[17 + row * 2]]
+                    .iter()
+                    .enumerate()
+                    .fold(0, |colour, (plane, byte)| colour | ((byte >> bit) & 0x01) << plane);
+                if colour > 0 {
+                    let palette = ((entry >> 10) & 0x03) as usize;
+                    screen[y * SCREEN_WIDTH + x] = rgb555_to_rgb888(self.border_palettes[palette][colour as usize]);
+                }
+            }
+        }
+    }
+}
+
+// The first 256 tiles of the screen, 20 per row, encoded back into 2bpp tile data
+fn transfer_data(shades: &[u8]) -> Vec<u8> {
+    let mut data: Vec<u8> = vec![0; TRANSFER_LENGTH];
+    for tile in 0..256 {
+        let (tile_x, tile_y) = (tile % CELLS_X, tile / CELLS_X);
+        for row in 0..8 {
+            let line = &shades[(tile_y * 8 + row) * 160 + tile_x * 8..][..8];
+            for (x, shade) in line.iter().enumerate() {
+                data[tile * 16 + row * 2] |= (shade & 0x01) << (7 - x);
+                data[tile * 16 + row * 2 + 1] |= ((shade >> 1) & 0x01) << (7 - x);
+            }
+        }
+    }
+    data
+}
@@ -0,0 +1,63 @@
+use crate::cpu::CPU;
+use crate::gpu::GPU;
+
+// Snapshots of the whole machine (CPU, MMU and GPU) as a flat byte vector
+// Every snapshot of the same machine has the same length so they can be diffed against each other
+
+pub fn save_state(cpu: &CPU, gpu: &GPU) -> Vec<u8> {
+    let mut state: Vec<u8> = Vec::new();
+    cpu.save_state(&mut state);
+    cpu.mmu.borrow().save_state(&mut state);
+    gpu.save_state(&mut state);
+    state
+}
+
+pub fn load_state(cpu: &mut CPU, gpu: &mut GPU, state: &[u8]) {
+    let mut reader = StateReader::new(state);
+    cpu.load_state(&mut reader);
+    cpu.mmu.borrow_mut().load_state(&mut reader);
+    gpu.load_state(&mut reader);
+}
+
+pub fn write_u16(state: &mut Vec<u8>, value: u16) {
+    state.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u32(state: &mut Vec<u8>, value: u32) {
+    state.extend_from_slice(&value.to_le_bytes());
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read_u8(&mut self) -> u8 {
+        let value = self.data[self.position];
+        self.position += 1;
+        value
+    }
+
+    pub fn read_bool(&mut self) -> bool {
+        self.read_u8() > 0
+    }
+
+    pub fn read_u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.read_u8(), self.read_u8()])
+    }
+
+    pub fn read_u32(&mut self) -> u32 {
+        u32::from_le_bytes([self.read_u8(), self.read_u8(), self.read_u8(), self.read_u8()])
+    }
+
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) {
+        let end = self.position + buffer.len();
+        buffer.copy_from_slice(&self.data[self.position..end]);
+        self.position = end;
+    }
+}
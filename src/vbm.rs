@@ -0,0 +1,98 @@
+use crate::hash::crc32;
+use crate::mmu::Model;
+use crate::movie::{Movie, MovieRecorder, StartCondition};
+use std::fs::File;
+use std::io::{self, Read};
+
+// VisualBoyAdvance VBM movies
+// A 64 byte header is followed by two bytes of buttons per controller per frame:
+// bit 0 A, 1 B, 2 Select, 3 Start, 4 Right, 5 Left, 6 Up, 7 Down (bit 8 and above are GBA buttons and reset)
+// Only movies that start from power-on can be played, VBA save states and SRAM can't be loaded
+
+const MAGIC: &[u8; 4] = b"VBM\x1A";
+
+pub fn import(path: &str, rom: &[u8]) -> io::Result<Movie> {
+    let mut data: Vec<u8> = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if data.len() < 0x40 || &data[0..4] != MAGIC {
+        return Err(invalid("not a VBM movie"));
+    }
+
+    let read_u32 = |position: usize| u32::from_le_bytes([data[position], data[position + 1], data[position + 2], data[position + 3]]);
+    let length = read_u32(0x0C);
+    let start_flags = data[0x14];
+    let controller_flags = data[0x15];
+    let system_flags = data[0x16];
+    let header_checksum = data[0x31];
+    let global_checksum = u16::from_le_bytes([data[0x32], data[0x33]]);
+    let input_offset = read_u32(0x3C) as usize;
+
+    if start_flags & 0x3 != 0 {
+        return Err(invalid("VBM movies starting from a save state or SRAM aren't supported"));
+    }
+    if system_flags & 0x1 > 0 {
+        return Err(invalid("movie is for the Game Boy Advance"));
+    }
+
+    // VBA stores the ROM's header checksum (0x14D) and global checksum (0x14E-0x14F)
+    if rom.len() > 0x14F {
+        let rom_global_checksum = u16::from_le_bytes([rom[0x14E], rom[0x14F]]);
+        if rom[0x14D] != header_checksum || rom_global_checksum != global_checksum {
+            return Err(invalid("movie was recorded with a different ROM (checksum mismatch)"));
+        }
+    }
+
+    let model = if system_flags & 0x2 > 0 {
+        Model::Cgb
+    } else if system_flags & 0x4 > 0 {
+        Model::Sgb
+    } else {
+        Model::Dmg
+    };
+
+    let controllers = (controller_flags & 0xF).count_ones().max(1) as usize;
+    let mut recorder = MovieRecorder::new(Movie::new(crc32(rom), model, StartCondition::PowerOn));
+
+    for frame in 0..length {
+        // First active controller only
+        let position = input_offset + (frame as usize) * controllers * 2;
+        let buttons = *data.get(position).ok_or_else(|| invalid("VBM input data is truncated"))?;
+        // VBA keeps action buttons in the low nibble and directions in the high one, the opposite of movie.rs
+        recorder.record(frame, buttons.rotate_left(4));
+    }
+
+    Ok(recorder.finish())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::import;
+
+    // Four frames of A, Up and A, Right and Start, nothing, from power-on with one controller
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/movie.vbm");
+
+    #[test]
+    fn imports_the_first_controller() {
+        let movie = import(FIXTURE, &[0; 0x8000]).unwrap();
+        assert_eq!(movie.length, 4);
+        let events: Vec<(u32, u8)> = movie.events.iter().map(|event| (event.frame, event.joypad)).collect();
+        assert_eq!(events, vec![(0, 0x10), (1, 0x14), (2, 0x81), (3, 0x00)]);
+    }
+
+    #[test]
+    fn rejects_a_rom_with_other_checksums() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = 0x12;
+        assert!(import(FIXTURE, &rom).is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(import(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/movie.bk2"), &[0; 0x8000]).is_err());
+    }
+}
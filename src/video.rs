@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Uncompressed video of every emulated frame as YUV4MPEG2 (4:4:4, BT.601 limited range),
+// with a WAV file of exactly the same length next to it
+// There is no sound emulation yet so the WAV holds silence, it keeps the timing tools expect
+
+pub const CLOCK_SPEED: u64 = 4194304; // Hz
+pub const FRAME_CYCLES: u64 = 70224; // One frame of the LCD, about 59.73 frames per second
+pub const SAMPLE_RATE: u64 = 48000;
+const CHANNELS: u16 = 2;
+const BYTES_PER_SAMPLE: u16 = 2;
+
+pub struct VideoRecorder {
+    video: BufWriter<File>,
+    audio: BufWriter<File>,
+    width: usize,
+    height: usize,
+    frames: u64,
+    samples: u64,
+    error: Option<io::Error>, // The first write that failed, later frames are dropped
+}
+
+impl VideoRecorder {
+    // The WAV goes to the same path with a .wav extension
+    pub fn new(path: &Path, width: usize, height: usize) -> io::Result<Self> {
+        let mut video = BufWriter::new(File::create(path)?);
+        writeln!(video, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444", width, height, CLOCK_SPEED, FRAME_CYCLES)?;
+        let mut audio = BufWriter::new(File::create(path.with_extension("wav"))?);
+        audio.write_all(&wav_header(0))?;
+        Ok(Self { video, audio, width, height, frames: 0, samples: 0, error: None })
+    }
+
+    // RGB888 pixels of one frame
+    pub fn add_frame(&mut self, pixels: &[u32]) {
+        if self.error.is_none() {
+            if let Err(e) = self.write_frame(pixels) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn write_frame(&mut self, pixels: &[u32]) -> io::Result<()> {
+        if pixels.len() != self.width * self.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} pixels in a {}x{} video", pixels.len(), self.width, self.height),
+            ));
+        }
+        let mut planes: Vec<u8> = vec![0; pixels.len() * 3];
+        let (y_plane, chroma) = planes.split_at_mut(pixels.len());
+        let (u_plane, v_plane) = chroma.split_at_mut(pixels.len());
+        for (i, pixel) in pixels.iter().enumerate() {
+            let r = ((pixel >> 16) & 0xFF) as i32;
+            let g = ((pixel >> 8) & 0xFF) as i32;
+            let b = (pixel & 0xFF) as i32;
+            y_plane[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            u_plane[i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+            v_plane[i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+        }
+        self.video.write_all(b"FRAME\n")?;
+        self.video.write_all(&planes)?;
+        self.frames += 1;
+
+        // Rounded down at every frame boundary so the audio never drifts from the video
+        let samples = self.frames * FRAME_CYCLES * SAMPLE_RATE / CLOCK_SPEED;
+        let silence: Vec<u8> = vec![0; ((samples - self.samples) * (CHANNELS * BYTES_PER_SAMPLE) as u64) as usize];
+        self.audio.write_all(&silence)?;
+        self.samples = samples;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Fills in the WAV sizes and flushes both files
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.video.flush()?;
+        let data_size = self.samples * (CHANNELS * BYTES_PER_SAMPLE) as u64;
+        if data_size > u32::MAX as u64 - 36 {
+            return Err(io::Error::other("recording too long for a WAV file"));
+        }
+        self.audio.seek(SeekFrom::Start(0))?;
+        self.audio.write_all(&wav_header(data_size as u32))?;
+        self.audio.flush()
+    }
+}
+
+// 16 bit stereo PCM
+fn wav_header(data_size: u32) -> Vec<u8> {
+    let block_align: u16 = CHANNELS * BYTES_PER_SAMPLE;
+    let mut header: Vec<u8> = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&CHANNELS.to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE as u32 * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VideoRecorder, CLOCK_SPEED, FRAME_CYCLES, SAMPLE_RATE};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn recording_path(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("rustboy-video-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory.join("clip.y4m")
+    }
+
+    #[test]
+    fn y4m_has_a_header_and_a_frame_of_every_plane() {
+        let path = recording_path("y4m");
+        let mut video = VideoRecorder::new(&path, 4, 2).unwrap();
+        video.add_frame(&[0xFFFFFF; 8]);
+        video.add_frame(&[0x000000; 8]);
+        video.finish().unwrap();
+
+        let y4m = fs::read(&path).unwrap();
+        let header = b"YUV4MPEG2 W4 H2 F4194304:70224 Ip A1:1 C444\n";
+        assert_eq!(&y4m[..header.len()], header);
+        let frames = &y4m[header.len()..];
+        assert_eq!(frames.len(), 2 * (6 + 4 * 2 * 3));
+        // White then black, BT.601 limited range
+        assert_eq!(&frames[..6], b"FRAME\n");
+        assert_eq!(&frames[6..6 + 24], &[[235; 8], [128; 8], [128; 8]].concat()[..]);
+        assert_eq!(&frames[30..36], b"FRAME\n");
+        assert_eq!(&frames[36..], &[[16; 8], [128; 8], [128; 8]].concat()[..]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wav_lasts_as_long_as_the_frames() {
+        let path = recording_path("wav");
+        let mut video = VideoRecorder::new(&path, 1, 1).unwrap();
+        for _ in 0..60 {
+            video.add_frame(&[0]);
+        }
+        assert_eq!(video.frames(), 60);
+        video.finish().unwrap();
+
+        let wav = fs::read(path.with_extension("wav")).unwrap();
+        let samples = 60 * FRAME_CYCLES * SAMPLE_RATE / CLOCK_SPEED;
+        let data_size = u32::from_le_bytes(wav[40..44].try_into().unwrap());
+        assert_eq!(data_size as u64, samples * 4);
+        assert_eq!(wav.len() as u64, 44 + samples * 4);
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + data_size);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn frame_of_the_wrong_size_is_an_error() {
+        let path = recording_path("size");
+        let mut video = VideoRecorder::new(&path, 2, 2).unwrap();
+        video.add_frame(&[0; 3]);
+        video.add_frame(&[0; 4]);
+        assert_eq!(video.frames(), 0);
+        assert!(video.finish().is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,508 @@
+use crate::hash::crc32;
+use std::io;
+
+// Just enough of the zip format for BizHawk movies
+// Reading supports stored and deflated entries, writing always stores
+// deflate() is also used on its own for PNG screenshots
+
+pub struct ZipEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+pub fn read_zip(data: &[u8]) -> io::Result<Vec<ZipEntry>> {
+    // The end of central directory record sits in the last 22 bytes plus an optional comment
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .find(|&i| read_u32(data, i) == Some(0x06054B50))
+        .ok_or_else(|| invalid("not a zip file"))?;
+
+    let entry_count = read_u16(data, end + 10).ok_or_else(|| invalid("zip file is truncated"))? as usize;
+    let mut position = read_u32(data, end + 16).ok_or_else(|| invalid("zip file is truncated"))? as usize;
+    let mut entries: Vec<ZipEntry> = Vec::new();
+
+    for _ in 0..entry_count {
+        if read_u32(data, position) != Some(0x02014B50) {
+            return Err(invalid("corrupt zip central directory"));
+        }
+        let header = |offset: usize| read_u16(data, position + offset).ok_or_else(|| invalid("zip file is truncated"));
+        let method = header(10)?;
+        let compressed_size = read_u32(data, position + 20).ok_or_else(|| invalid("zip file is truncated"))? as usize;
+        let name_length = header(28)? as usize;
+        let extra_length = header(30)? as usize;
+        let comment_length = header(32)? as usize;
+        let local_offset = read_u32(data, position + 42).ok_or_else(|| invalid("zip file is truncated"))? as usize;
+        let name_bytes = data.get(position + 46..position + 46 + name_length).ok_or_else(|| invalid("zip file is truncated"))?;
+        let name = String::from_utf8_lossy(name_bytes).to_string();
+        position += 46 + name_length + extra_length + comment_length;
+
+        // Sizes in the local header can be zero when a data descriptor is used, so only its name and extra lengths are read
+        if read_u32(data, local_offset) != Some(0x04034B50) {
+            return Err(invalid("corrupt zip local header"));
+        }
+        let local_name_length = read_u16(data, local_offset + 26).ok_or_else(|| invalid("zip file is truncated"))? as usize;
+        let local_extra_length = read_u16(data, local_offset + 28).ok_or_else(|| invalid("zip file is truncated"))? as usize;
+        let start = local_offset + 30 + local_name_length + local_extra_length;
+        let compressed = data.get(start..start + compressed_size).ok_or_else(|| invalid("zip file is truncated"))?;
+
+        let contents = match method {
+            0 => compressed.to_vec(),
+            8 => inflate(compressed)?,
+            _ => return Err(invalid("unsupported zip compression method")),
+        };
+        entries.push(ZipEntry { name, data: contents });
+    }
+
+    Ok(entries)
+}
+
+pub fn write_zip(entries: &[ZipEntry]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut directory: Vec<u8> = Vec::new();
+
+    for entry in entries.iter() {
+        let offset = output.len() as u32;
+        let crc = crc32(&entry.data);
+        let size = entry.data.len() as u32;
+        let name = entry.name.as_bytes();
+
+        // Local file header
+        output.extend_from_slice(&0x04034B50u32.to_le_bytes());
+        output.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]); // Version, flags, stored, time, date (1980-01-01)
+        output.extend_from_slice(&crc.to_le_bytes());
+        output.extend_from_slice(&size.to_le_bytes());
+        output.extend_from_slice(&size.to_le_bytes());
+        output.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        output.extend_from_slice(&0u16.to_le_bytes());
+        output.extend_from_slice(name);
+        output.extend_from_slice(&entry.data);
+
+        // Central directory header
+        directory.extend_from_slice(&0x02014B50u32.to_le_bytes());
+        directory.extend_from_slice(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]); // Versions, flags, stored, time, date
+        directory.extend_from_slice(&crc.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        directory.extend_from_slice(&[0; 12]); // Extra and comment lengths, disk, attributes
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name);
+    }
+
+    let directory_offset = output.len() as u32;
+    output.extend_from_slice(&directory);
+
+    // End of central directory
+    output.extend_from_slice(&0x06054B50u32.to_le_bytes());
+    output.extend_from_slice(&[0; 4]);
+    output.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    output.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    output.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    output.extend_from_slice(&directory_offset.to_le_bytes());
+    output.extend_from_slice(&0u16.to_le_bytes());
+    output
+}
+
+fn read_u16(data: &[u8], position: usize) -> Option<u16> {
+    let bytes = data.get(position..position + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], position: usize) -> Option<u32> {
+    let bytes = data.get(position..position + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Inflate (RFC 1951)
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u8) -> io::Result<u32> {
+        let mut value: u32 = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.position).ok_or_else(|| invalid("deflate stream is truncated"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.position += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.position += 1;
+        }
+    }
+}
+
+// Canonical Huffman code stored as symbol counts per length and symbols sorted by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for length in lengths.iter() {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for i in 1..16 {
+            offsets[i] = offsets[i - 1] + counts[i - 1];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length > 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> io::Result<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid huffman code"))
+    }
+}
+
+pub fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader = BitReader { data, position: 0, bit: 0 };
+    let mut output: Vec<u8> = Vec::new();
+
+    loop {
+        let is_final = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data.get(reader.position..reader.position + 4).ok_or_else(|| invalid("deflate stream is truncated"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let start = reader.position + 4;
+                let block = data.get(start..start + length).ok_or_else(|| invalid("deflate stream is truncated"))?;
+                output.extend_from_slice(block);
+                reader.position = start + length;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                for (i, length) in lengths.iter_mut().enumerate() {
+                    *length = match i {
+                        0..=143 => 8,
+                        144..=255 => 9,
+                        256..=279 => 7,
+                        _ => 8,
+                    };
+                }
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            2 => {
+                let literal_count = reader.bits(5)? as usize + 257;
+                let distance_count = reader.bits(5)? as usize + 1;
+                let code_length_count = reader.bits(4)? as usize + 4;
+
+                let mut code_lengths = [0u8; 19];
+                for i in 0..code_length_count {
+                    code_lengths[CODE_LENGTH_ORDER[i]] = reader.bits(3)? as u8;
+                }
+                let code_length_huffman = Huffman::new(&code_lengths);
+
+                let mut lengths: Vec<u8> = Vec::new();
+                while lengths.len() < literal_count + distance_count {
+                    let symbol = code_length_huffman.decode(&mut reader)?;
+                    let (value, repeat) = match symbol {
+                        0..=15 => (symbol as u8, 1),
+                        16 => (*lengths.last().ok_or_else(|| invalid("invalid code length repeat"))?, 3 + reader.bits(2)?),
+                        17 => (0, 3 + reader.bits(3)?),
+                        _ => (0, 11 + reader.bits(7)?),
+                    };
+                    for _ in 0..repeat {
+                        lengths.push(value);
+                    }
+                }
+
+                let literals = Huffman::new(&lengths[..literal_count]);
+                let distances = Huffman::new(&lengths[literal_count..literal_count + distance_count]);
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            _ => return Err(invalid("invalid deflate block type")),
+        }
+
+        if is_final {
+            return Ok(output);
+        }
+    }
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256 {
+            output.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let index = symbol - 257;
+            if index >= 29 {
+                return Err(invalid("invalid deflate length"));
+            }
+            let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])? as usize;
+
+            let distance_symbol = distances.decode(reader)? as usize;
+            if distance_symbol >= 30 {
+                return Err(invalid("invalid deflate distance"));
+            }
+            let distance = DISTANCE_BASE[distance_symbol] as usize + reader.bits(DISTANCE_EXTRA[distance_symbol])? as usize;
+            if distance > output.len() {
+                return Err(invalid("deflate distance is too far back"));
+            }
+
+            let start = output.len() - distance;
+            for i in 0..length {
+                output.push(output[start + i]);
+            }
+        }
+    }
+}
+
+// Deflate (RFC 1951)
+// A single block with the fixed Huffman code and greedy LZ77 matching, plenty for screenshots
+
+const WINDOW_SIZE: usize = 32768;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64; // Earlier positions tried per match
+
+struct BitWriter {
+    output: Vec<u8>,
+    bit: u8,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u8) {
+        for i in 0..count {
+            if self.bit == 0 {
+                self.output.push(0);
+            }
+            *self.output.last_mut().unwrap() |= (((value >> i) & 1) as u8) << self.bit;
+            self.bit = (self.bit + 1) % 8;
+        }
+    }
+
+    // Huffman codes are packed starting with their most significant bit
+    fn code(&mut self, code: u32, length: u8) {
+        let reversed = code.reverse_bits() >> (32 - length);
+        self.bits(reversed, length);
+    }
+
+    fn literal(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol as u32, 8),
+            144..=255 => self.code(0x190 + (symbol - 144) as u32, 9),
+            256..=279 => self.code((symbol - 256) as u32, 7),
+            _ => self.code(0xC0 + (symbol - 280) as u32, 8),
+        }
+    }
+
+    fn length_distance(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASE.iter().rposition(|base| *base as usize <= length).unwrap();
+        self.literal(257 + code as u16);
+        self.bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code]);
+        let code = DISTANCE_BASE.iter().rposition(|base| *base as usize <= distance).unwrap();
+        self.code(code as u32, 5);
+        self.bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code]);
+    }
+}
+
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter { output: Vec::new(), bit: 0 };
+    writer.bits(1, 1); // Final block
+    writer.bits(1, 2); // Fixed Huffman code
+
+    // Positions by their first three bytes, most recent first through previous
+    let hash = |i: usize| ((data[i] as usize) << 10 ^ (data[i + 1] as usize) << 5 ^ data[i + 2] as usize) & 0x7FFF;
+    let mut head: Vec<Option<usize>> = vec![None; 0x8000];
+    let mut previous: Vec<Option<usize>> = vec![None; data.len()];
+    let insert = |i: usize, head: &mut [Option<usize>], previous: &mut [Option<usize>]| {
+        if i + 2 < data.len() {
+            let h = hash(i);
+            previous[i] = head[h];
+            head[h] = Some(i);
+        }
+    };
+
+    let mut i: usize = 0;
+    while i < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if i + 2 < data.len() {
+            let mut candidate = head[hash(i)];
+            let mut chain = 0;
+            while let Some(start) = candidate {
+                if i - start > WINDOW_SIZE || chain == MAX_CHAIN {
+                    break;
+                }
+                let length = data[start..].iter().zip(&data[i..]).take(MAX_MATCH).take_while(|(a, b)| a == b).count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = i - start;
+                }
+                candidate = previous[start];
+                chain += 1;
+            }
+        }
+
+        if best_length >= 3 {
+            writer.length_distance(best_length, best_distance);
+            for j in i..i + best_length {
+                insert(j, &mut head, &mut previous);
+            }
+            i += best_length;
+        } else {
+            writer.literal(data[i] as u16);
+            insert(i, &mut head, &mut previous);
+            i += 1;
+        }
+    }
+
+    writer.literal(256); // End of block
+    writer.output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deflate, inflate, read_zip, write_zip, ZipEntry};
+
+    // Made by zlib at level 9, which picks a dynamic Huffman block for it
+    const DYNAMIC_BLOCK: &[u8] = include_bytes!("../tests/fixtures/dynamic.deflate");
+
+    fn dynamic_block_text() -> Vec<u8> {
+        (0..600).map(|i| (i * i % 1000).to_string()).collect::<Vec<String>>().join(" ").into_bytes()
+    }
+
+    #[test]
+    fn inflate_stored_block() {
+        assert_eq!(inflate(&[0x01, 0x05, 0x00, 0xFA, 0xFF, b'h', b'e', b'l', b'l', b'o']).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn inflate_fixed_block_round_trip() {
+        let pseudo_random: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let inputs: [Vec<u8>; 5] = [Vec::new(), b"a".to_vec(), b"abcabcabcabcabcabcabc".to_vec(), vec![7; 10000], pseudo_random];
+        for input in inputs.iter() {
+            let compressed = deflate(input);
+            assert_eq!((compressed[0] >> 1) & 0x03, 1, "fixed Huffman block");
+            assert_eq!(&inflate(&compressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn inflate_dynamic_block() {
+        assert_eq!((DYNAMIC_BLOCK[0] >> 1) & 0x03, 2);
+        assert_eq!(inflate(DYNAMIC_BLOCK).unwrap(), dynamic_block_text());
+    }
+
+    #[test]
+    fn inflate_stored_then_fixed_block() {
+        let mut stream: Vec<u8> = vec![0x00, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'];
+        stream.extend(deflate(b"defdef"));
+        assert_eq!(inflate(&stream).unwrap(), b"abcdefdef");
+    }
+
+    #[test]
+    fn inflate_truncated_stream_is_an_error() {
+        let compressed = deflate(&dynamic_block_text());
+        assert!(inflate(&compressed[..compressed.len() / 2]).is_err());
+        assert!(inflate(&DYNAMIC_BLOCK[..DYNAMIC_BLOCK.len() - 10]).is_err());
+        assert!(inflate(&[0x01, 0x05, 0x00, 0xFA, 0xFF, b'h']).is_err());
+        assert!(inflate(&[0x07]).is_err());
+    }
+
+    fn noise(seed: u32, length: usize) -> Vec<u8> {
+        (0..length as u32).map(|i| (i.wrapping_add(seed).wrapping_mul(2654435761) >> 19) as u8).collect()
+    }
+
+    #[test]
+    fn deflate_compresses_repeats() {
+        assert!(deflate(&[0; 10000]).len() < 100);
+        let text = dynamic_block_text();
+        assert!(deflate(&text).len() < text.len() / 2);
+    }
+
+    #[test]
+    fn deflate_matches_across_the_whole_window() {
+        // The repeat 32768 bytes back is in reach, the one 32769 back isn't and must not be referenced
+        for gap in [32768 - 300, 32769 - 300] {
+            let block = noise(1, 300);
+            let data: Vec<u8> = [block.clone(), noise(2, gap), block].concat();
+            let compressed = deflate(&data);
+            assert_eq!(inflate(&compressed).unwrap(), data);
+            if gap == 32768 - 300 {
+                assert!(compressed.len() < data.len() - 250);
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_long_and_overlapping_matches() {
+        // Runs longer than the 258 byte maximum match, and a match overlapping the bytes it copies
+        let data: Vec<u8> = [vec![b'x'; 1000], b"ab".repeat(500), noise(3, 50), vec![b'x'; 259]].concat();
+        assert_eq!(inflate(&deflate(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let entries = [
+            ZipEntry { name: "Header.txt".to_string(), data: b"Platform GB\n".to_vec() },
+            ZipEntry { name: "empty".to_string(), data: Vec::new() },
+        ];
+        let read = read_zip(&write_zip(&entries)).unwrap();
+        assert_eq!(read.len(), 2);
+        for (read, written) in read.iter().zip(entries.iter()) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.data, written.data);
+        }
+    }
+
+    #[test]
+    fn zip_errors() {
+        assert!(read_zip(b"not a zip file at all").is_err());
+        let zip = write_zip(&[ZipEntry { name: "a".to_string(), data: vec![1; 100] }]);
+        // The central directory points past the truncated data
+        assert!(read_zip(&[&zip[..40], &zip[zip.len() - 70..]].concat()).is_err());
+    }
+}